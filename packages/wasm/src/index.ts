@@ -77,6 +77,20 @@ export class WasmAdapter implements Adapter {
     // Nothing to do
   }
   
+  /**
+   * Delete documents expired by TTL indexes in every collection
+   */
+  sweepExpired(now: number = Date.now()): number {
+    return this.db.sweep_expired(now);
+  }
+  
+  /**
+   * Find documents in a collection joined with others by $lookup specs
+   */
+  findWithLookup(collection: string, query: Query, lookup: LookupSpec | LookupSpec[]): string {
+    return this.db.find_with_lookup(collection, JSON.stringify(query), JSON.stringify(lookup));
+  }
+  
   /**
    * Get a collection from WASM
    */
@@ -148,6 +162,26 @@ export class WasmDatabase implements Database {
     }
   }
   
+  /**
+   * Delete documents expired by TTL indexes in every collection
+   */
+  async sweepExpired(now: number = Date.now()): Promise<number> {
+    return this.adapter.sweepExpired(now);
+  }
+  
+  /**
+   * Find documents in a collection, each joined with documents of other
+   * collections by one or more $lookup specs
+   */
+  async findWithLookup<T extends Document = Document>(collection: string, query: Query, lookup: LookupSpec | LookupSpec[]): Promise<T[]> {
+    try {
+      return JSON.parse(this.adapter.findWithLookup(collection, query, lookup));
+    } catch (error) {
+      console.error(`Failed to find documents with lookup in ${collection}:`, error);
+      throw error;
+    }
+  }
+  
   /**
    * Close the database
    */
@@ -156,6 +190,101 @@ export class WasmDatabase implements Database {
   }
 }
 
+/**
+ * A $lookup stage: documents of `from` whose `foreignField` equals the
+ * document's `localField` and/or the results of `pipeline` over them, with
+ * `let` variables available in the pipeline as `$$name`, stored in `as`
+ */
+export interface LookupSpec {
+  from: string;
+  localField?: string;
+  foreignField?: string;
+  let?: Record<string, Expression>;
+  pipeline?: Record<string, any>[];
+  as: string;
+}
+
+/**
+ * A plan reported by `explain`: a collection scan, an index scan, or the
+ * intersection (AND_HASH) or union (OR) of the ids of its inputs
+ */
+export interface QueryPlan {
+  stage: 'COLLSCAN' | 'IXSCAN' | 'AND_HASH' | 'OR';
+  index: string | null;
+  estimatedRows: number;
+  cost: number;
+  inputs?: QueryPlan[];
+}
+
+/**
+ * An expression evaluated against a document: a field reference such as
+ * `'$age'`, a variable such as `'$$ROOT'`, a constant, an operator object
+ * such as `{ $add: ['$a', 1] }`, or an object or array of expressions
+ */
+export type Expression = any;
+
+/**
+ * Options for `find` and `findOne`. Sort keys apply in the order given and
+ * a projection either keeps or drops the listed fields, never both; computed
+ * fields count as kept.
+ */
+export interface FindOptions {
+  sort?: Record<string, 1 | -1 | 'asc' | 'desc' | { $meta: 'textScore' }>;
+  skip?: number;
+  limit?: number;
+  projection?: Record<string, 0 | 1 | boolean | { $meta: 'textScore' } | Expression>;
+  /** Continuation token returned by `findPage` for the previous page */
+  after?: string;
+}
+
+/**
+ * Batched iteration over query results. Documents are read as their batch is
+ * fetched: updates made meanwhile are seen and deleted documents skipped.
+ * Iterating with `for await` closes the cursor when the loop ends.
+ */
+export class WasmCursor<T extends Document = Document> implements AsyncIterable<T> {
+  private wasmCursor: any;
+  private batchSize: number;
+  
+  constructor(wasmCursor: any, batchSize: number = 100) {
+    this.wasmCursor = wasmCursor;
+    this.batchSize = batchSize;
+  }
+  
+  /**
+   * Fetch up to `size` more documents
+   */
+  async nextBatch(size: number = this.batchSize): Promise<T[]> {
+    return JSON.parse(this.wasmCursor.next_batch(size));
+  }
+  
+  /**
+   * Whether any documents remain
+   */
+  hasNext(): boolean {
+    return this.wasmCursor.has_next();
+  }
+  
+  /**
+   * Release the remaining results
+   */
+  close(): void {
+    this.wasmCursor.close();
+  }
+  
+  async *[Symbol.asyncIterator](): AsyncIterator<T> {
+    try {
+      while (this.hasNext()) {
+        for (const doc of await this.nextBatch()) {
+          yield doc;
+        }
+      }
+    } finally {
+      this.close();
+    }
+  }
+}
+
 /**
  * WASM-optimized collection implementation
  */
@@ -191,10 +320,10 @@ export class WasmCollection<T extends Document = Document> {
   /**
    * Find documents
    */
-  async find(query: Query = {}): Promise<T[]> {
+  async find(query: Query = {}, options: FindOptions = {}): Promise<T[]> {
     try {
       const json = JSON.stringify(query);
-      const results = this.wasmCollection.find(json);
+      const results = this.wasmCollection.find(json, JSON.stringify(options));
       
       return JSON.parse(results);
     } catch (error) {
@@ -206,10 +335,10 @@ export class WasmCollection<T extends Document = Document> {
   /**
    * Find one document
    */
-  async findOne(query: Query = {}): Promise<T | null> {
+  async findOne(query: Query = {}, options: FindOptions = {}): Promise<T | null> {
     try {
       const json = JSON.stringify(query);
-      const result = this.wasmCollection.find_one(json);
+      const result = this.wasmCollection.find_one(json, JSON.stringify(options));
       
       if (result === 'null') {
         return null;
@@ -222,6 +351,53 @@ export class WasmCollection<T extends Document = Document> {
     }
   }
   
+  /**
+   * Find one page of documents ordered by `sort` then id. Pass `next` back as
+   * `after` to fetch the following page; it is null on the last page.
+   */
+  async findPage(query: Query = {}, options: FindOptions = {}): Promise<{ documents: T[]; next: string | null }> {
+    try {
+      const json = JSON.stringify(query);
+      const result = this.wasmCollection.find_page(json, JSON.stringify(options));
+      
+      return JSON.parse(result);
+    } catch (error) {
+      console.error(`Failed to find page of documents in ${this.name}:`, error);
+      throw error;
+    }
+  }
+  
+  /**
+   * Open a cursor over the results of a query, read in batches rather than
+   * serialized all at once
+   */
+  findCursor(query: Query = {}, options: FindOptions = {}): WasmCursor<T> {
+    try {
+      const json = JSON.stringify(query);
+      
+      return new WasmCursor<T>(this.wasmCollection.find_cursor(json, JSON.stringify(options)));
+    } catch (error) {
+      console.error(`Failed to open cursor on ${this.name}:`, error);
+      throw error;
+    }
+  }
+  
+  /**
+   * Run an aggregation pipeline, such as
+   * `[{ $match: {...} }, { $group: { id: '$city', n: { $count: {} } } }]`
+   */
+  async aggregate<R = any>(pipeline: Record<string, any>[]): Promise<R[]> {
+    try {
+      const json = JSON.stringify(pipeline);
+      const results = this.wasmCollection.aggregate(json);
+      
+      return JSON.parse(results);
+    } catch (error) {
+      console.error(`Failed to aggregate documents in ${this.name}:`, error);
+      throw error;
+    }
+  }
+  
   /**
    * Update documents
    */
@@ -251,19 +427,55 @@ export class WasmCollection<T extends Document = Document> {
     }
   }
   
+  /**
+   * Delete documents expired by TTL indexes
+   */
+  async expire(now: number = Date.now()): Promise<number> {
+    try {
+      return this.wasmCollection.expire(now);
+    } catch (error) {
+      console.error(`Failed to expire documents in ${this.name}:`, error);
+      throw error;
+    }
+  }
+  
+  /**
+   * Find the k documents whose vector field is nearest to the given vector,
+   * optionally restricted to documents matching a query
+   */
+  async vectorSearch(field: string, vector: number[], k: number, filter: Query = {}): Promise<(T & { $vectorScore: number })[]> {
+    try {
+      const results = this.wasmCollection.vector_search(field, JSON.stringify(vector), k, JSON.stringify(filter));
+      
+      return JSON.parse(results);
+    } catch (error) {
+      console.error(`Failed to run vector search in ${this.name}:`, error);
+      throw error;
+    }
+  }
+  
   /**
    * Create an index
    */
   async createIndex(options: {
     name: string;
     fields: string[];
-    type?: 'single' | 'unique' | 'multi';
+    type?: 'single' | 'unique' | 'multi' | 'ordered' | 'text' | 'vector' | '2d' | '2dsphere';
+    sparse?: boolean;
+    partialFilterExpression?: Query;
+    stemming?: boolean;
+    metric?: 'cosine' | 'dotProduct' | 'euclidean';
+    dimensions?: number;
+    hnsw?: { m?: number; efConstruction?: number; efSearch?: number };
+    cellSize?: number;
+    expireAfterSeconds?: number;
   }): Promise<void> {
     try {
-      const { name, fields, type = 'single' } = options;
+      const { name, fields, type = 'single', ...indexOptions } = options;
       const fieldsJson = JSON.stringify(fields);
+      const optionsJson = JSON.stringify(indexOptions);
       
-      this.wasmCollection.create_index(name, fieldsJson, type);
+      this.wasmCollection.create_index(name, fieldsJson, type, optionsJson);
     } catch (error) {
       console.error(`Failed to create index in ${this.name}:`, error);
       throw error;
@@ -296,6 +508,39 @@ export class WasmCollection<T extends Document = Document> {
     }
   }
   
+  /**
+   * Describe how a query is answered: the chosen plan, the plans passed over
+   * and why, and unless verbosity is 'queryPlanner' the work done running it
+   */
+  async explain(query: Query = {}, options: { verbosity?: 'queryPlanner' | 'executionStats' } = {}): Promise<{
+    winningPlan: QueryPlan;
+    rejectedPlans: (Partial<QueryPlan> & { index?: string | null; reason: string })[];
+    executionStats?: { keysExamined: number; docsExamined: number; nReturned: number; executionTimeMillis: number };
+  }> {
+    try {
+      const json = this.wasmCollection.explain(JSON.stringify(query), JSON.stringify(options));
+      
+      return JSON.parse(json);
+    } catch (error) {
+      console.error(`Failed to explain query in ${this.name}:`, error);
+      throw error;
+    }
+  }
+  
+  /**
+   * Get the size statistics the query planner uses, keyed by index name
+   */
+  async getIndexStats(): Promise<Record<string, { distinctKeys: number; entries: number; avgBucketSize: number }>> {
+    try {
+      const json = this.wasmCollection.get_index_stats();
+      
+      return JSON.parse(json);
+    } catch (error) {
+      console.error(`Failed to get index stats from ${this.name}:`, error);
+      throw error;
+    }
+  }
+  
   /**
    * Subscribe to changes
    * 
@@ -0,0 +1,797 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use crate::expr::{self, Expr};
+use crate::find::{self, SortSpec};
+use crate::key;
+use crate::query::Query;
+
+// A stage of an aggregation pipeline, written as `{"$stage": spec}`
+#[derive(Clone)]
+pub enum PipelineStage {
+    Match(Query),
+    Project(ProjectSpec),
+    AddFields(Vec<(String, Expr)>),
+    // Group key expression and (field, accumulator, expression) outputs
+    Group(Expr, Vec<(String, Accumulator, Expr)>),
+    Sort(SortSpec),
+    Skip(usize),
+    Limit(usize),
+    Unwind(UnwindSpec),
+    // Field to hold the number of documents
+    Count(String),
+    Facet(BTreeMap<String, Vec<PipelineStage>>),
+    Lookup(LookupSpec),
+}
+
+// Joins the documents of a $lookup stage with the collection it names
+pub type Lookup<'a> = dyn Fn(&LookupSpec, Vec<Value>) -> Result<Vec<Value>, String> + 'a;
+
+impl<'de> Deserialize<'de> for PipelineStage {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct StageVisitor;
+        
+        impl<'de> Visitor<'de> for StageVisitor {
+            type Value = PipelineStage;
+            
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object with a single stage such as {\"$match\": {...}}")
+            }
+            
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<PipelineStage, A::Error> {
+                let name: String = map.next_key()?
+                    .ok_or_else(|| de::Error::custom("Pipeline stage is empty"))?;
+                
+                // $sort and stages holding pipelines are read straight from
+                // the input, which keeps the order of sort keys that a
+                // `Value` would lose
+                let stage = match name.as_str() {
+                    "$match" => PipelineStage::Match(map.next_value()?),
+                    "$sort" => {
+                        let sort: SortSpec = map.next_value()?;
+                        if sort.uses_text_score() {
+                            return Err(de::Error::custom("$sort stages cannot sort by $meta textScore"));
+                        }
+                        PipelineStage::Sort(sort)
+                    },
+                    "$facet" => {
+                        let facets: BTreeMap<String, Vec<PipelineStage>> = map.next_value()?;
+                        for pipeline in facets.values() {
+                            check_text_stages(pipeline, false).map_err(de::Error::custom)?;
+                        }
+                        PipelineStage::Facet(facets)
+                    },
+                    "$lookup" => {
+                        let lookup: LookupSpec = map.next_value()?;
+                        lookup.validate().map_err(de::Error::custom)?;
+                        PipelineStage::Lookup(lookup)
+                    },
+                    _ => PipelineStage::parse(&name, map.next_value()?).map_err(de::Error::custom)?,
+                };
+                
+                if map.next_key::<String>()?.is_some() {
+                    return Err(de::Error::custom(format!("Pipeline stage {} has more than one field", name)));
+                }
+                
+                Ok(stage)
+            }
+        }
+        
+        deserializer.deserialize_map(StageVisitor)
+    }
+}
+
+impl PipelineStage {
+    fn parse(name: &str, spec: Value) -> Result<Self, String> {
+        match name {
+            "$project" => ProjectSpec::parse(&spec).map(PipelineStage::Project),
+            "$addFields" => match spec {
+                Value::Object(fields) => fields.iter()
+                    .map(|(path, expr)| Expr::parse(expr).map(|expr| (path.clone(), expr)))
+                    .collect::<Result<_, _>>()
+                    .map(PipelineStage::AddFields),
+                _ => Err("$addFields must be an object".to_string()),
+            },
+            "$group" => parse_group(spec),
+            "$skip" => count_of(&spec, name).map(PipelineStage::Skip),
+            "$limit" => match count_of(&spec, name)? {
+                0 => Err("$limit must be positive".to_string()),
+                limit => Ok(PipelineStage::Limit(limit)),
+            },
+            "$unwind" => UnwindSpec::parse(spec).map(PipelineStage::Unwind),
+            "$count" => match spec {
+                Value::String(field) if !field.is_empty() && !field.starts_with('$') && !field.contains('.') => {
+                    Ok(PipelineStage::Count(field))
+                },
+                _ => Err("$count must be a field name".to_string()),
+            },
+            _ => Err(format!("Unknown pipeline stage: {}", name)),
+        }
+    }
+    
+    // Copy of the stage with each of the variables replaced by its value
+    pub fn bind(&self, variables: &Map<String, Value>) -> Result<PipelineStage, String> {
+        let bind = |expr: &Expr| expr.bind(variables);
+        let bind_pipeline = |pipeline: &Vec<PipelineStage>| pipeline.iter()
+            .map(|stage| stage.bind(variables))
+            .collect::<Result<Vec<_>, String>>();
+        
+        Ok(match self {
+            PipelineStage::Match(query) => PipelineStage::Match(query.bind(variables)?),
+            PipelineStage::Project(spec) => PipelineStage::Project(ProjectSpec {
+                fields: spec.fields.iter()
+                    .map(|(path, field)| match field {
+                        ProjectField::Computed(expr) => (path.clone(), ProjectField::Computed(bind(expr))),
+                        _ => (path.clone(), field.clone()),
+                    })
+                    .collect(),
+                ..spec.clone()
+            }),
+            PipelineStage::AddFields(fields) => PipelineStage::AddFields(fields.iter()
+                .map(|(path, expr)| (path.clone(), bind(expr)))
+                .collect()),
+            PipelineStage::Group(id, accumulators) => PipelineStage::Group(bind(id), accumulators.iter()
+                .map(|(field, accumulator, expr)| (field.clone(), *accumulator, bind(expr)))
+                .collect()),
+            PipelineStage::Facet(facets) => PipelineStage::Facet(facets.iter()
+                .map(|(name, pipeline)| Ok((name.clone(), bind_pipeline(pipeline)?)))
+                .collect::<Result<_, String>>()?),
+            PipelineStage::Lookup(spec) => PipelineStage::Lookup(LookupSpec {
+                variables: spec.variables.iter()
+                    .map(|(name, expr)| (name.clone(), bind(expr)))
+                    .collect(),
+                pipeline: spec.pipeline.as_ref().map(bind_pipeline).transpose()?,
+                ..spec.clone()
+            }),
+            stage => stage.clone(),
+        })
+    }
+}
+
+fn count_of(spec: &Value, name: &str) -> Result<usize, String> {
+    spec.as_u64()
+        .map(|n| n as usize)
+        .ok_or_else(|| format!("{} must be a non-negative integer", name))
+}
+
+// Run the stages over documents in order
+pub fn run(stages: &[PipelineStage], mut docs: Vec<Value>, lookup: &Lookup) -> Result<Vec<Value>, String> {
+    for (i, stage) in stages.iter().enumerate() {
+        docs = match stage {
+            PipelineStage::Match(query) => {
+                docs.retain(|doc| doc.is_object() && query.matches(doc));
+                docs
+            },
+            PipelineStage::Project(spec) => docs.into_iter()
+                .map(|doc| spec.apply(doc))
+                .collect::<Result<_, _>>()?,
+            PipelineStage::AddFields(fields) => docs.into_iter()
+                .map(|doc| add_fields(doc, fields))
+                .collect::<Result<_, _>>()?,
+            PipelineStage::Group(id, accumulators) => group(docs, id, accumulators)?,
+            PipelineStage::Sort(sort) => {
+                find::sort_values(&mut docs, sort, leading_limit(&stages[i + 1..]));
+                docs
+            },
+            PipelineStage::Skip(skip) => docs.into_iter().skip(*skip).collect(),
+            PipelineStage::Limit(limit) => {
+                docs.truncate(*limit);
+                docs
+            },
+            PipelineStage::Unwind(spec) => spec.apply(docs),
+            PipelineStage::Count(field) => {
+                if docs.is_empty() {
+                    docs
+                } else {
+                    let mut counted = Map::new();
+                    counted.insert(field.clone(), docs.len().into());
+                    vec![Value::Object(counted)]
+                }
+            },
+            PipelineStage::Facet(facets) => {
+                let mut results = Map::new();
+                for (name, pipeline) in facets {
+                    results.insert(name.clone(), Value::Array(run(pipeline, docs.clone(), lookup)?));
+                }
+                vec![Value::Object(results)]
+            },
+            PipelineStage::Lookup(spec) => lookup(spec, docs)?,
+        };
+    }
+    
+    Ok(docs)
+}
+
+// Reject $match stages searching $text anywhere but at the start of a
+// top-level pipeline, the only place the collection's text index answers
+// them. Pipelines nested in $facet and $lookup are checked as they are parsed.
+pub fn check_text_stages(stages: &[PipelineStage], top_level: bool) -> Result<(), String> {
+    let searching = stages.iter()
+        .enumerate()
+        .any(|(i, stage)| matches!(stage, PipelineStage::Match(query) if query.has_text() && !(top_level && i == 0)));
+    
+    if searching {
+        return Err("$text is only allowed in the first $match stage of a pipeline".to_string());
+    }
+    
+    Ok(())
+}
+
+// How many leading documents are read by stages that begin with $skip and
+// $limit, so a sort before them only has to order that many
+pub fn leading_limit(stages: &[PipelineStage]) -> Option<usize> {
+    let mut skipped: usize = 0;
+    
+    for stage in stages {
+        match stage {
+            PipelineStage::Skip(skip) => skipped = skipped.saturating_add(*skip),
+            PipelineStage::Limit(limit) => return Some(skipped.saturating_add(*limit)),
+            _ => return None,
+        }
+    }
+    
+    None
+}
+
+#[derive(Clone)]
+pub enum ProjectField {
+    Include,
+    Exclude,
+    Computed(Expr),
+}
+
+// Fields kept, dropped or computed by a $project stage
+#[derive(Clone)]
+pub struct ProjectSpec {
+    // Whether only the listed and computed fields are kept
+    inclusive: bool,
+    include_id: bool,
+    fields: Vec<(String, ProjectField)>,
+}
+
+impl ProjectSpec {
+    fn parse(spec: &Value) -> Result<Self, String> {
+        let spec = spec.as_object().ok_or("$project must be an object")?;
+        let mut inclusive = None;
+        let mut include_id = true;
+        let mut fields = Vec::new();
+        
+        for (path, rule) in spec {
+            let field = match rule {
+                Value::Bool(b) if *b => ProjectField::Include,
+                Value::Bool(_) => ProjectField::Exclude,
+                Value::Number(n) if n.as_f64() == Some(0.0) => ProjectField::Exclude,
+                Value::Number(_) => ProjectField::Include,
+                expr => ProjectField::Computed(Expr::parse(expr)?),
+            };
+            
+            if path == "id" && !matches!(field, ProjectField::Computed(_)) {
+                include_id = matches!(field, ProjectField::Include);
+                continue;
+            }
+            
+            let includes = !matches!(field, ProjectField::Exclude);
+            if *inclusive.get_or_insert(includes) != includes {
+                return Err("$project cannot both include and exclude fields".to_string());
+            }
+            fields.push((path.clone(), field));
+        }
+        
+        Ok(ProjectSpec {
+            // A projection of only the id keeps just the id
+            inclusive: inclusive.unwrap_or(include_id && !spec.is_empty()),
+            include_id,
+            fields,
+        })
+    }
+    
+    fn apply(&self, doc: Value) -> Result<Value, String> {
+        let source = match &doc {
+            Value::Object(obj) => obj,
+            _ => return Ok(doc),
+        };
+        
+        let mut projected = if self.inclusive {
+            let included: Vec<&str> = self.fields.iter()
+                .filter(|(_, field)| matches!(field, ProjectField::Include))
+                .map(|(path, _)| path.as_str())
+                .collect();
+            find::include_paths(source, &included)
+        } else {
+            source.clone()
+        };
+        
+        if !self.include_id {
+            projected.remove("id");
+        } else if let Some(id) = source.get("id") {
+            projected.insert("id".to_string(), id.clone());
+        }
+        
+        for (path, field) in &self.fields {
+            match field {
+                ProjectField::Include => {
+                    // Kept above
+                },
+                ProjectField::Exclude => find::remove_path(&mut projected, path),
+                ProjectField::Computed(expr) => {
+                    if let Some(value) = expr.evaluate(&doc)? {
+                        find::set_path(&mut projected, path, value.into_owned());
+                    }
+                },
+            }
+        }
+        
+        Ok(Value::Object(projected))
+    }
+}
+
+fn add_fields(doc: Value, fields: &[(String, Expr)]) -> Result<Value, String> {
+    // Every expression sees the document as it came in
+    let mut obj = match &doc {
+        Value::Object(obj) => obj.clone(),
+        _ => return Ok(doc),
+    };
+    
+    for (path, expr) in fields {
+        if let Some(value) = expr.evaluate(&doc)? {
+            find::set_path(&mut obj, path, value.into_owned());
+        }
+    }
+    
+    Ok(Value::Object(obj))
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Accumulator {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+    Push,
+    AddToSet,
+    First,
+    Last,
+}
+
+fn parse_group(spec: Value) -> Result<PipelineStage, String> {
+    let spec = match spec {
+        Value::Object(spec) => spec,
+        _ => return Err("$group must be an object".to_string()),
+    };
+    
+    let id = Expr::parse(spec.get("id").ok_or("$group requires an id expression")?)?;
+    let mut accumulators = Vec::new();
+    
+    for (field, rule) in spec {
+        if field == "id" {
+            continue;
+        }
+        
+        let (op, expr) = match rule.as_object() {
+            Some(obj) if obj.len() == 1 => obj.iter().next().expect("object has one entry"),
+            _ => return Err(format!("Invalid accumulator for '{}': {}", field, rule)),
+        };
+        
+        let accumulator = match op.as_str() {
+            "$sum" => Accumulator::Sum,
+            "$avg" => Accumulator::Avg,
+            "$min" => Accumulator::Min,
+            "$max" => Accumulator::Max,
+            "$count" => Accumulator::Count,
+            "$push" => Accumulator::Push,
+            "$addToSet" => Accumulator::AddToSet,
+            "$first" => Accumulator::First,
+            "$last" => Accumulator::Last,
+            _ => return Err(format!("Unknown accumulator: {}", op)),
+        };
+        
+        accumulators.push((field.clone(), accumulator, Expr::parse(expr)?));
+    }
+    
+    Ok(PipelineStage::Group(id, accumulators))
+}
+
+// Running value of one accumulator over one group
+enum Accumulated {
+    Sum(f64),
+    Avg(f64, usize),
+    // Smallest or largest value so far
+    Extreme(Option<Value>),
+    Count(usize),
+    Values(Vec<Value>),
+    Set(Vec<Value>, HashSet<Vec<u8>>),
+    Single(Option<Value>),
+}
+
+impl Accumulator {
+    fn start(self) -> Accumulated {
+        match self {
+            Accumulator::Sum => Accumulated::Sum(0.0),
+            Accumulator::Avg => Accumulated::Avg(0.0, 0),
+            Accumulator::Min | Accumulator::Max => Accumulated::Extreme(None),
+            Accumulator::Count => Accumulated::Count(0),
+            Accumulator::Push => Accumulated::Values(Vec::new()),
+            Accumulator::AddToSet => Accumulated::Set(Vec::new(), HashSet::new()),
+            Accumulator::First | Accumulator::Last => Accumulated::Single(None),
+        }
+    }
+    
+    // Fold in the expression's value for the next document of the group.
+    // Sums and averages skip non-numbers, $min and $max skip nulls and
+    // $push and $addToSet skip missing fields.
+    fn add(self, state: &mut Accumulated, value: Option<Cow<Value>>) {
+        match (self, state) {
+            (Accumulator::Sum, Accumulated::Sum(sum)) => {
+                *sum += value.and_then(|v| v.as_f64()).unwrap_or(0.0);
+            },
+            (Accumulator::Avg, Accumulated::Avg(sum, count)) => {
+                if let Some(n) = value.and_then(|v| v.as_f64()) {
+                    *sum += n;
+                    *count += 1;
+                }
+            },
+            (Accumulator::Min | Accumulator::Max, Accumulated::Extreme(extreme)) => {
+                let value = match value {
+                    Some(value) if !value.is_null() => value,
+                    _ => return,
+                };
+                let replaces = match extreme {
+                    None => true,
+                    Some(current) => match self {
+                        Accumulator::Min => key::compare(&value, current).is_lt(),
+                        _ => key::compare(&value, current).is_gt(),
+                    },
+                };
+                if replaces {
+                    *extreme = Some(value.into_owned());
+                }
+            },
+            (Accumulator::Count, Accumulated::Count(count)) => *count += 1,
+            (Accumulator::Push, Accumulated::Values(values)) => values.extend(value.map(Cow::into_owned)),
+            (Accumulator::AddToSet, Accumulated::Set(values, seen)) => {
+                if let Some(value) = value {
+                    if seen.insert(key::encode_value(&value)) {
+                        values.push(value.into_owned());
+                    }
+                }
+            },
+            (Accumulator::First, Accumulated::Single(first)) => {
+                if first.is_none() {
+                    *first = Some(value.map_or(Value::Null, Cow::into_owned));
+                }
+            },
+            (Accumulator::Last, Accumulated::Single(last)) => *last = Some(value.map_or(Value::Null, Cow::into_owned)),
+            _ => unreachable!("accumulator state started by the same accumulator"),
+        }
+    }
+}
+
+impl Accumulated {
+    fn finish(self) -> Value {
+        match self {
+            Accumulated::Sum(sum) => expr::number(sum),
+            Accumulated::Avg(_, 0) => Value::Null,
+            Accumulated::Avg(sum, count) => expr::number(sum / count as f64),
+            Accumulated::Extreme(extreme) => extreme.unwrap_or(Value::Null),
+            Accumulated::Count(count) => count.into(),
+            Accumulated::Values(values) | Accumulated::Set(values, _) => Value::Array(values),
+            Accumulated::Single(value) => value.unwrap_or(Value::Null),
+        }
+    }
+}
+
+// One output document per distinct id, in order of first appearance
+fn group(docs: Vec<Value>, id: &Expr, accumulators: &[(String, Accumulator, Expr)]) -> Result<Vec<Value>, String> {
+    let mut groups: Vec<(Value, Vec<Accumulated>)> = Vec::new();
+    let mut positions: HashMap<Vec<u8>, usize> = HashMap::new();
+    
+    for doc in &docs {
+        let group_id = id.evaluate(doc)?.unwrap_or(Cow::Owned(Value::Null));
+        
+        let position = *positions.entry(key::encode_value(&group_id)).or_insert_with(|| {
+            let states = accumulators.iter().map(|(_, accumulator, _)| accumulator.start()).collect();
+            groups.push((group_id.into_owned(), states));
+            groups.len() - 1
+        });
+        
+        let states = &mut groups[position].1;
+        for ((_, accumulator, expr), state) in accumulators.iter().zip(states.iter_mut()) {
+            accumulator.add(state, expr.evaluate(doc)?);
+        }
+    }
+    
+    Ok(groups.into_iter()
+        .map(|(group_id, states)| {
+            let mut result = Map::new();
+            result.insert("id".to_string(), group_id);
+            for ((field, _, _), state) in accumulators.iter().zip(states) {
+                result.insert(field.clone(), state.finish());
+            }
+            Value::Object(result)
+        })
+        .collect())
+}
+
+// Options of an $unwind stage, which may also be given as just the path
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct UnwindSpec {
+    path: String,
+    #[serde(default)]
+    preserve_null_and_empty_arrays: bool,
+    // Field to hold each element's position in the array
+    #[serde(default)]
+    include_array_index: Option<String>,
+}
+
+impl UnwindSpec {
+    fn parse(spec: Value) -> Result<Self, String> {
+        let mut unwind = match spec {
+            Value::String(path) => UnwindSpec {
+                path,
+                preserve_null_and_empty_arrays: false,
+                include_array_index: None,
+            },
+            spec => serde_json::from_value(spec).map_err(|e| format!("Invalid $unwind: {}", e))?,
+        };
+        
+        unwind.path = unwind.path.strip_prefix('$')
+            .ok_or("$unwind path must start with '$'")?
+            .to_string();
+        Ok(unwind)
+    }
+    
+    // One document per element of the array at the path. Documents where it
+    // is missing, null or empty are dropped unless preserved, and other
+    // values pass through as they are.
+    fn apply(&self, docs: Vec<Value>) -> Vec<Value> {
+        let mut unwound = Vec::with_capacity(docs.len());
+        
+        for doc in docs {
+            let mut obj = match doc {
+                Value::Object(obj) => obj,
+                other => {
+                    unwound.push(other);
+                    continue;
+                },
+            };
+            
+            match find::get_path(&obj, &self.path).cloned() {
+                Some(Value::Array(items)) if !items.is_empty() => {
+                    for (i, item) in items.into_iter().enumerate() {
+                        let mut copy = obj.clone();
+                        find::set_path(&mut copy, &self.path, item);
+                        if let Some(field) = &self.include_array_index {
+                            find::set_path(&mut copy, field, i.into());
+                        }
+                        unwound.push(Value::Object(copy));
+                    }
+                },
+                None | Some(Value::Null) | Some(Value::Array(_)) if !self.preserve_null_and_empty_arrays => {},
+                _ => {
+                    if let Some(field) = &self.include_array_index {
+                        find::set_path(&mut obj, field, Value::Null);
+                    }
+                    unwound.push(Value::Object(obj));
+                },
+            }
+        }
+        
+        unwound
+    }
+}
+
+// Options of a $lookup stage: the documents of the `from` collection whose
+// `foreign_field` equals the document's `local_field`, run through
+// `pipeline` if given, are stored in the `output` field. Variables in `let`
+// are evaluated against the document and bound into the pipeline.
+#[derive(Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct LookupSpec {
+    pub from: String,
+    #[serde(default)]
+    pub local_field: Option<String>,
+    #[serde(default)]
+    pub foreign_field: Option<String>,
+    #[serde(rename = "let", default)]
+    pub variables: BTreeMap<String, Expr>,
+    #[serde(default)]
+    pub pipeline: Option<Vec<PipelineStage>>,
+    #[serde(rename = "as")]
+    pub output: String,
+}
+
+impl LookupSpec {
+    pub fn validate(&self) -> Result<(), String> {
+        match (&self.local_field, &self.foreign_field) {
+            (Some(_), Some(_)) => {},
+            (None, None) if self.pipeline.is_some() => {},
+            (None, None) => return Err("$lookup needs localField and foreignField, or a pipeline".to_string()),
+            _ => return Err("$lookup needs both localField and foreignField".to_string()),
+        }
+        
+        check_text_stages(self.pipeline.as_deref().unwrap_or_default(), false)
+    }
+    
+    // Whether every document joins with the same results
+    pub fn is_uncorrelated(&self) -> bool {
+        self.local_field.is_none() && self.variables.is_empty()
+    }
+    
+    // The pipeline with the `let` variables bound for a document
+    pub fn bound_pipeline(&self, doc: &Value) -> Result<Vec<PipelineStage>, String> {
+        let mut variables = Map::new();
+        for (name, expr) in &self.variables {
+            variables.insert(name.clone(), expr.evaluate(doc)?.map_or(Value::Null, Cow::into_owned));
+        }
+        
+        self.pipeline.iter()
+            .flatten()
+            .map(|stage| stage.bind(&variables))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    const TEXT_ERROR: &str = "$text is only allowed in the first $match stage of a pipeline";
+    
+    fn check(pipeline: Value) -> Result<(), String> {
+        let stages: Vec<PipelineStage> = serde_json::from_value(pipeline).map_err(|e| e.to_string())?;
+        check_text_stages(&stages, true)
+    }
+    
+    // Pipelines are parsed from text since `json!` objects lose their key
+    // order, which $sort depends on
+    fn aggregate(pipeline: &str) -> Result<Vec<Value>, String> {
+        let stages: Vec<PipelineStage> = serde_json::from_str(pipeline).map_err(|e| e.to_string())?;
+        run(&stages, people(), &|_, _| Err("no lookups here".to_string()))
+    }
+    
+    fn people() -> Vec<Value> {
+        vec![
+            json!({"id": "a", "city": "Paris", "age": 30, "tags": ["x", "y"], "score": 1.5}),
+            json!({"id": "b", "city": "Paris", "age": 40, "tags": [], "score": 2}),
+            json!({"id": "c", "city": "Lyon", "age": 25, "tags": ["y"]}),
+            json!({"id": "d", "city": "Lyon", "age": 35, "score": 4}),
+            json!({"id": "e", "age": 50, "tags": "z"}),
+        ]
+    }
+    
+    #[test]
+    fn text_only_leads_a_top_level_pipeline() {
+        let search = json!({"$match": {"$text": {"$search": "rust"}}});
+        assert!(check(json!([search, {"$limit": 1}])).is_ok());
+        
+        for pipeline in [
+            json!([{"$limit": 1}, search]),
+            json!([{"$match": {"a": 1}}, search]),
+            json!([{"$facet": {"hits": [search]}}]),
+            json!([{"$lookup": {"from": "notes", "pipeline": [search], "as": "notes"}}]),
+            json!([{"$lookup": {"from": "notes", "pipeline": [{"$facet": {"hits": [search]}}], "as": "notes"}}]),
+        ] {
+            let error = check(pipeline).unwrap_err();
+            assert!(error.contains(TEXT_ERROR), "{}", error);
+        }
+    }
+    
+    #[test]
+    fn lookup_validation() {
+        let lookup = |spec: Value| serde_json::from_value::<LookupSpec>(spec).unwrap().validate();
+        assert!(lookup(json!({"from": "b", "localField": "x", "foreignField": "y", "as": "out"})).is_ok());
+        assert_eq!(lookup(json!({"from": "b", "localField": "x", "as": "out"})).unwrap_err(), "$lookup needs both localField and foreignField");
+        assert_eq!(lookup(json!({"from": "b", "as": "out"})).unwrap_err(), "$lookup needs localField and foreignField, or a pipeline");
+    }
+    
+    #[test]
+    fn groups_accumulate_per_key() {
+        let groups = aggregate(r#"[
+            {"$group": {
+                "id": "$city",
+                "n": {"$count": {}},
+                "total": {"$sum": "$age"},
+                "avg": {"$avg": "$score"},
+                "min": {"$min": "$score"},
+                "max": {"$max": "$age"},
+                "ids": {"$push": "$id"},
+                "first": {"$first": "$score"},
+                "last": {"$last": "$score"}
+            }},
+            {"$sort": {"total": -1}}
+        ]"#).unwrap();
+        
+        assert_eq!(groups, [
+            json!({"id": "Paris", "n": 2, "total": 70, "avg": 1.75, "min": 1.5, "max": 40, "ids": ["a", "b"], "first": 1.5, "last": 2}),
+            json!({"id": "Lyon", "n": 2, "total": 60, "avg": 4, "min": 4, "max": 35, "ids": ["c", "d"], "first": null, "last": 4}),
+            json!({"id": null, "n": 1, "total": 50, "avg": null, "min": null, "max": 50, "ids": ["e"], "first": null, "last": null}),
+        ]);
+        
+        let groups = aggregate(r#"[{"$unwind": "$tags"}, {"$group": {"id": null, "tags": {"$addToSet": "$tags"}}}]"#).unwrap();
+        assert_eq!(groups, [json!({"id": null, "tags": ["x", "y", "z"]})]);
+    }
+    
+    #[test]
+    fn unwind_can_keep_empty_arrays() {
+        let unwound = aggregate(r#"[
+            {"$unwind": {"path": "$tags", "preserveNullAndEmptyArrays": true, "includeArrayIndex": "i"}},
+            {"$project": {"tags": 1, "i": 1, "id": 0}}
+        ]"#).unwrap();
+        
+        assert_eq!(unwound, [
+            json!({"i": 0, "tags": "x"}),
+            json!({"i": 1, "tags": "y"}),
+            json!({"i": null, "tags": []}),
+            json!({"i": 0, "tags": "y"}),
+            json!({"i": null}),
+            json!({"i": null, "tags": "z"}),
+        ]);
+        assert_eq!(aggregate(r#"[{"$unwind": "$tags"}]"#).unwrap().len(), 4);
+    }
+    
+    #[test]
+    fn stages_run_in_order() {
+        let ids = |docs: Vec<Value>| -> Vec<Value> { docs.into_iter().map(|doc| doc["id"].clone()).collect() };
+        
+        assert_eq!(ids(aggregate(r#"[{"$sort": {"city": 1, "age": -1}}, {"$skip": 1}, {"$limit": 2}]"#).unwrap()), ["d", "c"]);
+        assert_eq!(ids(aggregate(r#"[{"$limit": 2}, {"$sort": {"age": -1}}]"#).unwrap()), ["b", "a"]);
+        assert_eq!(aggregate(r#"[{"$match": {"age": {"$gt": 28}}}, {"$count": "n"}]"#).unwrap(), [json!({"n": 4})]);
+        assert!(aggregate(r#"[{"$match": {"age": {"$gt": 100}}}, {"$count": "n"}]"#).unwrap().is_empty());
+        
+        let added = aggregate(r#"[
+            {"$addFields": {"info.c": "$city", "k": {"$literal": "$x"}, "pair": ["$age", 2]}},
+            {"$match": {"info.c": "Lyon"}},
+            {"$project": {"info": 1, "k": 1, "pair": 1, "id": 0}}
+        ]"#).unwrap();
+        assert_eq!(added, [
+            json!({"info": {"c": "Lyon"}, "k": "$x", "pair": [25, 2]}),
+            json!({"info": {"c": "Lyon"}, "k": "$x", "pair": [35, 2]}),
+        ]);
+        
+        let faceted = aggregate(r#"[{"$facet": {
+            "young": [{"$match": {"age": {"$lt": 31}}}, {"$count": "n"}],
+            "oldest": [{"$sort": {"age": -1}}, {"$limit": 1}, {"$project": {"id": 1}}]
+        }}]"#).unwrap();
+        assert_eq!(faceted, [json!({"young": [{"n": 2}], "oldest": [{"id": "e"}]})]);
+        
+        let error = aggregate(r#"[{"$lookup": {"from": "b", "localField": "x", "foreignField": "y", "as": "out"}}]"#).unwrap_err();
+        assert_eq!(error, "no lookups here");
+    }
+    
+    #[test]
+    fn leading_limits_see_through_skips() {
+        let stages = |pipeline: &str| -> Vec<PipelineStage> { serde_json::from_str(pipeline).unwrap() };
+        
+        assert_eq!(leading_limit(&stages(r#"[{"$skip": 3}, {"$limit": 5}]"#)), Some(8));
+        assert_eq!(leading_limit(&stages(r#"[{"$limit": 5}, {"$skip": 3}]"#)), Some(5));
+        assert_eq!(leading_limit(&stages(r#"[{"$skip": 3}]"#)), None);
+        assert_eq!(leading_limit(&stages(r#"[{"$match": {}}, {"$limit": 5}]"#)), None);
+    }
+    
+    #[test]
+    fn rejects_invalid_stages() {
+        for pipeline in [
+            r#"[{"$nope": 1}]"#,
+            r#"[{"$limit": 0}]"#,
+            r#"[{"$skip": -1}]"#,
+            r#"[{"$project": {"a": 1, "b": 0}}]"#,
+            r#"[{"$group": {"n": {"$sum": 1}}}]"#,
+            r#"[{"$group": {"id": 1, "n": {"$bad": 1}}}]"#,
+            r#"[{"$unwind": "tags"}]"#,
+            r#"[{"$match": {}, "$limit": 1}]"#,
+            r#"[{"$match": {"a": {"$in": 1}}}]"#,
+            r#"[{"$count": "$x"}]"#,
+            r#"[{"$sort": {"a": {"$meta": "textScore"}}}]"#,
+            r#"[{"$project": {"a": {"$nope": [1, 2]}}}]"#,
+            r#"{}"#,
+        ] {
+            assert!(aggregate(pipeline).is_err(), "{}", pipeline);
+        }
+    }
+}
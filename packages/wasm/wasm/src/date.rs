@@ -0,0 +1,225 @@
+use serde_json::Value;
+
+// Furthest a JS Date reaches from the epoch, in milliseconds, and a year
+// safely beyond it in either direction
+pub const MAX_MILLIS: f64 = 8.64e15;
+const MAX_YEAR: i64 = 300_000;
+
+// Milliseconds since the Unix epoch of a date value: a number of epoch
+// milliseconds or an ISO 8601 string such as `JSON.stringify(new Date())` produces
+pub fn to_millis(value: &Value) -> Option<f64> {
+    match value {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => parse_iso(s),
+        _ => None,
+    }
+}
+
+// Parse `YYYY-MM-DD[THH:MM[:SS[.fff]]][Z|±HH:MM]`, reading times without an
+// offset as UTC
+pub fn parse_iso(s: &str) -> Option<f64> {
+    let s = s.trim();
+    let (date, time) = match s.find(['T', 't', ' ']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: u32 = date_parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || day < 1 || day > days_in_month(year, month) {
+        return None;
+    }
+    
+    let mut millis = days_from_civil(year, month, day) as f64 * 86_400_000.0;
+    
+    if let Some(time) = time {
+        // Split off the offset
+        let (clock, offset_minutes) = if let Some(clock) = time.strip_suffix(['Z', 'z']) {
+            (clock, 0)
+        } else if let Some(i) = time.rfind(['+', '-']) {
+            let (clock, offset) = time.split_at(i);
+            let sign = if offset.starts_with('-') { -1 } else { 1 };
+            let digits: String = offset[1..].chars().filter(|c| *c != ':').collect();
+            // Checked before slicing so a stray multibyte character cannot
+            // split the string mid-character
+            if digits.len() != 4 || !digits.bytes().all(|b| b.is_ascii_digit()) {
+                return None;
+            }
+            let hours: i64 = digits[..2].parse().ok()?;
+            let minutes: i64 = digits[2..].parse().ok()?;
+            (clock, sign * (hours * 60 + minutes))
+        } else {
+            (time, 0)
+        };
+        
+        let mut clock_parts = clock.splitn(3, ':');
+        let hours: u32 = clock_parts.next()?.parse().ok()?;
+        let minutes: u32 = clock_parts.next()?.parse().ok()?;
+        let seconds: f64 = match clock_parts.next() {
+            Some(seconds) => seconds.parse().ok()?,
+            None => 0.0,
+        };
+        if hours > 23 || minutes > 59 || !(0.0..60.0).contains(&seconds) {
+            return None;
+        }
+        
+        millis += (hours as f64 * 3600.0 + minutes as f64 * 60.0 + seconds) * 1000.0;
+        millis -= offset_minutes as f64 * 60_000.0;
+    }
+    
+    Some(millis)
+}
+
+// Days since 1970-01-01 of a proleptic Gregorian date (Howard Hinnant's algorithm)
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// Proleptic Gregorian date of a number of days since 1970-01-01, the inverse
+// of `days_from_civil`
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// Calendar fields of an instant in UTC
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DateParts {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub millisecond: u32,
+    // Days since 1970-01-01
+    pub days: i64,
+}
+
+impl DateParts {
+    pub fn from_millis(millis: f64) -> Self {
+        let millis = millis.floor() as i64;
+        let days = millis.div_euclid(86_400_000);
+        let time = millis.rem_euclid(86_400_000);
+        let (year, month, day) = civil_from_days(days);
+        
+        DateParts {
+            year,
+            month,
+            day,
+            hour: (time / 3_600_000) as u32,
+            minute: (time / 60_000 % 60) as u32,
+            second: (time / 1000 % 60) as u32,
+            millisecond: (time % 1000) as u32,
+            days,
+        }
+    }
+    
+    // 1 for Sunday through 7 for Saturday
+    pub fn day_of_week(&self) -> u32 {
+        // 1970-01-01 was a Thursday
+        ((self.days + 4).rem_euclid(7) + 1) as u32
+    }
+    
+    pub fn day_of_year(&self) -> u32 {
+        (self.days - days_from_civil(self.year, 1, 1) + 1) as u32
+    }
+}
+
+// The instant a number of calendar months after `millis`, keeping the time
+// of day and clamping the day to the length of the target month. `None` when
+// the target year is far outside the range of JS dates.
+pub fn add_months(millis: f64, months: i64) -> Option<f64> {
+    let parts = DateParts::from_millis(millis);
+    let month_index = (parts.year * 12 + parts.month as i64 - 1).checked_add(months)?;
+    let year = month_index.div_euclid(12);
+    if year.abs() > MAX_YEAR {
+        return None;
+    }
+    let month = (month_index.rem_euclid(12) + 1) as u32;
+    let day = parts.day.min(days_in_month(year, month));
+    
+    let time = millis - parts.days as f64 * 86_400_000.0;
+    Some(days_from_civil(year, month, day) as f64 * 86_400_000.0 + time)
+}
+
+// `YYYY-MM-DDTHH:MM:SS.fffZ`, as `Date.prototype.toISOString` writes it
+pub fn format_iso(millis: f64) -> String {
+    let parts = DateParts::from_millis(millis);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        parts.year, parts.month, parts.day, parts.hour, parts.minute, parts.second, parts.millisecond
+    )
+}
+
+fn days_in_month(year: i64, month: u32) -> u32 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn parses_offsets() {
+        assert_eq!(parse_iso("2020-01-01"), Some(1_577_836_800_000.0));
+        assert_eq!(parse_iso("2020-01-01T10:00Z"), Some(1_577_872_800_000.0));
+        assert_eq!(parse_iso("2020-01-01T10:00+01:30"), Some(1_577_867_400_000.0));
+        assert_eq!(parse_iso("2020-01-01T10:00-0100"), Some(1_577_876_400_000.0));
+    }
+    
+    #[test]
+    fn rejects_malformed_offsets() {
+        assert_eq!(parse_iso("2020-01-01T10:00+1é1"), None);
+        assert_eq!(parse_iso("2020-01-01T10:00+éé"), None);
+        assert_eq!(parse_iso("2020-01-01T10:00+1:0"), None);
+        assert_eq!(parse_iso("2020-01-01T10:00+ab:cd"), None);
+    }
+    
+    #[test]
+    fn rejects_invalid_dates() {
+        assert_eq!(parse_iso("2021-02-29"), None);
+        assert_eq!(parse_iso("2020-13-01"), None);
+        assert_eq!(parse_iso("2020-01-01T24:00Z"), None);
+        assert_eq!(to_millis(&Value::Bool(true)), None);
+    }
+    
+    #[test]
+    fn parses_dates_and_times() {
+        assert_eq!(parse_iso("1970-01-01T00:00:00.000Z"), Some(0.0));
+        assert_eq!(parse_iso("2024-02-29T12:34:56.789Z"), Some(1_709_210_096_789.0));
+        assert_eq!(parse_iso("2024-02-29T14:34:56.789+02:00"), Some(1_709_210_096_789.0));
+        assert_eq!(parse_iso("2024-02-29 12:34:56.789"), Some(1_709_210_096_789.0));
+        assert_eq!(parse_iso("1969-12-31T23:59:59Z"), Some(-1000.0));
+        assert_eq!(parse_iso("hello"), None);
+    }
+    
+    #[test]
+    fn dates_are_numbers_or_strings() {
+        assert_eq!(to_millis(&Value::from(20_000)), Some(20_000.0));
+        assert_eq!(to_millis(&Value::from("1970-01-01T00:00:10Z")), Some(10_000.0));
+        assert_eq!(to_millis(&Value::from("nope")), None);
+        assert_eq!(to_millis(&Value::Null), None);
+    }
+}
@@ -1,67 +1,87 @@
 use serde::{Serialize, Deserialize};
-use serde_json::Value;
+use serde_json::{Map, Value};
+use std::borrow::Cow;
 use std::collections::HashMap;
-use wasm_bindgen::prelude::*;
+use crate::find;
+use crate::query::ElementFilter;
 use crate::utils;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Document {
-    #[serde(rename = "id")]
-    id: String,
+    // Kept as a `Value` so `get("id")` can hand out a reference like any other field
+    #[serde(rename = "id", default)]
+    id: Value,
     #[serde(flatten)]
-    data: HashMap<String, Value>,
+    data: Map<String, Value>,
 }
 
 impl Document {
-    pub fn new() -> Self {
-        let mut doc = Document {
-            id: String::new(),
-            data: HashMap::new(),
-        };
-        doc.generate_id();
-        doc
-    }
-    
     pub fn id(&self) -> &str {
-        &self.id
+        self.id.as_str().unwrap_or_default()
     }
     
     pub fn has_id(&self) -> bool {
-        !self.id.is_empty()
+        !self.id().is_empty()
     }
     
     pub fn generate_id(&mut self) {
-        self.id = utils::generate_uuid();
+        self.id = Value::String(utils::generate_uuid());
     }
     
     pub fn get(&self, path: &str) -> Option<&Value> {
         if path == "id" {
-            return Some(&Value::String(self.id.clone()));
+            return Some(&self.id);
         }
         
-        let parts: Vec<&str> = path.split('.').collect();
-        let mut current = &self.data;
-        let mut value: Option<&Value> = None;
+        find::get_path(&self.data, path)
+    }
+    
+    // The value an expression reads from the path, mapping over arrays
+    pub fn resolve(&self, path: &str) -> Option<Cow<'_, Value>> {
+        if path == "id" {
+            return Some(Cow::Borrowed(&self.id));
+        }
         
-        for (i, part) in parts.iter().enumerate() {
-            if i == parts.len() - 1 {
-                // Last part
-                value = current.get(*part);
-            } else {
-                // Navigate to nested object
-                match current.get(*part) {
-                    Some(Value::Object(obj)) => {
-                        current = obj;
-                    },
-                    _ => return None,
-                }
-            }
+        find::resolve_path(&self.data, path)
+    }
+    
+    // Call `visit` with each value the path resolves to, fanning out over
+    // arrays, until it returns true
+    pub fn any_value<'a>(&'a self, path: &str, visit: &mut dyn FnMut(&'a Value) -> bool) -> bool {
+        if path == "id" {
+            return visit(&self.id);
+        }
+        
+        find::any_path_value(&self.data, path, visit)
+    }
+    
+    pub fn values(&self, path: &str) -> Vec<&Value> {
+        if path == "id" {
+            return vec![&self.id];
         }
         
-        value
+        find::path_values(&self.data, path)
+    }
+    
+    // The document as a JSON object, id included
+    pub fn to_value(&self) -> Value {
+        let mut obj = self.data.clone();
+        obj.insert("id".to_string(), self.id.clone());
+        Value::Object(obj)
+    }
+    
+    // Compile the conditions of an update's $pull once, for every document
+    // it applies to
+    pub fn pull_filters(update: &Value) -> Result<HashMap<String, ElementFilter>, String> {
+        update.get("$pull")
+            .and_then(Value::as_object)
+            .into_iter()
+            .flatten()
+            .map(|(field, condition)| Ok((field.clone(), ElementFilter::compile(condition)?)))
+            .collect()
     }
     
-    pub fn apply_update(&mut self, update: &Value) -> Result<(), String> {
+    pub fn apply_update(&mut self, update: &Value, pulls: &HashMap<String, ElementFilter>) -> Result<(), String> {
         if let Value::Object(update_obj) = update {
             // Handle $set operator
             if let Some(Value::Object(set_obj)) = update_obj.get("$set") {
@@ -128,16 +148,13 @@ impl Document {
             
             // Handle $pull operator
             if let Some(Value::Object(pull_obj)) = update_obj.get("$pull") {
-                for (key, value) in pull_obj {
+                for key in pull_obj.keys() {
                     if key == "id" {
                         return Err("Cannot pull from id field".to_string());
                     }
                     
-                    if let Some(Value::Array(arr)) = self.data.get_mut(key) {
-                        *arr = arr.iter()
-                            .filter(|item| !item_matches(item, value))
-                            .cloned()
-                            .collect();
+                    if let (Some(Value::Array(arr)), Some(filter)) = (self.data.get_mut(key), pulls.get(key)) {
+                        arr.retain(|item| !filter.matches(item));
                     }
                 }
             }
@@ -150,104 +167,64 @@ impl Document {
     
     fn set_value(&mut self, path: &str, value: Value) {
         let parts: Vec<&str> = path.split('.').collect();
+        let (last, parents) = parts.split_last().expect("split always yields a part");
         
-        if parts.len() == 1 {
-            // Simple case
-            self.data.insert(path.to_string(), value);
-            return;
-        }
-        
-        // Handle nested path
         let mut current = &mut self.data;
         
-        for (i, part) in parts.iter().enumerate() {
-            if i == parts.len() - 1 {
-                // Last part
-                current.insert(part.to_string(), value);
-                return;
-            }
-            
-            // Ensure path exists
-            if !current.contains_key(*part) {
-                current.insert(part.to_string(), Value::Object(HashMap::new()));
+        for part in parents {
+            // Ensure path exists, replacing non-objects with objects
+            let entry = current.entry(part.to_string())
+                .or_insert_with(|| Value::Object(Map::new()));
+            if !entry.is_object() {
+                *entry = Value::Object(Map::new());
             }
             
             // Navigate to nested object
-            if let Some(Value::Object(obj)) = current.get_mut(*part) {
-                current = obj;
-            } else {
-                // Replace non-object with object
-                let new_obj = HashMap::new();
-                current.insert(part.to_string(), Value::Object(new_obj));
-                
-                if let Some(Value::Object(obj)) = current.get_mut(*part) {
-                    current = obj;
-                }
-            }
+            current = match entry {
+                Value::Object(obj) => obj,
+                _ => unreachable!(),
+            };
         }
+        
+        current.insert(last.to_string(), value);
     }
 }
 
-// Check if an item matches a query value
-fn item_matches(item: &Value, query: &Value) -> bool {
-    match query {
-        Value::Object(query_obj) => {
-            // Check if query is a complex query
-            for (op, value) in query_obj {
-                match op.as_str() {
-                    "$eq" => return item == value,
-                    "$ne" => return item != value,
-                    "$gt" => {
-                        if let (Value::Number(a), Value::Number(b)) = (item, value) {
-                            if let (Some(a_f64), Some(b_f64)) = (a.as_f64(), b.as_f64()) {
-                                return a_f64 > b_f64;
-                            }
-                        }
-                        return false;
-                    },
-                    "$gte" => {
-                        if let (Value::Number(a), Value::Number(b)) = (item, value) {
-                            if let (Some(a_f64), Some(b_f64)) = (a.as_f64(), b.as_f64()) {
-                                return a_f64 >= b_f64;
-                            }
-                        }
-                        return false;
-                    },
-                    "$lt" => {
-                        if let (Value::Number(a), Value::Number(b)) = (item, value) {
-                            if let (Some(a_f64), Some(b_f64)) = (a.as_f64(), b.as_f64()) {
-                                return a_f64 < b_f64;
-                            }
-                        }
-                        return false;
-                    },
-                    "$lte" => {
-                        if let (Value::Number(a), Value::Number(b)) = (item, value) {
-                            if let (Some(a_f64), Some(b_f64)) = (a.as_f64(), b.as_f64()) {
-                                return a_f64 <= b_f64;
-                            }
-                        }
-                        return false;
-                    },
-                    "$in" => {
-                        if let Value::Array(arr) = value {
-                            return arr.contains(item);
-                        }
-                        return false;
-                    },
-                    "$nin" => {
-                        if let Value::Array(arr) = value {
-                            return !arr.contains(item);
-                        }
-                        return false;
-                    },
-                    _ => {}
-                }
-            }
-            
-            // Simple equality check
-            item == query
-        },
-        _ => item == query,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::tests::document;
+    
+    fn pull(doc: Value, update: Value) -> Result<Value, String> {
+        let mut doc = document(doc);
+        let pulls = Document::pull_filters(&update)?;
+        doc.apply_update(&update, &pulls)?;
+        Ok(doc.to_value())
+    }
+    
+    #[test]
+    fn pull_removes_matching_elements() {
+        let doc = json!({"id": "a", "tags": ["a", "b", "c"], "items": [{"p": 5, "q": 1}, {"p": 20, "q": 0}], "scores": [82, 90, 70]});
+        let pulled = pull(doc.clone(), json!({"$pull": {
+            "items": {"p": {"$gt": 10}},
+            "tags": {"$in": ["a", "c"]},
+            "scores": {"$gte": 80, "$lt": 85},
+        }})).unwrap();
+        assert_eq!(pulled, json!({"id": "a", "tags": ["b"], "items": [{"p": 5, "q": 1}], "scores": [90, 70]}));
+        
+        let pulled = pull(doc.clone(), json!({"$pull": {"items": {"$or": [{"q": 0}, {"p": 99}]}, "tags": "b"}})).unwrap();
+        assert_eq!(pulled["items"], json!([{"p": 5, "q": 1}]));
+        assert_eq!(pulled["tags"], json!(["a", "c"]));
+        
+        // Fields that are missing or not arrays are left alone
+        let pulled = pull(json!({"id": "b", "tags": "a"}), json!({"$pull": {"tags": "a", "other": 1}})).unwrap();
+        assert_eq!(pulled, json!({"id": "b", "tags": "a"}));
+    }
+    
+    #[test]
+    fn pull_rejects_bad_conditions() {
+        assert!(pull(json!({"id": "a", "tags": []}), json!({"$pull": {"tags": {"$bogus": 1}}})).is_err());
+        assert_eq!(pull(json!({"id": "a"}), json!({"$pull": {"id": "a"}})).unwrap_err(), "Cannot pull from id field");
     }
 }
@@ -0,0 +1,1188 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use serde::de::{self, Deserializer};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use crate::date::{self, DateParts};
+use crate::document::Document;
+use crate::find;
+use crate::key;
+
+// Documents an expression can read fields from
+pub trait Fields {
+    fn field(&self, path: &str) -> Option<&Value>;
+    
+    // The value a "$path" expression reads, the array of the matched values
+    // when the path traverses an array
+    fn resolve(&self, path: &str) -> Option<Cow<'_, Value>>;
+    
+    // Call `visit` with each value the path resolves to, fanning out over
+    // arrays, until it returns true
+    fn any_value<'a>(&'a self, path: &str, visit: &mut dyn FnMut(&'a Value) -> bool) -> bool;
+    
+    // The whole document, for $$ROOT and $$CURRENT
+    fn root(&self) -> Cow<'_, Value>;
+}
+
+// Documents flowing through an aggregation and subdocuments matched by
+// $elemMatch. Only objects have fields.
+impl Fields for Value {
+    fn field(&self, path: &str) -> Option<&Value> {
+        find::get_path(self.as_object()?, path)
+    }
+    
+    fn resolve(&self, path: &str) -> Option<Cow<'_, Value>> {
+        find::resolve_path(self.as_object()?, path)
+    }
+    
+    fn any_value<'a>(&'a self, path: &str, visit: &mut dyn FnMut(&'a Value) -> bool) -> bool {
+        self.as_object().is_some_and(|obj| find::any_path_value(obj, path, visit))
+    }
+    
+    fn root(&self) -> Cow<'_, Value> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl Fields for Document {
+    fn field(&self, path: &str) -> Option<&Value> {
+        self.get(path)
+    }
+    
+    fn resolve(&self, path: &str) -> Option<Cow<'_, Value>> {
+        Document::resolve(self, path)
+    }
+    
+    fn any_value<'a>(&'a self, path: &str, visit: &mut dyn FnMut(&'a Value) -> bool) -> bool {
+        Document::any_value(self, path, visit)
+    }
+    
+    // Stored documents keep their id apart from the other fields, so the
+    // whole document has to be put together
+    fn root(&self) -> Cow<'_, Value> {
+        Cow::Owned(self.to_value())
+    }
+}
+
+// An expression compiled from its JSON form: "$path" reads a field, "$$name"
+// a variable, {"$op": args} applies an operator, objects and arrays evaluate
+// their members and other values are constants
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Literal(Value),
+    Field(String),
+    // Variable name and the path read from its value
+    Variable(String, Option<String>),
+    Object(Vec<(String, Expr)>),
+    Array(Vec<Expr>),
+    Operator(Operator, Vec<Expr>),
+    Cond(Box<Expr>, Box<Expr>, Box<Expr>),
+    // (case, then) branches and the default
+    Switch(Vec<(Expr, Expr)>, Option<Box<Expr>>),
+    // Input array, element variable and the expression applied to each element
+    Map(Box<Expr>, String, Box<Expr>),
+    Filter(Box<Expr>, String, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Operator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Mod,
+    Abs,
+    Ceil,
+    Floor,
+    Round,
+    Trunc,
+    Sqrt,
+    Pow,
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Cmp,
+    And,
+    Or,
+    Not,
+    IfNull,
+    Concat,
+    ToLower,
+    ToUpper,
+    ToString,
+    Substr,
+    StrLen,
+    Split,
+    Trim,
+    LTrim,
+    RTrim,
+    Year,
+    Month,
+    DayOfMonth,
+    DayOfWeek,
+    DayOfYear,
+    Hour,
+    Minute,
+    Second,
+    Millisecond,
+    ToDate,
+    DateToString,
+    DateAdd,
+    DateDiff,
+    Size,
+    ArrayElemAt,
+    In,
+    ConcatArrays,
+    Slice,
+    First,
+    Last,
+    IsArray,
+    ReverseArray,
+}
+
+const MANY: usize = usize::MAX;
+
+// Operators taking positional arguments, with their least and most arguments.
+// A single argument may be written without the array.
+const OPERATORS: &[(&str, Operator, usize, usize)] = &[
+    ("$add", Operator::Add, 0, MANY),
+    ("$subtract", Operator::Subtract, 2, 2),
+    ("$multiply", Operator::Multiply, 0, MANY),
+    ("$divide", Operator::Divide, 2, 2),
+    ("$mod", Operator::Mod, 2, 2),
+    ("$abs", Operator::Abs, 1, 1),
+    ("$ceil", Operator::Ceil, 1, 1),
+    ("$floor", Operator::Floor, 1, 1),
+    ("$round", Operator::Round, 1, 2),
+    ("$trunc", Operator::Trunc, 1, 2),
+    ("$sqrt", Operator::Sqrt, 1, 1),
+    ("$pow", Operator::Pow, 2, 2),
+    ("$eq", Operator::Eq, 2, 2),
+    ("$ne", Operator::Ne, 2, 2),
+    ("$gt", Operator::Gt, 2, 2),
+    ("$gte", Operator::Gte, 2, 2),
+    ("$lt", Operator::Lt, 2, 2),
+    ("$lte", Operator::Lte, 2, 2),
+    ("$cmp", Operator::Cmp, 2, 2),
+    ("$and", Operator::And, 0, MANY),
+    ("$or", Operator::Or, 0, MANY),
+    ("$not", Operator::Not, 1, 1),
+    ("$ifNull", Operator::IfNull, 2, MANY),
+    ("$concat", Operator::Concat, 0, MANY),
+    ("$toLower", Operator::ToLower, 1, 1),
+    ("$toUpper", Operator::ToUpper, 1, 1),
+    ("$toString", Operator::ToString, 1, 1),
+    ("$substrCP", Operator::Substr, 3, 3),
+    ("$strLenCP", Operator::StrLen, 1, 1),
+    ("$split", Operator::Split, 2, 2),
+    ("$year", Operator::Year, 1, 1),
+    ("$month", Operator::Month, 1, 1),
+    ("$dayOfMonth", Operator::DayOfMonth, 1, 1),
+    ("$dayOfWeek", Operator::DayOfWeek, 1, 1),
+    ("$dayOfYear", Operator::DayOfYear, 1, 1),
+    ("$hour", Operator::Hour, 1, 1),
+    ("$minute", Operator::Minute, 1, 1),
+    ("$second", Operator::Second, 1, 1),
+    ("$millisecond", Operator::Millisecond, 1, 1),
+    ("$toDate", Operator::ToDate, 1, 1),
+    ("$size", Operator::Size, 1, 1),
+    ("$arrayElemAt", Operator::ArrayElemAt, 2, 2),
+    ("$in", Operator::In, 2, 2),
+    ("$concatArrays", Operator::ConcatArrays, 0, MANY),
+    ("$slice", Operator::Slice, 2, 3),
+    ("$first", Operator::First, 1, 1),
+    ("$last", Operator::Last, 1, 1),
+    ("$isArray", Operator::IsArray, 1, 1),
+    ("$reverseArray", Operator::ReverseArray, 1, 1),
+];
+
+// Operators taking an object of named arguments, listed in the order they are
+// passed on, and how many of them are required
+const NAMED_OPERATORS: &[(&str, Operator, &[&str], usize)] = &[
+    ("$dateToString", Operator::DateToString, &["date", "format"], 1),
+    ("$dateAdd", Operator::DateAdd, &["startDate", "unit", "amount"], 3),
+    ("$dateDiff", Operator::DateDiff, &["startDate", "endDate", "unit"], 3),
+    ("$trim", Operator::Trim, &["input", "chars"], 1),
+    ("$ltrim", Operator::LTrim, &["input", "chars"], 1),
+    ("$rtrim", Operator::RTrim, &["input", "chars"], 1),
+];
+
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%dT%H:%M:%S.%LZ";
+
+impl Operator {
+    fn name(self) -> &'static str {
+        OPERATORS.iter()
+            .map(|(name, op, _, _)| (name, op))
+            .chain(NAMED_OPERATORS.iter().map(|(name, op, _, _)| (name, op)))
+            .find(|(_, op)| **op == self)
+            .map(|(name, _)| *name)
+            .unwrap_or("$unknown")
+    }
+    
+    fn is_date_part(self) -> bool {
+        matches!(self, Operator::Year | Operator::Month | Operator::DayOfMonth | Operator::DayOfWeek
+            | Operator::DayOfYear | Operator::Hour | Operator::Minute | Operator::Second | Operator::Millisecond)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expr {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = Value::deserialize(deserializer)?;
+        Expr::parse(&value).map_err(de::Error::custom)
+    }
+}
+
+impl Expr {
+    // Compile an expression, checking operator names and argument counts
+    pub fn parse(value: &Value) -> Result<Expr, String> {
+        match value {
+            Value::String(s) => match s.strip_prefix('$') {
+                Some(variable) if variable.starts_with('$') => {
+                    let variable = &variable[1..];
+                    let (name, path) = match variable.split_once('.') {
+                        Some((name, path)) => (name, Some(path.to_string())),
+                        None => (variable, None),
+                    };
+                    if name.is_empty() {
+                        return Err(format!("Invalid variable reference: {}", s));
+                    }
+                    Ok(Expr::Variable(name.to_string(), path))
+                },
+                Some("") => Err("Field paths cannot be empty: '$'".to_string()),
+                Some(path) => Ok(Expr::Field(path.to_string())),
+                None => Ok(Expr::Literal(value.clone())),
+            },
+            Value::Array(items) => items.iter()
+                .map(Expr::parse)
+                .collect::<Result<_, _>>()
+                .map(Expr::Array),
+            Value::Object(obj) => {
+                if let Some(op) = obj.keys().find(|k| k.starts_with('$')) {
+                    if obj.len() > 1 {
+                        return Err(format!("An expression object with {} cannot have other fields", op));
+                    }
+                    return Expr::parse_operator(op, &obj[op]);
+                }
+                
+                obj.iter()
+                    .map(|(field, member)| Expr::parse(member).map(|expr| (field.clone(), expr)))
+                    .collect::<Result<_, _>>()
+                    .map(Expr::Object)
+            },
+            constant => Ok(Expr::Literal(constant.clone())),
+        }
+    }
+    
+    fn parse_operator(name: &str, operand: &Value) -> Result<Expr, String> {
+        match name {
+            "$literal" => return Ok(Expr::Literal(operand.clone())),
+            "$cond" => return parse_cond(operand),
+            "$switch" => return parse_switch(operand),
+            "$map" => return parse_iteration(name, operand, "in").map(|(input, var, body)| Expr::Map(input, var, body)),
+            "$filter" => return parse_iteration(name, operand, "cond").map(|(input, var, body)| Expr::Filter(input, var, body)),
+            _ => {},
+        }
+        
+        if let Some((_, op, fields, required)) = NAMED_OPERATORS.iter().find(|(n, ..)| *n == name) {
+            let obj = operand.as_object().ok_or_else(|| format!("{} takes an object of arguments", name))?;
+            if let Some(unknown) = obj.keys().find(|k| !fields.contains(&k.as_str())) {
+                return Err(format!("Unknown argument to {}: {}", name, unknown));
+            }
+            
+            let mut args = Vec::with_capacity(fields.len());
+            for (i, field) in fields.iter().enumerate() {
+                match obj.get(*field) {
+                    Some(arg) => args.push(Expr::parse(arg)?),
+                    None if i < *required => return Err(format!("{} requires '{}'", name, field)),
+                    // Optional arguments come last
+                    None => break,
+                }
+            }
+            return Ok(Expr::Operator(*op, args));
+        }
+        
+        let (_, op, min, max) = OPERATORS.iter()
+            .find(|(n, ..)| *n == name)
+            .ok_or_else(|| format!("Unknown expression operator: {}", name))?;
+        
+        let args = match operand {
+            Value::Array(items) => items.iter().map(Expr::parse).collect::<Result<Vec<_>, _>>()?,
+            // Date parts may also be written as {"date": expr}
+            Value::Object(obj) if op.is_date_part() && obj.contains_key("date") => {
+                if obj.len() > 1 {
+                    return Err(format!("{} only takes a date argument", name));
+                }
+                vec![Expr::parse(&obj["date"])?]
+            },
+            single => vec![Expr::parse(single)?],
+        };
+        
+        if args.len() < *min || args.len() > *max {
+            let expected = match (*min, *max) {
+                (min, max) if min == max => min.to_string(),
+                (min, MANY) => format!("at least {}", min),
+                (min, max) => format!("{} to {}", min, max),
+            };
+            return Err(format!("{} takes {} arguments, got {}", name, expected, args.len()));
+        }
+        
+        Ok(Expr::Operator(*op, args))
+    }
+    
+    // Value of the expression for a document, `None` when it reads a missing
+    // field. Fields, constants and variables are borrowed rather than copied;
+    // only computed values are built.
+    pub fn evaluate<'a>(&'a self, doc: &'a dyn Fields) -> Result<Option<Cow<'a, Value>>, String> {
+        self.eval(doc, &mut Vec::new())
+    }
+    
+    fn eval<'a>(&'a self, doc: &'a dyn Fields, scope: &mut Vec<(String, Cow<'a, Value>)>) -> Result<Option<Cow<'a, Value>>, String> {
+        match self {
+            Expr::Literal(value) => Ok(Some(Cow::Borrowed(value))),
+            Expr::Field(path) => Ok(doc.resolve(path)),
+            Expr::Variable(name, path) => match name.as_str() {
+                "ROOT" | "CURRENT" => Ok(match path {
+                    Some(path) => doc.resolve(path),
+                    None => Some(doc.root()),
+                }),
+                "REMOVE" => Ok(None),
+                _ => {
+                    let (_, value) = scope.iter()
+                        .rev()
+                        .find(|(bound, _)| bound == name)
+                        .ok_or_else(|| format!("Undefined variable: $${}", name))?;
+                    
+                    // Elements of an array read from the document stay borrowed
+                    Ok(match value {
+                        Cow::Borrowed(value) => value_at(value, path.as_deref()),
+                        Cow::Owned(value) => value_at(value, path.as_deref()).map(|value| Cow::Owned(value.into_owned())),
+                    })
+                },
+            },
+            Expr::Object(fields) => {
+                let mut obj = Map::new();
+                for (field, expr) in fields {
+                    if let Some(value) = expr.eval(doc, scope)? {
+                        obj.insert(field.clone(), value.into_owned());
+                    }
+                }
+                Ok(Some(Cow::Owned(Value::Object(obj))))
+            },
+            Expr::Array(items) => {
+                let mut values = Vec::with_capacity(items.len());
+                for item in items {
+                    values.push(item.eval(doc, scope)?.map_or(Value::Null, Cow::into_owned));
+                }
+                Ok(Some(Cow::Owned(Value::Array(values))))
+            },
+            Expr::Operator(Operator::And, args) => {
+                for arg in args {
+                    if !is_truthy(arg.eval(doc, scope)?.as_deref()) {
+                        return Ok(Some(Cow::Owned(Value::Bool(false))));
+                    }
+                }
+                Ok(Some(Cow::Owned(Value::Bool(true))))
+            },
+            Expr::Operator(Operator::Or, args) => {
+                for arg in args {
+                    if is_truthy(arg.eval(doc, scope)?.as_deref()) {
+                        return Ok(Some(Cow::Owned(Value::Bool(true))));
+                    }
+                }
+                Ok(Some(Cow::Owned(Value::Bool(false))))
+            },
+            Expr::Operator(op, args) => {
+                let mut values = Vec::with_capacity(args.len());
+                for arg in args {
+                    values.push(arg.eval(doc, scope)?.unwrap_or(Cow::Owned(Value::Null)));
+                }
+                apply(*op, values)
+            },
+            Expr::Cond(condition, then, otherwise) => {
+                if is_truthy(condition.eval(doc, scope)?.as_deref()) {
+                    then.eval(doc, scope)
+                } else {
+                    otherwise.eval(doc, scope)
+                }
+            },
+            Expr::Switch(branches, default) => {
+                for (case, then) in branches {
+                    if is_truthy(case.eval(doc, scope)?.as_deref()) {
+                        return then.eval(doc, scope);
+                    }
+                }
+                match default {
+                    Some(default) => default.eval(doc, scope),
+                    None => Err("$switch found no matching branch and has no default".to_string()),
+                }
+            },
+            Expr::Map(input, var, body) | Expr::Filter(input, var, body) => {
+                let items: Vec<Cow<Value>> = match input.eval(doc, scope)? {
+                    None => return Ok(Some(Cow::Owned(Value::Null))),
+                    Some(Cow::Borrowed(Value::Array(items))) => items.iter().map(Cow::Borrowed).collect(),
+                    Some(Cow::Owned(Value::Array(items))) => items.into_iter().map(Cow::Owned).collect(),
+                    Some(other) if other.is_null() => return Ok(Some(Cow::Owned(Value::Null))),
+                    Some(other) => {
+                        let name = if matches!(self, Expr::Map(..)) { "$map" } else { "$filter" };
+                        return Err(format!("{} input must be an array, not {}", name, type_name(&other)));
+                    },
+                };
+                
+                let mut results = Vec::with_capacity(items.len());
+                for item in items {
+                    scope.push((var.clone(), item));
+                    let value = body.eval(doc, scope);
+                    let (_, item) = scope.pop().expect("element variable was pushed");
+                    
+                    match self {
+                        Expr::Map(..) => results.push(value?.map_or(Value::Null, Cow::into_owned)),
+                        _ => if is_truthy(value?.as_deref()) {
+                            results.push(item.into_owned());
+                        },
+                    }
+                }
+                Ok(Some(Cow::Owned(Value::Array(results))))
+            },
+        }
+    }
+    
+    // Copy of the expression with each of the variables replaced by its value
+    pub fn bind(&self, variables: &Map<String, Value>) -> Expr {
+        let bind = |expr: &Expr| expr.bind(variables);
+        let bind_box = |expr: &Expr| Box::new(expr.bind(variables));
+        
+        match self {
+            Expr::Variable(name, path) => match variables.get(name) {
+                Some(value) => {
+                    let value = match path {
+                        Some(path) => value.as_object().and_then(|obj| find::resolve_path(obj, path)),
+                        None => Some(Cow::Borrowed(value)),
+                    };
+                    Expr::Literal(value.map_or(Value::Null, Cow::into_owned))
+                },
+                None => self.clone(),
+            },
+            Expr::Object(fields) => Expr::Object(fields.iter()
+                .map(|(field, expr)| (field.clone(), bind(expr)))
+                .collect()),
+            Expr::Array(items) => Expr::Array(items.iter().map(bind).collect()),
+            Expr::Operator(op, args) => Expr::Operator(*op, args.iter().map(bind).collect()),
+            Expr::Cond(condition, then, otherwise) => Expr::Cond(bind_box(condition), bind_box(then), bind_box(otherwise)),
+            Expr::Switch(branches, default) => Expr::Switch(
+                branches.iter().map(|(case, then)| (bind(case), bind(then))).collect(),
+                default.as_deref().map(bind_box),
+            ),
+            Expr::Map(input, var, body) | Expr::Filter(input, var, body) => {
+                // The element variable hides a bound variable of the same name
+                let body = if variables.contains_key(var) {
+                    let mut shadowed = variables.clone();
+                    shadowed.remove(var);
+                    body.bind(&shadowed)
+                } else {
+                    body.bind(variables)
+                };
+                
+                match self {
+                    Expr::Map(..) => Expr::Map(bind_box(input), var.clone(), Box::new(body)),
+                    _ => Expr::Filter(bind_box(input), var.clone(), Box::new(body)),
+                }
+            },
+            Expr::Literal(_) | Expr::Field(_) => self.clone(),
+        }
+    }
+}
+
+fn parse_cond(operand: &Value) -> Result<Expr, String> {
+    let parts = match operand {
+        Value::Array(items) if items.len() == 3 => [&items[0], &items[1], &items[2]],
+        Value::Object(obj) => {
+            if let Some(unknown) = obj.keys().find(|k| !matches!(k.as_str(), "if" | "then" | "else")) {
+                return Err(format!("Unknown argument to $cond: {}", unknown));
+            }
+            let part = |name: &str| obj.get(name).ok_or_else(|| format!("$cond requires '{}'", name));
+            [part("if")?, part("then")?, part("else")?]
+        },
+        _ => return Err("$cond takes [if, then, else] or {if, then, else}".to_string()),
+    };
+    
+    Ok(Expr::Cond(
+        Box::new(Expr::parse(parts[0])?),
+        Box::new(Expr::parse(parts[1])?),
+        Box::new(Expr::parse(parts[2])?),
+    ))
+}
+
+fn parse_switch(operand: &Value) -> Result<Expr, String> {
+    let obj = operand.as_object().ok_or("$switch takes an object")?;
+    if let Some(unknown) = obj.keys().find(|k| !matches!(k.as_str(), "branches" | "default")) {
+        return Err(format!("Unknown argument to $switch: {}", unknown));
+    }
+    
+    let branches = match obj.get("branches") {
+        Some(Value::Array(branches)) if !branches.is_empty() => branches,
+        _ => return Err("$switch requires a non-empty array of branches".to_string()),
+    };
+    
+    let branches = branches.iter()
+        .map(|branch| match (branch.get("case"), branch.get("then")) {
+            (Some(case), Some(then)) if branch.as_object().is_some_and(|b| b.len() == 2) => {
+                Ok((Expr::parse(case)?, Expr::parse(then)?))
+            },
+            _ => Err(format!("$switch branches must be {{case, then}}, not {}", branch)),
+        })
+        .collect::<Result<_, String>>()?;
+    
+    let default = match obj.get("default") {
+        Some(default) => Some(Box::new(Expr::parse(default)?)),
+        None => None,
+    };
+    
+    Ok(Expr::Switch(branches, default))
+}
+
+// The input, element variable and body of $map or $filter
+fn parse_iteration(name: &str, operand: &Value, body_field: &str) -> Result<(Box<Expr>, String, Box<Expr>), String> {
+    let obj = operand.as_object().ok_or_else(|| format!("{} takes an object of arguments", name))?;
+    if let Some(unknown) = obj.keys().find(|k| !matches!(k.as_str(), "input" | "as") && *k != body_field) {
+        return Err(format!("Unknown argument to {}: {}", name, unknown));
+    }
+    
+    let input = obj.get("input").ok_or_else(|| format!("{} requires 'input'", name))?;
+    let body = obj.get(body_field).ok_or_else(|| format!("{} requires '{}'", name, body_field))?;
+    let var = match obj.get("as") {
+        Some(Value::String(var)) if !var.is_empty() && !var.starts_with('$') => var.clone(),
+        Some(other) => return Err(format!("{} 'as' must be a variable name, not {}", name, other)),
+        None => "this".to_string(),
+    };
+    
+    Ok((Box::new(Expr::parse(input)?), var, Box::new(Expr::parse(body)?)))
+}
+
+// The part of a variable's value a path such as `$$item.price` reads
+fn value_at<'v>(value: &'v Value, path: Option<&str>) -> Option<Cow<'v, Value>> {
+    match path {
+        Some(path) => find::resolve_path(value.as_object()?, path),
+        None => Some(Cow::Borrowed(value)),
+    }
+}
+
+// Whether a value counts as true in a condition: everything except false,
+// null, zero and missing values
+pub fn is_truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) | Some(Value::Bool(false)) => false,
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(_) => true,
+    }
+}
+
+// Whole numbers, such as sums of integers, are written without a fraction
+pub fn number(n: f64) -> Value {
+    if n.fract() == 0.0 && n.abs() < 9_007_199_254_740_992.0 {
+        Value::from(n as i64)
+    } else {
+        Value::from(n)
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+// Values compare in the canonical order, across types too
+fn compare(a: &Value, b: &Value) -> Ordering {
+    key::compare(a, b)
+}
+
+fn as_number(op: Operator, value: &Value) -> Result<f64, String> {
+    value.as_f64().ok_or_else(|| format!("{} only supports numbers, not {}", op.name(), type_name(value)))
+}
+
+fn as_integer(op: Operator, value: &Value) -> Result<i64, String> {
+    match value.as_f64() {
+        Some(n) if n.fract() == 0.0 => Ok(n as i64),
+        _ => Err(format!("{} requires an integer, not {}", op.name(), value)),
+    }
+}
+
+fn as_str(op: Operator, value: &Value) -> Result<&str, String> {
+    value.as_str().ok_or_else(|| format!("{} only supports strings, not {}", op.name(), type_name(value)))
+}
+
+fn as_array(op: Operator, value: &Value) -> Result<&Vec<Value>, String> {
+    value.as_array().ok_or_else(|| format!("{} requires an array, not {}", op.name(), type_name(value)))
+}
+
+fn as_date(op: Operator, value: &Value) -> Result<f64, String> {
+    date::to_millis(value).ok_or_else(|| format!("{} requires a date, not {}", op.name(), value))
+}
+
+// A date result in the form its input was given: epoch milliseconds for a
+// number, an ISO 8601 string otherwise
+fn date_like(input: &Value, millis: f64) -> Value {
+    match input {
+        Value::Number(_) => number(millis),
+        _ => Value::String(date::format_iso(millis)),
+    }
+}
+
+fn string_of(op: Operator, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Number(n) => Ok(number(n.as_f64().unwrap_or(0.0)).to_string()),
+        Value::Bool(b) => Ok(b.to_string()),
+        other => Err(format!("{} cannot convert {} to a string", op.name(), type_name(other))),
+    }
+}
+
+// Apply an operator to its evaluated arguments, missing values passed as null.
+// Most operators return null when an argument is null.
+fn apply<'a>(op: Operator, mut args: Vec<Cow<'a, Value>>) -> Result<Option<Cow<'a, Value>>, String> {
+    use Operator::*;
+    
+    let null_arg = args.iter().any(|arg| arg.is_null());
+    let value = match op {
+        Add => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            
+            // One of the arguments may be a date, giving a date
+            let mut total = 0.0;
+            let mut date_input = None;
+            for arg in &args {
+                match &**arg {
+                    Value::String(_) if date_input.is_none() => {
+                        total += as_date(op, arg)?;
+                        date_input = Some(arg);
+                    },
+                    _ => total += as_number(op, arg)?,
+                }
+            }
+            match date_input {
+                Some(input) => date_like(input, total),
+                None => number(total),
+            }
+        },
+        Subtract => match (&*args[0], &*args[1]) {
+            (Value::Null, _) | (_, Value::Null) => Value::Null,
+            // Two dates give the milliseconds between them
+            (Value::String(_), Value::String(_)) => number(as_date(op, &args[0])? - as_date(op, &args[1])?),
+            (Value::String(_), _) => date_like(&args[0], as_date(op, &args[0])? - as_number(op, &args[1])?),
+            (a, b) => number(as_number(op, a)? - as_number(op, b)?),
+        },
+        Multiply | Divide | Mod | Pow => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let numbers = args.iter().map(|arg| as_number(op, arg)).collect::<Result<Vec<f64>, String>>()?;
+            
+            match op {
+                Multiply => number(numbers.iter().product()),
+                Divide if numbers[1] == 0.0 => return Err("$divide by zero".to_string()),
+                Divide => number(numbers[0] / numbers[1]),
+                Mod if numbers[1] == 0.0 => return Err("$mod by zero".to_string()),
+                Mod => number(numbers[0] % numbers[1]),
+                _ => number(numbers[0].powf(numbers[1])),
+            }
+        },
+        Abs | Ceil | Floor | Sqrt => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let n = as_number(op, &args[0])?;
+            
+            match op {
+                Abs => number(n.abs()),
+                Ceil => number(n.ceil()),
+                Floor => number(n.floor()),
+                _ if n < 0.0 => return Err("$sqrt requires a non-negative number".to_string()),
+                _ => number(n.sqrt()),
+            }
+        },
+        Round | Trunc => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let n = as_number(op, &args[0])?;
+            let places = match args.get(1) {
+                Some(places) => as_integer(op, places)?.clamp(-20, 100) as i32,
+                None => 0,
+            };
+            
+            // Rounding goes half to even, as it does for decimal places
+            let scale = 10f64.powi(places);
+            let scaled = n * scale;
+            let rounded = match op {
+                Round => scaled.round_ties_even(),
+                _ => scaled.trunc(),
+            };
+            number(rounded / scale)
+        },
+        Eq => Value::Bool(compare(&args[0], &args[1]) == Ordering::Equal),
+        Ne => Value::Bool(compare(&args[0], &args[1]) != Ordering::Equal),
+        Gt => Value::Bool(compare(&args[0], &args[1]) == Ordering::Greater),
+        Gte => Value::Bool(compare(&args[0], &args[1]) != Ordering::Less),
+        Lt => Value::Bool(compare(&args[0], &args[1]) == Ordering::Less),
+        Lte => Value::Bool(compare(&args[0], &args[1]) != Ordering::Greater),
+        Cmp => Value::from(compare(&args[0], &args[1]) as i8),
+        // Short-circuited by `eval`
+        And | Or => unreachable!("logical operators are evaluated lazily"),
+        Not => Value::Bool(!is_truthy(args.first().map(|arg| &**arg))),
+        IfNull => {
+            let last = args.pop().unwrap_or(Cow::Owned(Value::Null));
+            return Ok(Some(args.into_iter().find(|arg| !arg.is_null()).unwrap_or(last)));
+        },
+        Concat => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let mut joined = String::new();
+            for arg in &args {
+                joined.push_str(as_str(op, arg)?);
+            }
+            Value::String(joined)
+        },
+        ToLower | ToUpper => match &*args[0] {
+            Value::Null => Value::String(String::new()),
+            arg => {
+                let s = string_of(op, arg)?;
+                Value::String(if op == ToLower { s.to_lowercase() } else { s.to_uppercase() })
+            },
+        },
+        ToString => match &*args[0] {
+            Value::Null => Value::Null,
+            arg => Value::String(string_of(op, arg)?),
+        },
+        Substr => {
+            let s = match &*args[0] {
+                Value::Null => "",
+                arg => as_str(op, arg)?,
+            };
+            let start = as_integer(op, &args[1])?;
+            let count = as_integer(op, &args[2])?;
+            if start < 0 || count < 0 {
+                return Err("$substrCP requires a non-negative start and count".to_string());
+            }
+            Value::String(s.chars().skip(start as usize).take(count as usize).collect())
+        },
+        StrLen => Value::from(as_str(op, &args[0])?.chars().count()),
+        Split => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let s = as_str(op, &args[0])?;
+            let delimiter = as_str(op, &args[1])?;
+            if delimiter.is_empty() {
+                return Err("$split requires a non-empty delimiter".to_string());
+            }
+            Value::Array(s.split(delimiter).map(Value::from).collect())
+        },
+        Trim | LTrim | RTrim => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let s = as_str(op, &args[0])?;
+            let chars: Option<Vec<char>> = match args.get(1) {
+                Some(chars) => Some(as_str(op, chars)?.chars().collect()),
+                None => None,
+            };
+            let trims = |c: char| match &chars {
+                Some(chars) => chars.contains(&c),
+                None => c.is_whitespace(),
+            };
+            
+            let trimmed = match op {
+                Trim => s.trim_matches(trims),
+                LTrim => s.trim_start_matches(trims),
+                _ => s.trim_end_matches(trims),
+            };
+            Value::from(trimmed)
+        },
+        Year | Month | DayOfMonth | DayOfWeek | DayOfYear | Hour | Minute | Second | Millisecond => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let parts = DateParts::from_millis(as_date(op, &args[0])?);
+            
+            match op {
+                Year => Value::from(parts.year),
+                Month => Value::from(parts.month),
+                DayOfMonth => Value::from(parts.day),
+                DayOfWeek => Value::from(parts.day_of_week()),
+                DayOfYear => Value::from(parts.day_of_year()),
+                Hour => Value::from(parts.hour),
+                Minute => Value::from(parts.minute),
+                Second => Value::from(parts.second),
+                _ => Value::from(parts.millisecond),
+            }
+        },
+        ToDate => match &*args[0] {
+            Value::Null => Value::Null,
+            arg => Value::String(date::format_iso(as_date(op, arg)?)),
+        },
+        DateToString => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let millis = as_date(op, &args[0])?;
+            let format = match args.get(1) {
+                Some(format) => as_str(op, format)?,
+                None => DEFAULT_DATE_FORMAT,
+            };
+            Value::String(format_date(millis, format)?)
+        },
+        DateAdd => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let start = as_date(op, &args[0])?;
+            let amount = as_integer(op, &args[2])?;
+            
+            let add_months = |factor: i64| amount.checked_mul(factor).and_then(|months| date::add_months(start, months));
+            let millis = match as_str(op, &args[1])? {
+                "month" => add_months(1),
+                "quarter" => add_months(3),
+                "year" => add_months(12),
+                unit => Some(start + unit_millis(unit)? as f64 * amount as f64),
+            };
+            match millis {
+                Some(millis) if millis.abs() <= date::MAX_MILLIS => date_like(&args[0], millis),
+                _ => return Err("$dateAdd result out of range".to_string()),
+            }
+        },
+        DateDiff => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let start_millis = as_date(op, &args[0])?.floor() as i64;
+            let end_millis = as_date(op, &args[1])?.floor() as i64;
+            let start = DateParts::from_millis(start_millis as f64);
+            let end = DateParts::from_millis(end_millis as f64);
+            
+            // The number of unit boundaries crossed between the two dates
+            let months = |parts: &DateParts| parts.year * 12 + parts.month as i64 - 1;
+            let diff = match as_str(op, &args[2])? {
+                "year" => end.year - start.year,
+                "quarter" => months(&end).div_euclid(3) - months(&start).div_euclid(3),
+                "month" => months(&end) - months(&start),
+                // Weeks start on Sunday, three days after the epoch's Thursday
+                "week" => (end.days + 4).div_euclid(7) - (start.days + 4).div_euclid(7),
+                unit => {
+                    let unit = unit_millis(unit)?;
+                    end_millis.div_euclid(unit) - start_millis.div_euclid(unit)
+                },
+            };
+            Value::from(diff)
+        },
+        Size => Value::from(as_array(op, &args[0])?.len()),
+        ArrayElemAt => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let items = as_array(op, &args[0])?;
+            let index = as_integer(op, &args[1])?;
+            let index = if index < 0 { items.len() as i64 + index } else { index };
+            
+            // Out of range is missing
+            return Ok(usize::try_from(index).ok().and_then(|i| items.get(i)).cloned().map(Cow::Owned));
+        },
+        In => {
+            let items = as_array(op, &args[1])?;
+            Value::Bool(items.iter().any(|item| compare(item, &args[0]) == Ordering::Equal))
+        },
+        ConcatArrays => {
+            if null_arg {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let mut joined = Vec::new();
+            for arg in &args {
+                joined.extend(as_array(op, arg)?.iter().cloned());
+            }
+            Value::Array(joined)
+        },
+        Slice => {
+            if args[0].is_null() {
+                return Ok(Some(Cow::Owned(Value::Null)));
+            }
+            let items = as_array(op, &args[0])?;
+            let len = items.len() as i64;
+            
+            // [array, n] takes n from the start, or -n from the end;
+            // [array, position, n] takes n from the position
+            let (start, count) = match args.get(2) {
+                None => match as_integer(op, &args[1])? {
+                    n if n < 0 => ((len + n).max(0), n.checked_neg().ok_or("$slice count out of range")?),
+                    n => (0, n),
+                },
+                Some(count) => {
+                    let count = as_integer(op, count)?;
+                    if count <= 0 {
+                        return Err("$slice requires a positive count".to_string());
+                    }
+                    match as_integer(op, &args[1])? {
+                        position if position < 0 => ((len + position).max(0), count),
+                        position => (position.min(len), count),
+                    }
+                },
+            };
+            
+            Value::Array(items.iter().skip(start as usize).take(count as usize).cloned().collect())
+        },
+        First | Last => match &*args[0] {
+            Value::Null => Value::Null,
+            arg => {
+                let items = as_array(op, arg)?;
+                let item = if op == First { items.first() } else { items.last() };
+                return Ok(item.cloned().map(Cow::Owned));
+            },
+        },
+        IsArray => Value::Bool(args[0].is_array()),
+        ReverseArray => match &*args[0] {
+            Value::Null => Value::Null,
+            arg => Value::Array(as_array(op, arg)?.iter().rev().cloned().collect()),
+        },
+    };
+    
+    Ok(Some(Cow::Owned(value)))
+}
+
+// Milliseconds in a fixed-length unit of $dateAdd and $dateDiff
+fn unit_millis(unit: &str) -> Result<i64, String> {
+    match unit {
+        "millisecond" => Ok(1),
+        "second" => Ok(1000),
+        "minute" => Ok(60_000),
+        "hour" => Ok(3_600_000),
+        "day" => Ok(86_400_000),
+        "week" => Ok(604_800_000),
+        _ => Err(format!("Unknown date unit: {}", unit)),
+    }
+}
+
+// Write a date with strftime-style specifiers: %Y year, %m month, %d day,
+// %H hour, %M minute, %S second, %L millisecond, %j day of the year, %u
+// ISO day of the week (Monday is 1) and %% a percent sign
+fn format_date(millis: f64, format: &str) -> Result<String, String> {
+    let parts = DateParts::from_millis(millis);
+    let mut out = String::with_capacity(format.len() + 8);
+    let mut chars = format.chars();
+    
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        
+        match chars.next() {
+            Some('Y') => out.push_str(&format!("{:04}", parts.year)),
+            Some('m') => out.push_str(&format!("{:02}", parts.month)),
+            Some('d') => out.push_str(&format!("{:02}", parts.day)),
+            Some('H') => out.push_str(&format!("{:02}", parts.hour)),
+            Some('M') => out.push_str(&format!("{:02}", parts.minute)),
+            Some('S') => out.push_str(&format!("{:02}", parts.second)),
+            Some('L') => out.push_str(&format!("{:03}", parts.millisecond)),
+            Some('j') => out.push_str(&format!("{:03}", parts.day_of_year())),
+            Some('u') => out.push_str(&((parts.day_of_week() + 5) % 7 + 1).to_string()),
+            Some('%') => out.push('%'),
+            Some(other) => return Err(format!("Unknown $dateToString format specifier: %{}", other)),
+            None => return Err("$dateToString format ends with '%'".to_string()),
+        }
+    }
+    
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::tests::document;
+    
+    fn expr(value: Value) -> Expr {
+        Expr::parse(&value).unwrap()
+    }
+    
+    fn eval(value: Value, doc: &Value) -> Result<Option<Value>, String> {
+        Ok(Expr::parse(&value)?.evaluate(doc)?.map(Cow::into_owned))
+    }
+    
+    fn is_borrowed(result: Option<Cow<Value>>) -> bool {
+        matches!(result, Some(Cow::Borrowed(_)))
+    }
+    
+    #[test]
+    fn references_are_borrowed() {
+        let doc = json!({"a": {"b": 1}, "items": [{"price": 2}, {"price": 3}]});
+        
+        for reference in [json!("$a.b"), json!(5), json!({"$literal": "$a"}), json!("$$ROOT"), json!("$$CURRENT.a")] {
+            assert!(is_borrowed(expr(reference.clone()).evaluate(&doc).unwrap()), "{}", reference);
+        }
+        assert!(is_borrowed(expr(json!({"$ifNull": ["$missing", "$a"]})).evaluate(&doc).unwrap()));
+        assert_eq!(expr(json!("$$ROOT")).evaluate(&doc).unwrap().as_deref(), Some(&doc));
+        
+        // Computed values are built
+        assert!(!is_borrowed(expr(json!({"$add": ["$a.b", 1]})).evaluate(&doc).unwrap()));
+        assert_eq!(expr(json!("$missing")).evaluate(&doc).unwrap(), None);
+    }
+    
+    #[test]
+    fn paths_through_arrays_give_the_matched_values() {
+        let doc = json!({
+            "items": [{"price": 2, "tags": [{"n": "a"}]}, {"price": 3, "tags": []}, {"name": "x"}, 7],
+            "grid": [[{"v": 1}], [{"v": 2}, {"w": 3}]],
+        });
+        
+        assert_eq!(eval(json!("$items.price"), &doc).unwrap(), Some(json!([2, 3])));
+        assert_eq!(eval(json!("$items.tags.n"), &doc).unwrap(), Some(json!([["a"], []])));
+        assert_eq!(eval(json!("$grid.v"), &doc).unwrap(), Some(json!([[1], [2]])));
+        assert_eq!(eval(json!("$items.missing"), &doc).unwrap(), Some(json!([])));
+        assert_eq!(eval(json!({"$size": "$items.price"}), &doc).unwrap(), Some(json!(2)));
+        
+        // Numeric segments still select a position
+        assert_eq!(eval(json!("$items.1.price"), &doc).unwrap(), Some(json!(3)));
+        assert!(is_borrowed(expr(json!("$items.0.price")).evaluate(&doc).unwrap()));
+        
+        // Variables and stored documents resolve paths the same way
+        let nested = expr(json!({"$map": {"input": [{"rows": [{"v": 1}, {"v": 2}]}], "in": "$$this.rows.v"}}));
+        assert_eq!(nested.evaluate(&doc).unwrap().as_deref(), Some(&json!([[1, 2]])));
+        let stored = document(json!({"id": "a", "items": [{"price": 4}, {"price": 5}]}));
+        assert_eq!(expr(json!("$items.price")).evaluate(&stored).unwrap().as_deref(), Some(&json!([4, 5])));
+        assert_eq!(expr(json!("$$ROOT.items.price")).evaluate(&stored).unwrap().as_deref(), Some(&json!([4, 5])));
+    }
+    
+    #[test]
+    fn element_variables() {
+        let doc = json!({"items": [{"price": 2}, {"price": 3}], "limit": 2});
+        
+        let prices = expr(json!({"$map": {"input": "$items", "as": "item", "in": "$$item.price"}}));
+        assert_eq!(prices.evaluate(&doc).unwrap().as_deref(), Some(&json!([2, 3])));
+        
+        let computed = expr(json!({"$map": {"input": {"$concatArrays": ["$items", [{"price": 4}]]}, "in": "$$this.price"}}));
+        assert_eq!(computed.evaluate(&doc).unwrap().as_deref(), Some(&json!([2, 3, 4])));
+        
+        let over = expr(json!({"$filter": {"input": "$items", "cond": {"$gt": ["$$this.price", "$limit"]}}}));
+        assert_eq!(over.evaluate(&doc).unwrap().as_deref(), Some(&json!([{"price": 3}])));
+        
+        assert_eq!(expr(json!("$$item")).evaluate(&doc).unwrap_err(), "Undefined variable: $$item");
+    }
+    
+    #[test]
+    fn root_of_a_stored_document_includes_its_id() {
+        let doc = document(json!({"id": "a", "n": 1}));
+        assert_eq!(expr(json!("$$ROOT")).evaluate(&doc).unwrap().as_deref(), Some(&json!({"id": "a", "n": 1})));
+        assert!(is_borrowed(expr(json!("$$ROOT.n")).evaluate(&doc).unwrap()));
+    }
+    
+    #[test]
+    fn arithmetic_and_comparisons() {
+        let doc = json!({"spent": 120, "budget": 100, "none": null});
+        
+        for (expression, expected) in [
+            (json!({"$subtract": ["$spent", "$budget"]}), json!(20)),
+            (json!({"$multiply": ["$spent", 2, 0.5]}), json!(120)),
+            (json!({"$divide": ["$spent", 8]}), json!(15)),
+            (json!({"$gt": ["$spent", "$budget"]}), json!(true)),
+            (json!({"$gt": ["$none", 1]}), json!(false)),
+            (json!({"$lt": ["$none", 1]}), json!(true)),
+            (json!({"$eq": ["$missing", null]}), json!(true)),
+            (json!({"$and": [{"$gt": ["$spent", 1]}, {"$ne": ["$budget", null]}]}), json!(true)),
+            (json!({"$round": [2.5]}), json!(2)),
+            (json!({"$round": [1.2345, 2]}), json!(1.23)),
+            (json!({"$add": ["$spent", null]}), json!(null)),
+        ] {
+            assert_eq!(eval(expression.clone(), &doc).unwrap(), Some(expected), "{}", expression);
+        }
+        
+        assert_eq!(eval(json!({"$divide": ["$spent", 0]}), &doc).unwrap_err(), "$divide by zero");
+        assert!(eval(json!({"$subtract": [1]}), &doc).is_err());
+        assert!(eval(json!({"$add": ["$spent", "x"]}), &doc).is_err());
+    }
+    
+    #[test]
+    fn strings_and_arrays() {
+        let doc = json!({"name": " Ann ", "tags": ["x", "y", "z"]});
+        
+        for (expression, expected) in [
+            (json!({"$toUpper": {"$trim": {"input": "$name"}}}), json!("ANN")),
+            (json!({"$concat": ["a", "-", "b"]}), json!("a-b")),
+            (json!({"$split": ["a-b-c", "-"]}), json!(["a", "b", "c"])),
+            (json!({"$substrCP": ["héllo", 1, 3]}), json!("éll")),
+            (json!({"$arrayElemAt": ["$tags", -1]}), json!("z")),
+            (json!({"$slice": ["$tags", -2]}), json!(["y", "z"])),
+            (json!({"$slice": ["$tags", -1e18]}), json!(["x", "y", "z"])),
+            (json!({"$size": {"$ifNull": ["$missing", []]}}), json!(0)),
+            (json!({"$in": ["y", "$tags"]}), json!(true)),
+            (json!({"$map": {"input": "$tags", "as": "t", "in": {"$concat": ["$$t", "!"]}}}), json!(["x!", "y!", "z!"])),
+        ] {
+            assert_eq!(eval(expression.clone(), &doc).unwrap(), Some(expected), "{}", expression);
+        }
+        
+        assert_eq!(eval(json!({"$arrayElemAt": ["$tags", 9]}), &doc).unwrap(), None);
+        assert!(eval(json!({"$size": "$name"}), &doc).is_err());
+        assert_eq!(eval(json!({"$slice": ["$tags", -1e19]}), &doc).unwrap_err(), "$slice count out of range");
+    }
+    
+    #[test]
+    fn conditionals() {
+        let doc = json!({"spent": 120, "tags": ["y"]});
+        
+        let switch = json!({"$switch": {
+            "branches": [
+                {"case": {"$gt": ["$spent", 200]}, "then": "high"},
+                {"case": {"$gt": ["$spent", 100]}, "then": "mid"},
+            ],
+            "default": "low",
+        }});
+        assert_eq!(eval(switch, &doc).unwrap(), Some(json!("mid")));
+        assert_eq!(eval(json!({"$cond": {"if": {"$in": ["y", "$tags"]}, "then": 1, "else": 0}}), &doc).unwrap(), Some(json!(1)));
+        assert_eq!(eval(json!({"$cond": [{"$eq": ["$spent", 0]}, "zero", "some"]}), &doc).unwrap(), Some(json!("some")));
+        
+        let unmatched = json!({"$switch": {"branches": [{"case": false, "then": 1}]}});
+        assert!(eval(unmatched, &doc).is_err());
+    }
+    
+    #[test]
+    fn dates_keep_their_representation() {
+        let doc = json!({"at": "2024-02-29T10:30:15.250Z", "epoch": 1_700_000_000_000i64});
+        
+        for (expression, expected) in [
+            (json!({"$year": "$at"}), json!(2024)),
+            (json!({"$dayOfWeek": "$at"}), json!(5)),
+            (json!({"$dayOfYear": "$at"}), json!(60)),
+            (json!({"$dateToString": {"date": "$at", "format": "%Y/%m/%d %H:%M:%S.%L %j %u"}}), json!("2024/02/29 10:30:15.250 060 4")),
+            (json!({"$dateAdd": {"startDate": "$at", "unit": "month", "amount": 12}}), json!("2025-02-28T10:30:15.250Z")),
+            (json!({"$dateDiff": {"startDate": "$at", "endDate": "2024-03-01T00:00:00Z", "unit": "day"}}), json!(1)),
+            (json!({"$dateAdd": {"startDate": "$epoch", "unit": "day", "amount": 1}}), json!(1_700_086_400_000i64)),
+            (json!({"$toDate": "$epoch"}), json!("2023-11-14T22:13:20.000Z")),
+        ] {
+            assert_eq!(eval(expression.clone(), &doc).unwrap(), Some(expected), "{}", expression);
+        }
+        
+        assert!(eval(json!({"$year": "not a date"}), &doc).is_err());
+        
+        for amount in [json!(1e18), json!(-1e18), json!(9e18), json!(1e300)] {
+            for unit in ["month", "quarter", "year", "day"] {
+                let add = json!({"$dateAdd": {"startDate": 0, "unit": unit, "amount": amount}});
+                assert_eq!(eval(add.clone(), &doc).unwrap_err(), "$dateAdd result out of range", "{}", add);
+            }
+        }
+    }
+    
+    #[test]
+    fn rejects_unknown_operators() {
+        assert_eq!(Expr::parse(&json!({"$bogus": 1})).unwrap_err(), "Unknown expression operator: $bogus");
+        assert!(Expr::parse(&json!({"$add": 1, "$subtract": 2})).is_err());
+        assert!(Expr::parse(&json!({"$map": {"in": "$$this"}})).is_err());
+    }
+}
@@ -0,0 +1,845 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use serde::de::{self, Deserializer, MapAccess, Visitor};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use crate::document::Document;
+use crate::expr::Expr;
+use crate::key;
+
+// Options accepted by `Collection::find`
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct FindOptions {
+    pub sort: Option<SortSpec>,
+    pub skip: usize,
+    // 0 means no limit
+    pub limit: usize,
+    pub projection: Option<Projection>,
+    // Continuation token from a previous page
+    pub after: Option<String>,
+}
+
+impl FindOptions {
+    pub fn parse(options_str: Option<&str>) -> Result<Self, String> {
+        match options_str {
+            Some(options_str) if !options_str.is_empty() => serde_json::from_str(options_str)
+                .map_err(|e| format!("Failed to parse find options: {}", e)),
+            _ => Ok(FindOptions::default()),
+        }
+    }
+    
+    // Results skipped before the first one returned. A page resumed from a
+    // token starts right after it, so skip only applies to the first page.
+    fn skipped(&self) -> usize {
+        match self.after {
+            Some(_) => 0,
+            None => self.skip,
+        }
+    }
+    
+    // Number of leading results needed before skip and limit are applied
+    pub fn needed(&self) -> Option<usize> {
+        match self.limit {
+            0 => None,
+            limit => Some(self.skipped().saturating_add(limit)),
+        }
+    }
+    
+    // Apply skip and limit to results already in order
+    pub fn page<T>(&self, results: Vec<T>) -> Vec<T> {
+        let results = results.into_iter().skip(self.skipped());
+        match self.limit {
+            0 => results.collect(),
+            limit => results.take(limit).collect(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Ascending,
+    Descending,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SortField {
+    Path(String, Direction),
+    // {$meta: "textScore"}, most relevant first
+    TextScore,
+}
+
+// Sort keys in the order given. JSON objects are read in document order here,
+// which a `serde_json::Map` would not keep.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortSpec(pub Vec<SortField>);
+
+impl<'de> Deserialize<'de> for SortSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct SortVisitor;
+        
+        impl<'de> Visitor<'de> for SortVisitor {
+            type Value = SortSpec;
+            
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("an object of field paths to 1, -1 or {\"$meta\": \"textScore\"}")
+            }
+            
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<SortSpec, A::Error> {
+                let mut fields = Vec::new();
+                
+                while let Some((path, order)) = map.next_entry::<String, Value>()? {
+                    let field = match &order {
+                        Value::Number(n) if n.as_f64() == Some(1.0) => SortField::Path(path, Direction::Ascending),
+                        Value::Number(n) if n.as_f64() == Some(-1.0) => SortField::Path(path, Direction::Descending),
+                        Value::String(s) if s == "asc" => SortField::Path(path, Direction::Ascending),
+                        Value::String(s) if s == "desc" => SortField::Path(path, Direction::Descending),
+                        Value::Object(obj) if obj.get("$meta") == Some(&Value::from("textScore")) => SortField::TextScore,
+                        _ => return Err(de::Error::custom(format!("Invalid sort order for '{}': {}", path, order))),
+                    };
+                    fields.push(field);
+                }
+                
+                Ok(SortSpec(fields))
+            }
+        }
+        
+        deserializer.deserialize_map(SortVisitor)
+    }
+}
+
+impl SortSpec {
+    pub fn uses_text_score(&self) -> bool {
+        self.0.contains(&SortField::TextScore)
+    }
+    
+    // The sorted paths if they all go the same direction
+    pub fn paths(&self) -> Option<(Vec<&str>, Direction)> {
+        let mut direction = None;
+        let mut paths = Vec::with_capacity(self.0.len());
+        
+        for field in &self.0 {
+            match field {
+                SortField::Path(path, dir) if *direction.get_or_insert(*dir) == *dir => paths.push(path.as_str()),
+                _ => return None,
+            }
+        }
+        
+        Some((paths, direction?))
+    }
+    
+    // FNV-1a hash of the spec, tying continuation tokens to the sort they
+    // were issued for
+    fn fingerprint(&self) -> u32 {
+        format!("{:?}", self.0).bytes()
+            .fold(0x811c9dc5, |hash, b| (hash ^ b as u32).wrapping_mul(0x01000193))
+    }
+}
+
+// Positions of one page of results, the relevance of its $text matches, and
+// the token resuming after it if more results follow
+pub struct Page {
+    pub slots: Vec<usize>,
+    pub scores: HashMap<usize, f64>,
+    pub next: Option<String>,
+}
+
+// Order slots by the sort spec, ties in insertion order. With `needed` only
+// that many leading slots are kept, selected without sorting the rest.
+pub fn sort_slots(slots: &mut Vec<usize>, documents: &[Document], sort: &SortSpec, scores: &HashMap<usize, f64>, needed: Option<usize>) {
+    let mut keyed: Vec<(Vec<u8>, usize)> = slots.iter()
+        .map(|&slot| (sort_key(sort, |path| documents[slot].values(path), score(scores, slot)), slot))
+        .collect();
+    
+    select_sorted(&mut keyed, needed);
+    *slots = keyed.into_iter().map(|(_, slot)| slot).collect();
+}
+
+// Order documents that are not stored in the collection, such as those
+// flowing through an aggregation, ties in their current order
+pub fn sort_values(values: &mut Vec<Value>, sort: &SortSpec, needed: Option<usize>) {
+    let mut keyed: Vec<(Vec<u8>, usize)> = values.iter()
+        .enumerate()
+        .map(|(i, value)| {
+            let get = |path: &str| value.as_object().map_or_else(Vec::new, |obj| path_values(obj, path));
+            (sort_key(sort, get, 0.0), i)
+        })
+        .collect();
+    
+    select_sorted(&mut keyed, needed);
+    
+    let mut taken: Vec<Option<Value>> = values.drain(..).map(Some).collect();
+    *values = keyed.into_iter()
+        .filter_map(|(_, i)| taken[i].take())
+        .collect();
+}
+
+// Order slots by the sort spec then document id, keep the page after the
+// `after` token, and return the token resuming after that page if more
+// results follow it. Keys only depend on the document, so a token resumes at
+// the same place whatever was inserted or deleted since. Text scores change
+// with the rest of the collection, so pages sorted by them get no token.
+pub fn page_slots(slots: &mut Vec<usize>, documents: &[Document], options: &FindOptions, scores: &HashMap<usize, f64>) -> Result<Option<String>, String> {
+    let default_sort = SortSpec::default();
+    let sort = options.sort.as_ref().unwrap_or(&default_sort);
+    let fingerprint = sort.fingerprint();
+    
+    if sort.uses_text_score() && options.after.is_some() {
+        return Err("Cannot resume a find sorted by textScore: scores change as the collection does".to_string());
+    }
+    
+    let after = match &options.after {
+        Some(token) => Some(decode_token(token, fingerprint)?),
+        None => None,
+    };
+    
+    let mut keyed: Vec<(Vec<u8>, usize)> = slots.iter()
+        .map(|&slot| {
+            let mut key = sort_key(sort, |path| documents[slot].values(path), score(scores, slot));
+            key.extend(key::encode_value(&Value::from(documents[slot].id())));
+            (key, slot)
+        })
+        .filter(|(key, _)| after.as_ref().is_none_or(|after| key > after))
+        .collect();
+    
+    // One more than the page shows whether another page follows
+    select_sorted(&mut keyed, options.needed().map(|needed| needed.saturating_add(1)));
+    
+    let next = match options.needed() {
+        Some(needed) if keyed.len() > needed && !sort.uses_text_score() => Some(encode_token(fingerprint, &keyed[needed - 1].0)),
+        _ => None,
+    };
+    
+    *slots = options.page(keyed).into_iter().map(|(_, slot)| slot).collect();
+    Ok(next)
+}
+
+// Sort keyed slots, keeping only the first `needed` if given
+fn select_sorted(keyed: &mut Vec<(Vec<u8>, usize)>, needed: Option<usize>) {
+    if let Some(needed) = needed {
+        if needed < keyed.len() {
+            if needed == 0 {
+                keyed.clear();
+            } else {
+                keyed.select_nth_unstable(needed - 1);
+                keyed.truncate(needed);
+            }
+        }
+    }
+    
+    keyed.sort_unstable();
+}
+
+fn score(scores: &HashMap<usize, f64>, slot: usize) -> f64 {
+    scores.get(&slot).copied().unwrap_or(0.0)
+}
+
+// Bytes that compare like a document does under the sort, given its field
+// values and text score: the encoding of each sort value, inverted for
+// descending order. Encodings are self-delimiting, so the concatenation
+// compares field by field.
+fn sort_key<'a>(sort: &SortSpec, get: impl Fn(&str) -> Vec<&'a Value>, score: f64) -> Vec<u8> {
+    let mut key = Vec::new();
+    
+    for field in &sort.0 {
+        let (encoded, direction) = match field {
+            SortField::Path(path, direction) => (field_key(&get(path), *direction), *direction),
+            SortField::TextScore => (key::encode_value(&Value::from(score)), Direction::Descending),
+        };
+        
+        match direction {
+            Direction::Ascending => key.extend(encoded),
+            Direction::Descending => key.extend(encoded.iter().map(|b| !b)),
+        }
+    }
+    
+    key
+}
+
+// Encoded sort key of a field's values, missing fields sorting as null. A
+// path resolving to several values or to arrays sorts by the smallest value
+// or element ascending and the largest descending.
+fn field_key(values: &[&Value], direction: Direction) -> Vec<u8> {
+    let keys = values.iter()
+        .flat_map(|&value| match value {
+            Value::Array(items) if !items.is_empty() => items.as_slice(),
+            _ => std::slice::from_ref(value),
+        })
+        .map(key::encode_value);
+    
+    match direction {
+        Direction::Ascending => keys.min(),
+        Direction::Descending => keys.max(),
+    }
+    .unwrap_or_else(|| key::encode_value(&Value::Null))
+}
+
+// Tokens are the hex of the sort fingerprint followed by the last key returned
+fn encode_token(fingerprint: u32, key: &[u8]) -> String {
+    fingerprint.to_be_bytes().iter()
+        .chain(key)
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn decode_token(token: &str, fingerprint: u32) -> Result<Vec<u8>, String> {
+    let invalid = || "Invalid continuation token".to_string();
+    
+    if !token.len().is_multiple_of(2) || !token.is_ascii() {
+        return Err(invalid());
+    }
+    let bytes = (0..token.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&token[i..i + 2], 16).map_err(|_| invalid()))
+        .collect::<Result<Vec<u8>, String>>()?;
+    
+    if bytes.len() < 4 {
+        return Err(invalid());
+    }
+    if bytes[..4] != fingerprint.to_be_bytes() {
+        return Err("Continuation token was issued for a different sort".to_string());
+    }
+    
+    Ok(bytes[4..].to_vec())
+}
+
+// Fields to keep, drop or compute for each result
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "Map<String, Value>")]
+pub struct Projection {
+    // Whether the listed paths are the ones kept
+    inclusive: bool,
+    paths: Vec<String>,
+    include_id: bool,
+    // Fields set to the $text relevance score
+    text_score_fields: Vec<String>,
+    // Fields set to an expression evaluated against the document
+    computed: Vec<(String, Expr)>,
+}
+
+impl TryFrom<Map<String, Value>> for Projection {
+    type Error = String;
+    
+    fn try_from(spec: Map<String, Value>) -> Result<Self, String> {
+        let mut inclusive = None;
+        let mut paths = Vec::new();
+        let mut include_id = true;
+        let mut text_score_fields = Vec::new();
+        let mut computed = Vec::new();
+        
+        for (path, rule) in spec {
+            if rule.get("$meta") == Some(&Value::from("textScore")) {
+                text_score_fields.push(path);
+                continue;
+            }
+            
+            // Any other rule is an expression, which includes its field
+            let include = match &rule {
+                Value::Bool(b) => Some(*b),
+                Value::Number(n) => Some(n.as_f64() != Some(0.0)),
+                _ => None,
+            };
+            
+            match include {
+                Some(include) if path == "id" => {
+                    include_id = include;
+                    continue;
+                },
+                Some(include) => {
+                    if *inclusive.get_or_insert(include) != include {
+                        return Err("Projection cannot both include and exclude fields".to_string());
+                    }
+                    paths.push(path);
+                },
+                None => {
+                    if !*inclusive.get_or_insert(true) {
+                        return Err("Projection cannot both include and exclude fields".to_string());
+                    }
+                    let expr = Expr::parse(&rule).map_err(|e| format!("Invalid projection for '{}': {}", path, e))?;
+                    computed.push((path, expr));
+                },
+            }
+        }
+        
+        Ok(Projection {
+            inclusive: inclusive.unwrap_or(false),
+            paths,
+            include_id,
+            text_score_fields,
+            computed,
+        })
+    }
+}
+
+impl Projection {
+    pub fn uses_text_score(&self) -> bool {
+        !self.text_score_fields.is_empty()
+    }
+    
+    // Project a serialized document, adding the text score fields if given one
+    pub fn apply(&self, doc: Value, score: Option<f64>) -> Result<Value, String> {
+        let source = match &doc {
+            Value::Object(obj) => obj,
+            _ => return Ok(doc),
+        };
+        
+        let mut projected = if self.inclusive {
+            let paths: Vec<&str> = self.paths.iter().map(String::as_str).collect();
+            include_paths(source, &paths)
+        } else {
+            let mut kept = source.clone();
+            for path in &self.paths {
+                remove_path(&mut kept, path);
+            }
+            kept
+        };
+        
+        if self.include_id {
+            if let Some(id) = source.get("id") {
+                projected.insert("id".to_string(), id.clone());
+            }
+        } else {
+            projected.remove("id");
+        }
+        
+        for (path, expr) in &self.computed {
+            if let Some(value) = expr.evaluate(&doc)? {
+                set_path(&mut projected, path, value.into_owned());
+            }
+        }
+        
+        if let Some(score) = score {
+            for field in &self.text_score_fields {
+                set_path(&mut projected, field, score.into());
+            }
+        }
+        
+        Ok(Value::Object(projected))
+    }
+}
+
+// Value at a dot path, through nested objects and numeric array positions
+pub fn get_path<'a>(obj: &'a Map<String, Value>, path: &str) -> Option<&'a Value> {
+    match path.split_once('.') {
+        Some((head, rest)) => get_nested(obj.get(head)?, rest),
+        None => obj.get(path),
+    }
+}
+
+fn get_nested<'a>(value: &'a Value, path: &str) -> Option<&'a Value> {
+    match value {
+        Value::Object(obj) => get_path(obj, path),
+        Value::Array(items) => {
+            let (head, rest) = split_path(path);
+            let item = items.get(array_index(head)?)?;
+            match rest {
+                Some(rest) => get_nested(item, rest),
+                None => Some(item),
+            }
+        },
+        _ => None,
+    }
+}
+
+// The value an expression's "$path" reads. Numeric segments select array
+// positions as in `get_path`, and other segments map over arrays, so
+// "items.price" gives the array of the items' prices. Arrays are only built
+// for paths that traverse one.
+pub fn resolve_path<'a>(obj: &'a Map<String, Value>, path: &str) -> Option<Cow<'a, Value>> {
+    match path.split_once('.') {
+        Some((head, rest)) => resolve_nested(obj.get(head)?, rest),
+        None => obj.get(path).map(Cow::Borrowed),
+    }
+}
+
+fn resolve_nested<'a>(value: &'a Value, path: &str) -> Option<Cow<'a, Value>> {
+    match value {
+        Value::Object(obj) => resolve_path(obj, path),
+        Value::Array(items) => {
+            let (head, rest) = split_path(path);
+            if let Some(item) = array_index(head).and_then(|i| items.get(i)) {
+                return match rest {
+                    Some(rest) => resolve_nested(item, rest),
+                    None => Some(Cow::Borrowed(item)),
+                };
+            }
+            
+            // Elements the path doesn't reach are left out
+            let values = items.iter()
+                .filter_map(|item| resolve_nested(item, path))
+                .map(Cow::into_owned)
+                .collect();
+            Some(Cow::Owned(Value::Array(values)))
+        },
+        _ => None,
+    }
+}
+
+// Call `visit` with each value a dot path resolves to until it returns true.
+// Numeric segments select array positions, and other segments fan out over
+// the subdocuments of an array, so "items.price" reaches every item's price.
+pub fn any_path_value<'a>(obj: &'a Map<String, Value>, path: &str, visit: &mut dyn FnMut(&'a Value) -> bool) -> bool {
+    match path.split_once('.') {
+        Some((head, rest)) => obj.get(head).is_some_and(|value| any_nested_value(value, rest, visit)),
+        None => obj.get(path).is_some_and(visit),
+    }
+}
+
+fn any_nested_value<'a>(value: &'a Value, path: &str, visit: &mut dyn FnMut(&'a Value) -> bool) -> bool {
+    match value {
+        Value::Object(obj) => any_path_value(obj, path, visit),
+        Value::Array(items) => {
+            let (head, rest) = split_path(path);
+            let positional = array_index(head).and_then(|i| items.get(i)).is_some_and(|item| match rest {
+                Some(rest) => any_nested_value(item, rest, visit),
+                None => visit(item),
+            });
+            
+            positional || items.iter().any(|item| matches!(item, Value::Object(obj) if any_path_value(obj, path, visit)))
+        },
+        _ => false,
+    }
+}
+
+// Every value a dot path resolves to, in document order
+pub fn path_values<'a>(obj: &'a Map<String, Value>, path: &str) -> Vec<&'a Value> {
+    let mut values = Vec::new();
+    any_path_value(obj, path, &mut |value| {
+        values.push(value);
+        false
+    });
+    values
+}
+
+fn split_path(path: &str) -> (&str, Option<&str>) {
+    match path.split_once('.') {
+        Some((head, rest)) => (head, Some(rest)),
+        None => (path, None),
+    }
+}
+
+// The array position a path segment names, if it is all digits
+fn array_index(segment: &str) -> Option<usize> {
+    if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) {
+        segment.parse().ok()
+    } else {
+        None
+    }
+}
+
+pub fn set_path(obj: &mut Map<String, Value>, path: &str, value: Value) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            let entry = obj.entry(head).or_insert_with(|| Value::Object(Map::new()));
+            if let Value::Object(inner) = entry {
+                set_path(inner, rest, value);
+            }
+        },
+        None => {
+            obj.insert(path.to_string(), value);
+        },
+    }
+}
+
+// The parts of an object that dot paths reach, fanning out over arrays as
+// path resolution does: "items.price" keeps the price of every item and
+// "items.0" the first item. Subdocuments and elements left with nothing are
+// dropped.
+pub fn include_paths(obj: &Map<String, Value>, paths: &[&str]) -> Map<String, Value> {
+    let mut kept = Map::new();
+    let mut nested: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+    
+    for path in paths {
+        match path.split_once('.') {
+            Some((head, rest)) => nested.entry(head).or_default().push(rest),
+            None => {
+                if let Some(value) = obj.get(*path) {
+                    kept.insert(path.to_string(), value.clone());
+                }
+            },
+        }
+    }
+    
+    for (head, rests) in nested {
+        // A field kept whole already holds its nested paths
+        if kept.contains_key(head) {
+            continue;
+        }
+        if let Some(value) = obj.get(head).and_then(|value| include_nested(value, &rests)) {
+            kept.insert(head.to_string(), value);
+        }
+    }
+    
+    kept
+}
+
+fn include_nested(value: &Value, paths: &[&str]) -> Option<Value> {
+    match value {
+        Value::Object(obj) => {
+            let kept = include_paths(obj, paths);
+            (!kept.is_empty()).then_some(Value::Object(kept))
+        },
+        Value::Array(items) => {
+            let kept: Vec<Value> = items.iter()
+                .enumerate()
+                .filter_map(|(i, item)| {
+                    // Paths through this position, then those fanning out
+                    // over subdocuments
+                    let mut item_paths = Vec::new();
+                    for path in paths {
+                        let (head, rest) = split_path(path);
+                        if array_index(head) == Some(i) {
+                            match rest {
+                                Some(rest) => item_paths.push(rest),
+                                None => return Some(item.clone()),
+                            }
+                        }
+                    }
+                    if item.is_object() {
+                        item_paths.extend(paths);
+                    }
+                    
+                    include_nested(item, &item_paths)
+                })
+                .collect();
+            (!kept.is_empty()).then_some(Value::Array(kept))
+        },
+        _ => None,
+    }
+}
+
+// Remove what a dot path reaches, through array positions and subdocuments
+// as in `include_paths`
+pub fn remove_path(obj: &mut Map<String, Value>, path: &str) {
+    match path.split_once('.') {
+        Some((head, rest)) => {
+            if let Some(value) = obj.get_mut(head) {
+                remove_nested(value, rest);
+            }
+        },
+        None => {
+            obj.remove(path);
+        },
+    }
+}
+
+fn remove_nested(value: &mut Value, path: &str) {
+    match value {
+        Value::Object(obj) => remove_path(obj, path),
+        Value::Array(items) => {
+            let (head, rest) = split_path(path);
+            if let (Some(i), Some(rest)) = (array_index(head), rest) {
+                if let Some(item) = items.get_mut(i) {
+                    remove_nested(item, rest);
+                }
+            }
+            for item in items {
+                if let Value::Object(obj) = item {
+                    remove_path(obj, path);
+                }
+            }
+        },
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::tests::{document, documents};
+    
+    fn numbered(count: usize) -> Vec<Document> {
+        (0..count)
+            .map(|n| document(json!({"id": format!("d{}", n), "n": n})))
+            .collect()
+    }
+    
+    fn page(documents: &[Document], options: Value) -> (Vec<usize>, Option<String>) {
+        let options: FindOptions = serde_json::from_value(options).unwrap();
+        let mut slots: Vec<usize> = (0..documents.len()).collect();
+        let next = page_slots(&mut slots, documents, &options, &HashMap::new()).unwrap();
+        (slots, next)
+    }
+    
+    // The sort is parsed from text since `json!` objects lose their key order
+    fn sorted_ids<'a>(documents: &'a [Document], sort: &str, needed: Option<usize>) -> Vec<&'a str> {
+        let sort: SortSpec = serde_json::from_str(sort).unwrap();
+        let mut slots: Vec<usize> = (0..documents.len()).collect();
+        sort_slots(&mut slots, documents, &sort, &HashMap::new(), needed);
+        slots.into_iter().map(|slot| documents[slot].id()).collect()
+    }
+    
+    fn projection(spec: Value) -> Result<Projection, String> {
+        serde_json::from_value(spec).map_err(|e| e.to_string())
+    }
+    
+    #[test]
+    fn skip_only_applies_to_the_first_page() {
+        let documents = numbered(6);
+        
+        let (slots, next) = page(&documents, json!({"sort": {"n": 1}, "skip": 1, "limit": 2}));
+        assert_eq!(slots, [1, 2]);
+        
+        let (slots, next) = page(&documents, json!({"sort": {"n": 1}, "skip": 1, "limit": 2, "after": next.unwrap()}));
+        assert_eq!(slots, [3, 4]);
+        
+        let (slots, next) = page(&documents, json!({"sort": {"n": 1}, "skip": 1, "limit": 2, "after": next.unwrap()}));
+        assert_eq!(slots, [5]);
+        assert!(next.is_none());
+    }
+    
+    #[test]
+    fn tokens_only_resume_their_sort() {
+        let documents = numbered(3);
+        let (_, next) = page(&documents, json!({"sort": {"n": 1}, "limit": 1}));
+        
+        let options: FindOptions = serde_json::from_value(json!({"sort": {"n": -1}, "after": next.unwrap()})).unwrap();
+        assert!(page_slots(&mut vec![0, 1, 2], &documents, &options, &HashMap::new()).is_err());
+    }
+    
+    #[test]
+    fn text_score_sorts_cannot_be_resumed() {
+        let documents = numbered(3);
+        let scores = HashMap::from([(0, 0.5), (1, 2.0), (2, 1.0)]);
+        let sort = r#"{"sort": {"score": {"$meta": "textScore"}}, "limit": 2}"#;
+        
+        let options: FindOptions = serde_json::from_str(sort).unwrap();
+        let mut slots = vec![0, 1, 2];
+        assert_eq!(page_slots(&mut slots, &documents, &options, &scores).unwrap(), None);
+        assert_eq!(slots, [1, 2]);
+        
+        let (_, next) = page(&documents, json!({"sort": {"n": 1}, "limit": 1}));
+        let mut options = options;
+        options.after = next;
+        let error = page_slots(&mut vec![0, 1, 2], &documents, &options, &scores).unwrap_err();
+        assert!(error.contains("textScore"), "{}", error);
+    }
+    
+    #[test]
+    fn sorts_by_each_key_in_turn() {
+        let documents = documents(&[
+            json!({"id": "a", "n": 3, "s": "x", "o": {"k": 2}, "arr": [5, 1]}),
+            json!({"id": "b", "n": 1, "s": "y", "o": {"k": 9}, "arr": [3]}),
+            json!({"id": "c", "n": 3, "s": "a", "o": {"k": 1}}),
+            json!({"id": "d", "s": "z"}),
+            json!({"id": "e", "n": "str", "o": {"k": 5}}),
+        ]);
+        
+        // Missing fields sort first and strings after numbers, as in key order
+        assert_eq!(sorted_ids(&documents, r#"{"n": 1, "s": -1}"#, None), ["d", "b", "a", "c", "e"]);
+        assert_eq!(sorted_ids(&documents, r#"{"n": 1, "s": 1}"#, None), ["d", "b", "c", "a", "e"]);
+        assert_eq!(sorted_ids(&documents, r#"{"s": 1, "n": 1}"#, None), ["e", "c", "a", "b", "d"]);
+        assert_eq!(sorted_ids(&documents, r#"{"n": "desc", "s": "asc"}"#, None), ["e", "c", "a", "b", "d"]);
+        assert_eq!(sorted_ids(&documents, r#"{"o.k": -1}"#, Some(2)), ["b", "e"]);
+        
+        // Arrays sort by their smallest element ascending, their largest descending
+        assert_eq!(sorted_ids(&documents[..2], r#"{"arr": 1}"#, None), ["a", "b"]);
+        assert_eq!(sorted_ids(&documents[..2], r#"{"arr": -1}"#, None), ["a", "b"]);
+    }
+    
+    #[test]
+    fn partial_sorts_keep_the_leading_results() {
+        let documents: Vec<Document> = (0..300)
+            .map(|n| document(json!({"id": format!("d{}", n), "v": (n * 37) % 101})))
+            .collect();
+        let all = sorted_ids(&documents, r#"{"v": -1}"#, None);
+        
+        for needed in [0, 1, 5, 150, 299, 300, 1000] {
+            let leading = sorted_ids(&documents, r#"{"v": -1}"#, Some(needed));
+            assert_eq!(leading, all[..needed.min(all.len())], "{}", needed);
+        }
+    }
+    
+    #[test]
+    fn rejects_invalid_options() {
+        assert!(FindOptions::parse(Some(r#"{"sort": {"a": 2}}"#)).is_err());
+        assert!(FindOptions::parse(Some(r#"{"sort": {"a": {"$meta": "vectorScore"}}}"#)).is_err());
+        assert!(FindOptions::parse(Some(r#"{"limit": -1}"#)).is_err());
+        assert!(FindOptions::parse(Some("")).is_ok());
+        
+        let error = projection(json!({"a": 1, "b": 0})).unwrap_err();
+        assert_eq!(error, "Projection cannot both include and exclude fields");
+        assert!(projection(json!({"a": 0, "b": {"$add": [1, 2]}})).is_err());
+        assert!(projection(json!({"a": {"$nope": 1}})).is_err());
+    }
+    
+    #[test]
+    fn projections_keep_or_drop_paths() {
+        let doc = json!({"id": "a", "n": 3, "s": "x", "o": {"k": 2, "j": 1}, "arr": [5, 1]});
+        let apply = |spec: Value| projection(spec).unwrap().apply(doc.clone(), None).unwrap();
+        
+        assert_eq!(apply(json!({"o.k": 1, "s": 1})), json!({"id": "a", "o": {"k": 2}, "s": "x"}));
+        assert_eq!(apply(json!({"o": 0, "arr": 0, "id": 0})), json!({"n": 3, "s": "x"}));
+        assert_eq!(apply(json!({"o.k": 0})), json!({"id": "a", "n": 3, "s": "x", "o": {"j": 1}, "arr": [5, 1]}));
+        assert_eq!(apply(json!({"id": 0})), json!({"n": 3, "s": "x", "o": {"k": 2, "j": 1}, "arr": [5, 1]}));
+        assert_eq!(apply(json!({"missing": 1})), json!({"id": "a"}));
+        
+        let scored = projection(json!({"s": 1, "score": {"$meta": "textScore"}})).unwrap();
+        assert!(scored.uses_text_score());
+        assert_eq!(scored.apply(doc.clone(), Some(1.5)).unwrap(), json!({"id": "a", "s": "x", "score": 1.5}));
+    }
+    
+    fn nested() -> Map<String, Value> {
+        match json!({"items": [{"price": 5, "tags": ["x"]}, {"price": 20}], "o": {"list": [{"v": 1}, {"v": [2, 3]}, 7]}, "m": [[{"price": 100}]]}) {
+            Value::Object(obj) => obj,
+            _ => unreachable!(),
+        }
+    }
+    
+    #[test]
+    fn paths_walk_positions_and_fan_out() {
+        let doc = nested();
+        
+        assert_eq!(get_path(&doc, "items.0.price"), Some(&json!(5)));
+        assert_eq!(get_path(&doc, "items.1"), Some(&json!({"price": 20})));
+        assert_eq!(get_path(&doc, "m.0.0.price"), Some(&json!(100)));
+        assert_eq!(get_path(&doc, "items.price"), None);
+        assert_eq!(get_path(&doc, "items.5.price"), None);
+        
+        assert_eq!(path_values(&doc, "items.price"), [&json!(5), &json!(20)]);
+        assert_eq!(path_values(&doc, "items.tags"), [&json!(["x"])]);
+        assert_eq!(path_values(&doc, "o.list.v"), [&json!(1), &json!([2, 3])]);
+        assert_eq!(path_values(&doc, "o.list.2"), [&json!(7)]);
+        // Fan-out only reaches subdocuments directly inside an array
+        assert!(path_values(&doc, "m.price").is_empty());
+        assert!(path_values(&doc, "items.-1.price").is_empty());
+        
+        let mut seen = 0;
+        assert!(any_path_value(&doc, "items.price", &mut |value| {
+            seen += 1;
+            value == &json!(5)
+        }));
+        assert_eq!(seen, 1);
+    }
+    
+    #[test]
+    fn projections_follow_paths_through_arrays() {
+        let doc = Value::Object(nested());
+        let project = |spec: Value| projection(spec).unwrap().apply(doc.clone(), None).unwrap();
+        
+        assert_eq!(project(json!({"items.price": 1, "o.list.v": 1})), json!({"items": [{"price": 5}, {"price": 20}], "o": {"list": [{"v": 1}, {"v": [2, 3]}]}}));
+        assert_eq!(project(json!({"items.1.price": 1, "items.tags": 1})), json!({"items": [{"tags": ["x"]}, {"price": 20}]}));
+        assert_eq!(project(json!({"items.0": 1, "items.price": 1})), json!({"items": [{"price": 5, "tags": ["x"]}, {"price": 20}]}));
+        assert_eq!(project(json!({"items.missing": 1})), json!({}));
+        
+        assert_eq!(project(json!({"items.price": 0, "o.list.0.v": 0, "m": 0})), json!({"items": [{"tags": ["x"]}, {}], "o": {"list": [{}, {"v": [2, 3]}, 7]}}));
+    }
+    
+    #[test]
+    fn fanned_out_sort_keys_use_the_extreme_value() {
+        let documents = documents(&[
+            json!({"id": "a", "items": [{"price": 5}, {"price": 20}]}),
+            json!({"id": "b", "items": [{"price": 12}, {"price": 3}]}),
+            json!({"id": "c", "items": {"price": 8}}),
+            json!({"id": "d", "items": []}),
+        ]);
+        
+        // Ascending sorts use each document's smallest value, descending
+        // its largest, and documents without one sort as missing
+        assert_eq!(sorted_ids(&documents, r#"{"items.price": 1}"#, None), ["d", "b", "a", "c"]);
+        assert_eq!(sorted_ids(&documents, r#"{"items.price": -1}"#, None), ["a", "b", "c", "d"]);
+        assert_eq!(sorted_ids(&documents, r#"{"items.0.price": 1}"#, None), ["c", "d", "a", "b"]);
+    }
+}
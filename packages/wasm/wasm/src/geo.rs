@@ -0,0 +1,673 @@
+use std::collections::{HashMap, HashSet};
+use serde_json::{Map, Value};
+
+// Mean earth radius used for spherical distances, in meters
+const EARTH_RADIUS_METERS: f64 = 6378100.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Point {
+    x: f64,
+    y: f64,
+}
+
+// Shapes are compared on the plane of their coordinates; spherical queries
+// only use great-circle math for distances
+#[derive(Debug, Clone)]
+enum Geometry {
+    Point(Point),
+    LineString(Vec<Point>),
+    // Exterior ring followed by holes
+    Polygon(Vec<Vec<Point>>),
+    MultiPolygon(Vec<Vec<Vec<Point>>>),
+}
+
+// Area of a $geoWithin query
+#[derive(Debug, Clone)]
+enum Region {
+    Box(Point, Point),
+    Circle { center: Point, radius: f64, spherical: bool },
+    Polygons(Vec<Vec<Vec<Point>>>),
+}
+
+// Axis-aligned area holding every point a query can match
+#[derive(Debug, Clone, Copy)]
+pub struct BBox {
+    min: Point,
+    max: Point,
+}
+
+#[derive(Debug, Clone)]
+struct NearSpec {
+    center: Point,
+    spherical: bool,
+    // Spherical distances are in meters for GeoJSON centers, radians otherwise
+    meters: bool,
+    max: Option<f64>,
+    min: Option<f64>,
+}
+
+pub fn is_geo_operator(op: &str) -> bool {
+    matches!(op, "$near" | "$nearSphere" | "$geoWithin" | "$geoIntersects")
+}
+
+// A geo operator with its operand parsed once, to check against many documents
+#[derive(Debug, Clone)]
+pub struct GeoCondition(GeoOperand);
+
+#[derive(Debug, Clone)]
+enum GeoOperand {
+    Near(NearSpec),
+    Within(Region),
+    Intersects(Geometry),
+}
+
+impl GeoCondition {
+    // Parse the operand of a geo operator. `siblings` holds the other
+    // operators of the condition, where legacy queries put $maxDistance.
+    pub fn parse(op: &str, spec: &Value, siblings: &Map<String, Value>) -> Result<GeoCondition, String> {
+        let operand = match op {
+            "$near" | "$nearSphere" => parse_near(op, spec, siblings).map(GeoOperand::Near),
+            "$geoWithin" => parse_region(spec).map(GeoOperand::Within),
+            _ => spec.get("$geometry").and_then(parse_geometry).map(GeoOperand::Intersects),
+        };
+        
+        operand.map(GeoCondition).ok_or_else(|| format!("Invalid {} operand: {}", op, spec))
+    }
+    
+    pub fn matches(&self, value: &Value) -> bool {
+        match &self.0 {
+            GeoOperand::Near(near) => near.distance(value).is_some(),
+            // $geoWithin: the whole document geometry lies in the region
+            GeoOperand::Within(region) => {
+                parse_geometry(value).is_some_and(|geometry| vertices(&geometry).iter().all(|point| region.contains(*point)))
+            },
+            // $geoIntersects: the document geometry and the query geometry share a point
+            GeoOperand::Intersects(query_geometry) => {
+                parse_geometry(value).is_some_and(|geometry| geometries_intersect(query_geometry, &geometry))
+            },
+        }
+    }
+    
+    pub fn is_near(&self) -> bool {
+        matches!(self.0, GeoOperand::Near(_))
+    }
+    
+    // Bounding box of the points the condition can match, `None` when it can
+    // match anywhere
+    pub fn bounds(&self) -> Option<BBox> {
+        match &self.0 {
+            GeoOperand::Near(near) => {
+                let radius = near.max?;
+                if near.spherical {
+                    let radians = if near.meters { radius / EARTH_RADIUS_METERS } else { radius };
+                    Some(spherical_bounds(near.center, radians))
+                } else {
+                    Some(circle_bounds(near.center, radius))
+                }
+            },
+            GeoOperand::Within(region) => match region {
+                Region::Box(a, b) => Some(BBox {
+                    min: Point { x: a.x.min(b.x), y: a.y.min(b.y) },
+                    max: Point { x: a.x.max(b.x), y: a.y.max(b.y) },
+                }),
+                Region::Circle { center, radius, spherical: true } => Some(spherical_bounds(*center, *radius)),
+                Region::Circle { center, radius, spherical: false } => Some(circle_bounds(*center, *radius)),
+                Region::Polygons(polygons) => points_bounds(polygons.iter().flatten().flatten()),
+            },
+            GeoOperand::Intersects(geometry) => points_bounds(vertices(geometry).iter()),
+        }
+    }
+    
+    // Distance from a $near/$nearSphere center to the document's point, if it
+    // is a point within the min/max distance
+    pub fn near_distance(&self, value: &Value) -> Option<f64> {
+        match &self.0 {
+            GeoOperand::Near(near) => near.distance(value),
+            _ => None,
+        }
+    }
+}
+
+impl NearSpec {
+    fn distance(&self, value: &Value) -> Option<f64> {
+        let point = parse_point(value)?;
+        
+        let distance = if self.spherical {
+            let angle = haversine(self.center, point);
+            if self.meters { angle * EARTH_RADIUS_METERS } else { angle }
+        } else {
+            planar_distance(self.center, point)
+        };
+        
+        if self.max.is_some_and(|max| distance > max) || self.min.is_some_and(|min| distance < min) {
+            return None;
+        }
+        
+        Some(distance)
+    }
+}
+
+// Grid of points, bucketed by cell. Documents holding other shapes are kept
+// apart and returned as candidates for every query.
+#[derive(Debug, Clone)]
+pub struct GeoIndex {
+    cell_size: f64,
+    cells: HashMap<(i64, i64), HashSet<String>>,
+    // Every point of a document, as a path through an array of subdocuments
+    // may resolve to several
+    points: HashMap<String, Vec<Point>>,
+    shapes: HashSet<String>,
+}
+
+impl GeoIndex {
+    pub fn new(cell_size: f64) -> Self {
+        GeoIndex {
+            cell_size,
+            cells: HashMap::new(),
+            points: HashMap::new(),
+            shapes: HashSet::new(),
+        }
+    }
+    
+    // Index each value of a document's field. A document holding any other
+    // shape is kept with the shapes; values that aren't geometries are not
+    // indexed.
+    pub fn add(&mut self, id: &str, values: &[&Value]) {
+        let mut points = Vec::new();
+        for geometry in values.iter().filter_map(|value| parse_geometry(value)) {
+            match geometry {
+                Geometry::Point(point) => points.push(point),
+                _ => {
+                    self.shapes.insert(id.to_string());
+                    return;
+                },
+            }
+        }
+        
+        if points.is_empty() {
+            return;
+        }
+        for &point in &points {
+            self.cells.entry(self.cell(point)).or_default().insert(id.to_string());
+        }
+        self.points.insert(id.to_string(), points);
+    }
+    
+    pub fn remove(&mut self, id: &str) {
+        for point in self.points.remove(id).unwrap_or_default() {
+            let cell = self.cell(point);
+            if let Some(ids) = self.cells.get_mut(&cell) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+        self.shapes.remove(id);
+    }
+    
+    pub fn clear(&mut self) {
+        self.cells.clear();
+        self.points.clear();
+        self.shapes.clear();
+    }
+    
+    // Number of occupied grid cells and of indexed geometries
+    pub fn stats(&self) -> (usize, usize) {
+        (self.cells.len() + self.shapes.len(), self.points.len() + self.shapes.len())
+    }
+    
+    // Expected number of candidates for the bounding box, assuming points are
+    // spread evenly over the occupied cells
+    pub fn estimate(&self, bounds: Option<BBox>) -> f64 {
+        let bounds = match bounds {
+            Some(bounds) if !self.cells.is_empty() => bounds,
+            _ => return (self.points.len() + self.shapes.len()) as f64,
+        };
+        
+        let (_, _, covered) = self.cell_span(bounds);
+        let fraction = (covered / self.cells.len() as f64).min(1.0);
+        
+        self.shapes.len() as f64 + self.points.len() as f64 * fraction
+    }
+    
+    // Ids of the documents that may lie in the bounding box, each once
+    pub fn candidates(&self, bounds: Option<BBox>) -> Vec<&str> {
+        let mut results: Vec<&str> = self.shapes.iter().map(String::as_str).collect();
+        
+        let bounds = match bounds {
+            Some(bounds) => bounds,
+            None => {
+                results.extend(self.points.keys().map(String::as_str));
+                return results;
+            },
+        };
+        
+        let ((min_x, min_y), (max_x, max_y), covered) = self.cell_span(bounds);
+        
+        // Visit whichever is smaller: the cells in the box or the occupied cells
+        if covered > self.cells.len() as f64 {
+            for ((x, y), ids) in &self.cells {
+                if (min_x..=max_x).contains(x) && (min_y..=max_y).contains(y) {
+                    results.extend(ids.iter().map(String::as_str));
+                }
+            }
+        } else {
+            for x in min_x..=max_x {
+                for y in min_y..=max_y {
+                    if let Some(ids) = self.cells.get(&(x, y)) {
+                        results.extend(ids.iter().map(String::as_str));
+                    }
+                }
+            }
+        }
+        
+        // Documents with points in several cells were gathered once per cell
+        let mut seen = HashSet::new();
+        results.retain(|id| seen.insert(*id));
+        results
+    }
+    
+    fn cell(&self, point: Point) -> (i64, i64) {
+        ((point.x / self.cell_size).floor() as i64, (point.y / self.cell_size).floor() as i64)
+    }
+    
+    // Corner cells of a bounding box and the number of cells it covers. Cell
+    // coordinates saturate for boxes far larger than the grid, so the count
+    // is worked out in f64 where it cannot overflow.
+    fn cell_span(&self, bounds: BBox) -> ((i64, i64), (i64, i64), f64) {
+        let (min_x, min_y) = self.cell(bounds.min);
+        let (max_x, max_y) = self.cell(bounds.max);
+        let covered = (max_x as f64 - min_x as f64 + 1.0) * (max_y as f64 - min_y as f64 + 1.0);
+        ((min_x, min_y), (max_x, max_y), covered)
+    }
+}
+
+impl Region {
+    fn contains(&self, point: Point) -> bool {
+        match self {
+            Region::Box(a, b) => {
+                point.x >= a.x.min(b.x) && point.x <= a.x.max(b.x)
+                    && point.y >= a.y.min(b.y) && point.y <= a.y.max(b.y)
+            },
+            Region::Circle { center, radius, spherical: true } => haversine(*center, point) <= *radius,
+            Region::Circle { center, radius, spherical: false } => planar_distance(*center, point) <= *radius,
+            Region::Polygons(polygons) => polygons.iter().any(|rings| in_polygon(point, rings)),
+        }
+    }
+}
+
+fn parse_near(op: &str, spec: &Value, siblings: &Map<String, Value>) -> Option<NearSpec> {
+    let distance = |key: &str| spec.get(key).or_else(|| siblings.get(key)).and_then(Value::as_f64);
+    
+    // GeoJSON form: {$geometry: Point, $maxDistance: meters}
+    if let Some(geometry) = spec.get("$geometry") {
+        return Some(NearSpec {
+            center: parse_point(geometry)?,
+            spherical: true,
+            meters: true,
+            max: distance("$maxDistance"),
+            min: distance("$minDistance"),
+        });
+    }
+    
+    // Legacy form: [x, y] with a sibling $maxDistance in coordinate units
+    // ($near) or radians ($nearSphere)
+    Some(NearSpec {
+        center: parse_coordinates(spec)?,
+        spherical: op == "$nearSphere",
+        meters: false,
+        max: distance("$maxDistance"),
+        min: distance("$minDistance"),
+    })
+}
+
+fn parse_region(spec: &Value) -> Option<Region> {
+    let spec = spec.as_object()?;
+    
+    if let Some(corners) = spec.get("$box") {
+        let corners = corners.as_array()?;
+        return Some(Region::Box(parse_coordinates(corners.first()?)?, parse_coordinates(corners.get(1)?)?));
+    }
+    
+    for (key, spherical) in [("$center", false), ("$centerSphere", true)] {
+        if let Some(circle) = spec.get(key) {
+            let circle = circle.as_array()?;
+            return Some(Region::Circle {
+                center: parse_coordinates(circle.first()?)?,
+                radius: circle.get(1)?.as_f64()?,
+                spherical,
+            });
+        }
+    }
+    
+    if let Some(points) = spec.get("$polygon") {
+        return Some(Region::Polygons(vec![vec![parse_ring(points)?]]));
+    }
+    
+    match parse_geometry(spec.get("$geometry")?)? {
+        Geometry::Polygon(rings) => Some(Region::Polygons(vec![rings])),
+        Geometry::MultiPolygon(polygons) => Some(Region::Polygons(polygons)),
+        _ => None,
+    }
+}
+
+// A GeoJSON geometry, or a legacy [x, y] coordinate pair
+fn parse_geometry(value: &Value) -> Option<Geometry> {
+    if value.is_array() {
+        return parse_coordinates(value).map(Geometry::Point);
+    }
+    
+    let coordinates = value.get("coordinates")?;
+    match value.get("type")?.as_str()? {
+        "Point" => Some(Geometry::Point(parse_coordinates(coordinates)?)),
+        "LineString" => Some(Geometry::LineString(parse_ring(coordinates)?)),
+        "Polygon" => Some(Geometry::Polygon(parse_rings(coordinates)?)),
+        "MultiPolygon" => Some(Geometry::MultiPolygon(
+            coordinates.as_array()?.iter().map(parse_rings).collect::<Option<_>>()?
+        )),
+        _ => None,
+    }
+}
+
+fn parse_point(value: &Value) -> Option<Point> {
+    match parse_geometry(value)? {
+        Geometry::Point(point) => Some(point),
+        _ => None,
+    }
+}
+
+fn parse_coordinates(value: &Value) -> Option<Point> {
+    match value.as_array()?.as_slice() {
+        [x, y] => Some(Point { x: x.as_f64()?, y: y.as_f64()? }),
+        _ => None,
+    }
+}
+
+fn parse_ring(value: &Value) -> Option<Vec<Point>> {
+    value.as_array()?.iter().map(parse_coordinates).collect()
+}
+
+fn parse_rings(value: &Value) -> Option<Vec<Vec<Point>>> {
+    value.as_array()?.iter().map(parse_ring).collect()
+}
+
+fn vertices(geometry: &Geometry) -> Vec<Point> {
+    match geometry {
+        Geometry::Point(point) => vec![*point],
+        Geometry::LineString(points) => points.clone(),
+        Geometry::Polygon(rings) => rings.first().cloned().unwrap_or_default(),
+        Geometry::MultiPolygon(polygons) => polygons.iter().filter_map(|rings| rings.first()).flatten().copied().collect(),
+    }
+}
+
+// Line segments making up a geometry's outline
+fn edges(geometry: &Geometry) -> Vec<(Point, Point)> {
+    let segments = |points: &[Point]| points.windows(2).map(|w| (w[0], w[1])).collect::<Vec<_>>();
+    
+    match geometry {
+        Geometry::Point(_) => Vec::new(),
+        Geometry::LineString(points) => segments(points),
+        Geometry::Polygon(rings) => rings.iter().flat_map(|ring| segments(ring)).collect(),
+        Geometry::MultiPolygon(polygons) => polygons.iter().flatten().flat_map(|ring| segments(ring)).collect(),
+    }
+}
+
+fn geometry_contains(geometry: &Geometry, point: Point) -> bool {
+    match geometry {
+        Geometry::Point(p) => *p == point,
+        Geometry::LineString(_) => edges(geometry).iter().any(|&(a, b)| on_segment(point, a, b)),
+        Geometry::Polygon(rings) => in_polygon(point, rings),
+        Geometry::MultiPolygon(polygons) => polygons.iter().any(|rings| in_polygon(point, rings)),
+    }
+}
+
+fn geometries_intersect(a: &Geometry, b: &Geometry) -> bool {
+    vertices(a).iter().any(|&point| geometry_contains(b, point))
+        || vertices(b).iter().any(|&point| geometry_contains(a, point))
+        || edges(a).iter().any(|&(p1, p2)| edges(b).iter().any(|&(q1, q2)| segments_intersect(p1, p2, q1, q2)))
+}
+
+// Inside the exterior ring (boundary included) and outside every hole
+fn in_polygon(point: Point, rings: &[Vec<Point>]) -> bool {
+    match rings.split_first() {
+        Some((exterior, holes)) => {
+            in_ring(point, exterior) && !holes.iter().any(|hole| in_ring(point, hole) && !on_ring(point, hole))
+        },
+        None => false,
+    }
+}
+
+// Ray casting, counting points on the boundary as inside
+fn in_ring(point: Point, ring: &[Point]) -> bool {
+    if on_ring(point, ring) {
+        return true;
+    }
+    
+    let mut inside = false;
+    let mut j = ring.len().wrapping_sub(1);
+    for i in 0..ring.len() {
+        let (a, b) = (ring[i], ring[j]);
+        if (a.y > point.y) != (b.y > point.y) && point.x < (b.x - a.x) * (point.y - a.y) / (b.y - a.y) + a.x {
+            inside = !inside;
+        }
+        j = i;
+    }
+    
+    inside
+}
+
+fn on_ring(point: Point, ring: &[Point]) -> bool {
+    (0..ring.len()).any(|i| on_segment(point, ring[i], ring[(i + 1) % ring.len()]))
+}
+
+fn cross(o: Point, a: Point, b: Point) -> f64 {
+    (a.x - o.x) * (b.y - o.y) - (a.y - o.y) * (b.x - o.x)
+}
+
+fn on_segment(point: Point, a: Point, b: Point) -> bool {
+    cross(a, b, point).abs() <= f64::EPSILON * (1.0 + a.x.abs() + a.y.abs() + b.x.abs() + b.y.abs())
+        && point.x >= a.x.min(b.x) && point.x <= a.x.max(b.x)
+        && point.y >= a.y.min(b.y) && point.y <= a.y.max(b.y)
+}
+
+fn segments_intersect(p1: Point, p2: Point, q1: Point, q2: Point) -> bool {
+    let d1 = cross(q1, q2, p1);
+    let d2 = cross(q1, q2, p2);
+    let d3 = cross(p1, p2, q1);
+    let d4 = cross(p1, p2, q2);
+    
+    if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0)) && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0)) {
+        return true;
+    }
+    
+    // Touching or collinear segments
+    on_segment(p1, q1, q2) || on_segment(p2, q1, q2) || on_segment(q1, p1, p2) || on_segment(q2, p1, p2)
+}
+
+fn planar_distance(a: Point, b: Point) -> f64 {
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt()
+}
+
+// Great-circle angle in radians between two [longitude, latitude] points
+fn haversine(a: Point, b: Point) -> f64 {
+    let (lat1, lat2) = (a.y.to_radians(), b.y.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lng = (b.x - a.x).to_radians();
+    
+    let h = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lng / 2.0).sin().powi(2);
+    2.0 * h.sqrt().min(1.0).asin()
+}
+
+fn circle_bounds(center: Point, radius: f64) -> BBox {
+    BBox {
+        min: Point { x: center.x - radius, y: center.y - radius },
+        max: Point { x: center.x + radius, y: center.y + radius },
+    }
+}
+
+// Longitude/latitude box around a spherical cap, widened to every longitude
+// near the poles or across the antimeridian
+fn spherical_bounds(center: Point, radians: f64) -> BBox {
+    let degrees = radians.to_degrees();
+    let min_y = (center.y - degrees).max(-90.0);
+    let max_y = (center.y + degrees).min(90.0);
+    
+    let widest = min_y.abs().max(max_y.abs());
+    let (min_x, max_x) = if widest >= 90.0 {
+        (-180.0, 180.0)
+    } else {
+        let spread = degrees / widest.to_radians().cos();
+        let (min_x, max_x) = (center.x - spread, center.x + spread);
+        if min_x < -180.0 || max_x > 180.0 { (-180.0, 180.0) } else { (min_x, max_x) }
+    };
+    
+    BBox {
+        min: Point { x: min_x, y: min_y },
+        max: Point { x: max_x, y: max_y },
+    }
+}
+
+fn points_bounds<'a>(points: impl Iterator<Item = &'a Point>) -> Option<BBox> {
+    let mut bounds: Option<BBox> = None;
+    
+    for point in points {
+        let b = bounds.get_or_insert(BBox { min: *point, max: *point });
+        b.min = Point { x: b.min.x.min(point.x), y: b.min.y.min(point.y) };
+        b.max = Point { x: b.max.x.max(point.x), y: b.max.y.max(point.y) };
+    }
+    
+    bounds
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    fn index(points: &[(&str, f64, f64)]) -> GeoIndex {
+        let mut index = GeoIndex::new(1.0);
+        for (id, x, y) in points {
+            index.add(id, &[&json!([x, y])]);
+        }
+        index
+    }
+    
+    fn candidates<'a>(index: &'a GeoIndex, op: &str, spec: Value) -> Vec<&'a str> {
+        let mut ids = index.candidates(condition(op, spec).bounds());
+        ids.sort_unstable();
+        ids
+    }
+    
+    #[test]
+    fn oversized_regions_visit_occupied_cells() {
+        let index = index(&[("a", 0.0, 0.0), ("b", 5.0, -3.0), ("c", -1e6, 1e6)]);
+        
+        assert_eq!(candidates(&index, "$geoWithin", json!({"$center": [[0, 0], 1e300]})), vec!["a", "b", "c"]);
+        assert_eq!(candidates(&index, "$geoWithin", json!({"$box": [[-1e300, -1e300], [1e300, 1e300]]})), vec!["a", "b", "c"]);
+        assert_eq!(candidates(&index, "$geoWithin", json!({"$box": [[0, -1e300], [1e300, 1e300]]})), vec!["a", "b"]);
+        assert_eq!(candidates(&index, "$geoWithin", json!({"$centerSphere": [[0, 0], 1e300]})), vec!["a", "b"]);
+    }
+    
+    #[test]
+    fn oversized_regions_estimate_every_point() {
+        let index = index(&[("a", 0.0, 0.0), ("b", 5.0, -3.0)]);
+        let bounds = |spec: Value| condition("$geoWithin", spec).bounds();
+        
+        assert_eq!(index.estimate(bounds(json!({"$center": [[0, 0], 1e300]}))), 2.0);
+        assert_eq!(index.estimate(bounds(json!({"$box": [[-1e300, 0], [1e300, 1]]}))), 2.0);
+        assert_eq!(index.estimate(bounds(json!({"$box": [[0, 0], [0.5, 0.5]]}))), 1.0);
+    }
+    
+    fn condition(op: &str, spec: Value) -> GeoCondition {
+        GeoCondition::parse(op, &spec, &Map::new()).unwrap()
+    }
+    
+    #[test]
+    fn within_requires_the_whole_geometry_inside() {
+        let square = condition("$geoWithin", json!({"$geometry": {
+            "type": "Polygon",
+            "coordinates": [[[0, 0], [10, 0], [10, 10], [0, 10], [0, 0]], [[4, 4], [6, 4], [6, 6], [4, 6], [4, 4]]],
+        }}));
+        
+        assert!(square.matches(&json!([1, 1])));
+        assert!(square.matches(&json!({"type": "Point", "coordinates": [10, 5]})));
+        assert!(!square.matches(&json!([5, 5])));
+        assert!(!square.matches(&json!([11, 5])));
+        assert!(square.matches(&json!({"type": "LineString", "coordinates": [[1, 1], [2, 8]]})));
+        assert!(!square.matches(&json!({"type": "LineString", "coordinates": [[1, 1], [12, 8]]})));
+        assert!(!square.matches(&json!("nowhere")));
+        
+        let boxed = condition("$geoWithin", json!({"$box": [[0, 0], [2, 2]]}));
+        assert!(boxed.matches(&json!([1, 2])));
+        assert!(!boxed.matches(&json!([3, 1])));
+        
+        let circle = condition("$geoWithin", json!({"$center": [[0, 0], 5]}));
+        assert!(circle.matches(&json!([3, 4])));
+        assert!(!circle.matches(&json!([4, 4])));
+    }
+    
+    #[test]
+    fn intersects_shares_a_point() {
+        let line = condition("$geoIntersects", json!({"$geometry": {"type": "LineString", "coordinates": [[-1, 5], [1, 5]]}}));
+        let area = json!({"type": "Polygon", "coordinates": [[[0, 0], [5, 0], [5, 10], [0, 10], [0, 0]]]});
+        
+        assert!(line.matches(&area));
+        assert!(line.matches(&json!([0, 5])));
+        assert!(!line.matches(&json!([0, 6])));
+        assert!(!line.matches(&json!({"type": "LineString", "coordinates": [[-1, 6], [1, 6]]})));
+        
+        let point = condition("$geoIntersects", json!({"$geometry": {"type": "Point", "coordinates": [1, 1]}}));
+        assert!(point.matches(&area));
+        assert!(!point.matches(&json!({"type": "MultiPolygon", "coordinates": [[[[2, 2], [3, 2], [3, 3], [2, 2]]]]})));
+    }
+    
+    #[test]
+    fn near_distances_use_the_operand_units() {
+        let paris = json!({"type": "Point", "coordinates": [2.35, 48.85]});
+        let london = json!({"type": "Point", "coordinates": [-0.12, 51.5]});
+        
+        let meters = condition("$near", json!({"$geometry": {"type": "Point", "coordinates": [2.35, 48.85]}, "$maxDistance": 400000}));
+        assert_eq!(meters.near_distance(&paris), Some(0.0));
+        let distance = meters.near_distance(&london).unwrap();
+        assert!((340000.0..350000.0).contains(&distance), "{}", distance);
+        assert!(meters.near_distance(&json!({"type": "Point", "coordinates": [-74.0, 40.7]})).is_none());
+        
+        let siblings = json!({"$maxDistance": 1});
+        let planar = GeoCondition::parse("$near", &json!([2, 50]), siblings.as_object().unwrap()).unwrap();
+        assert_eq!(planar.near_distance(&paris), None);
+        assert_eq!(planar.near_distance(&json!([2, 49])), Some(1.0));
+        
+        let radians = condition("$nearSphere", json!([0, 0]));
+        assert!((radians.near_distance(&json!([90, 0])).unwrap() - std::f64::consts::FRAC_PI_2).abs() < 1e-9);
+        assert_eq!(radians.near_distance(&json!({"type": "LineString", "coordinates": [[0, 0], [1, 1]]})), None);
+    }
+    
+    #[test]
+    fn invalid_operands_are_rejected() {
+        for (op, spec) in [
+            ("$geoWithin", json!({"$box": [[0, 0]]})),
+            ("$geoWithin", json!({"$polygon": "square"})),
+            ("$geoIntersects", json!({"type": "Point", "coordinates": [0, 0]})),
+            ("$near", json!("here")),
+        ] {
+            let error = GeoCondition::parse(op, &spec, &Map::new()).unwrap_err();
+            assert!(error.starts_with(&format!("Invalid {} operand", op)), "{}", error);
+        }
+    }
+    
+    #[test]
+    fn shapes_are_candidates_for_every_query() {
+        let mut index = index(&[("a", 0.5, 0.5), ("b", 20.0, 20.0)]);
+        index.add("area", &[&json!({"type": "Polygon", "coordinates": [[[0, 0], [1, 0], [1, 1], [0, 0]]]})]);
+        index.add("none", &[&json!("nowhere")]);
+        
+        assert_eq!(index.stats(), (3, 3));
+        assert_eq!(candidates(&index, "$geoWithin", json!({"$box": [[0, 0], [1, 1]]})), vec!["a", "area"]);
+        assert_eq!(candidates(&index, "$near", json!([0, 0])), vec!["a", "area", "b"]);
+        
+        index.remove("a");
+        index.remove("area");
+        assert_eq!(candidates(&index, "$geoWithin", json!({"$box": [[0, 0], [1, 1]]})), Vec::<&str>::new());
+    }
+}
@@ -1,13 +1,89 @@
-use std::collections::{HashMap, HashSet};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Bound;
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
+use crate::date;
 use crate::document::Document;
+use crate::geo::GeoIndex;
+use crate::key;
+use crate::planner::{CLOSED_RANGE_SELECTIVITY, OPEN_RANGE_SELECTIVITY};
 use crate::query::Query;
+use crate::text::TextIndex;
+use crate::vector::{HnswOptions, Metric, VectorIndex};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum IndexType {
     Single,
     Unique,
     Multi,
+    Ordered,
+    Text,
+    Vector,
+    Geo,
+}
+
+// Options accepted by `Collection::create_index`
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct IndexOptions {
+    // Skip documents that have none of the indexed fields
+    pub sparse: bool,
+    // Only index documents matching this query
+    pub partial_filter_expression: Option<Query>,
+    // Reduce words to their stems in text indexes
+    pub stemming: bool,
+    // Similarity measure of vector indexes
+    pub metric: Metric,
+    // Required vector length, taken from the first vector when not set
+    pub dimensions: Option<usize>,
+    // Use an approximate HNSW graph instead of exact flat search
+    pub hnsw: Option<HnswOptions>,
+    // Grid cell width of geo indexes, in coordinate units
+    pub cell_size: Option<f64>,
+    // Expire documents this long after the date in the indexed field
+    pub expire_after_seconds: Option<f64>,
+}
+
+impl IndexOptions {
+    // Reject options no index could be built with
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(cell_size) = self.cell_size {
+            if !(cell_size.is_finite() && cell_size > 0.0) {
+                return Err(format!("cellSize must be a positive number, got {}", cell_size));
+            }
+        }
+        if let Some(hnsw) = &self.hnsw {
+            hnsw.validate()?;
+        }
+        if let Some(seconds) = self.expire_after_seconds {
+            if !(seconds.is_finite() && seconds >= 0.0) {
+                return Err(format!("expireAfterSeconds must be a non-negative number, got {}", seconds));
+            }
+        }
+        
+        Ok(())
+    }
+}
+
+// Size of an index, which the planner estimates plan costs from
+#[derive(Serialize, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub struct IndexStats {
+    pub distinct_keys: usize,
+    // Number of (key, document) pairs; multikey documents count once per key
+    pub entries: usize,
+    pub avg_bucket_size: f64,
+}
+
+// Start and end of a scan over encoded keys
+type KeyRange = (Bound<Vec<u8>>, Bound<Vec<u8>>);
+
+// How a key-based index answers a query: the equality or $in values of a run
+// of its leading fields, then optionally a range on the next field
+struct KeyPlan<'a> {
+    prefix: Vec<Vec<&'a Value>>,
+    range: Option<(Bound<&'a Value>, Bound<&'a Value>)>,
 }
 
 #[derive(Debug, Clone)]
@@ -15,180 +91,963 @@ pub struct Index {
     name: String,
     fields: Vec<String>,
     index_type: IndexType,
-    // For single/unique indexes: field_value -> document_id
-    single_index: HashMap<String, String>,
-    // For multi indexes: field_value -> set of document_ids
-    multi_index: HashMap<String, HashSet<String>>,
+    options: IndexOptions,
+    // Per field, whether any document was indexed under the elements of an
+    // array there. Stays set until the index is cleared.
+    multikey: Vec<bool>,
+    backend: Backend,
+}
+
+// Where an index keeps its entries, which depends on its type. Keys are the
+// encoded field values, see `key`, kept sorted so a leading subset of the
+// fields can be looked up as a key prefix.
+#[derive(Debug, Clone)]
+enum Backend {
+    // Unique indexes: key -> document id
+    Unique(BTreeMap<Vec<u8>, String>),
+    // Single, multi and ordered indexes: key -> document ids, with the number
+    // of ids across the sets
+    Buckets(BTreeMap<Vec<u8>, HashSet<String>>, usize),
+    // Term postings over the string content of all fields
+    Text(TextIndex),
+    // Embeddings of the single indexed field
+    Vector(VectorIndex),
+    // Grid of the points in the single indexed field
+    Geo(GeoIndex),
 }
 
 impl Index {
-    pub fn new(name: &str, fields: &[String], index_type: IndexType) -> Self {
+    pub fn new(name: &str, fields: &[String], index_type: IndexType, options: IndexOptions) -> Self {
+        let backend = match index_type {
+            IndexType::Unique => Backend::Unique(BTreeMap::new()),
+            IndexType::Single | IndexType::Multi | IndexType::Ordered => Backend::Buckets(BTreeMap::new(), 0),
+            IndexType::Text => Backend::Text(TextIndex::new(options.stemming)),
+            IndexType::Vector => Backend::Vector(VectorIndex::new(options.metric, options.dimensions, options.hnsw.clone())),
+            IndexType::Geo => Backend::Geo(GeoIndex::new(options.cell_size.unwrap_or(1.0))),
+        };
+        
         Index {
             name: name.to_string(),
             fields: fields.to_vec(),
             index_type,
-            single_index: HashMap::new(),
-            multi_index: HashMap::new(),
+            options,
+            multikey: vec![false; fields.len()],
+            backend,
         }
     }
     
     pub fn add_document(&mut self, doc: &Document) -> Result<(), String> {
-        let key = self.get_index_key(doc)?;
+        let keys = match self.backend {
+            Backend::Unique(_) | Backend::Buckets(..) => self.get_index_keys(doc)?,
+            _ if self.skips(doc) => return Ok(()),
+            _ => Vec::new(),
+        };
+        if keys.len() > 1 {
+            if let Some(i) = self.fields.iter().position(|field| key_values(doc, field).len() > 1) {
+                self.multikey[i] = true;
+            }
+        }
         
-        match self.index_type {
-            IndexType::Single | IndexType::Unique => {
-                if let Some(existing_id) = self.single_index.get(&key) {
-                    if self.index_type == IndexType::Unique && existing_id != doc.id() {
-                        return Err(format!("Duplicate key '{}' for unique index '{}'", key, self.name));
+        // Check every key before inserting any, so a rejected document leaves no entries
+        if let Backend::Unique(ids) = &self.backend {
+            if keys.iter().any(|key| ids.get(key).is_some_and(|id| id != doc.id())) {
+                return Err(format!("Duplicate key '{}' for unique index '{}'", self.describe_key(doc), self.name));
+            }
+        }
+        
+        match &mut self.backend {
+            Backend::Unique(ids) => {
+                for key in keys {
+                    ids.insert(key, doc.id().to_string());
+                }
+            },
+            Backend::Buckets(buckets, entries) => {
+                for key in keys {
+                    if buckets.entry(key).or_default().insert(doc.id().to_string()) {
+                        *entries += 1;
                     }
                 }
-                
-                self.single_index.insert(key, doc.id().to_string());
             },
-            IndexType::Multi => {
-                let entry = self.multi_index.entry(key).or_insert_with(HashSet::new);
-                entry.insert(doc.id().to_string());
-            }
+            Backend::Text(text) => text.add(doc.id(), &field_values(&self.fields, doc)),
+            Backend::Vector(vectors) => vectors.add(doc.id(), &doc.values(&self.fields[0]))?,
+            Backend::Geo(grid) => grid.add(doc.id(), &doc.values(&self.fields[0])),
         }
         
         Ok(())
     }
     
     pub fn remove_document(&mut self, doc: &Document) -> Result<(), String> {
-        let key = self.get_index_key(doc)?;
+        let keys = match self.backend {
+            Backend::Unique(_) | Backend::Buckets(..) => self.get_index_keys(doc)?,
+            _ => Vec::new(),
+        };
         
-        match self.index_type {
-            IndexType::Single | IndexType::Unique => {
-                if let Some(id) = self.single_index.get(&key) {
-                    if id == doc.id() {
-                        self.single_index.remove(&key);
+        match &mut self.backend {
+            Backend::Unique(ids) => {
+                for key in keys {
+                    if ids.get(&key).is_some_and(|id| id == doc.id()) {
+                        ids.remove(&key);
                     }
                 }
             },
-            IndexType::Multi => {
-                if let Some(ids) = self.multi_index.get_mut(&key) {
-                    ids.remove(doc.id());
-                    if ids.is_empty() {
-                        self.multi_index.remove(&key);
+            Backend::Buckets(buckets, entries) => {
+                for key in keys {
+                    if let Some(ids) = buckets.get_mut(&key) {
+                        if ids.remove(doc.id()) {
+                            *entries -= 1;
+                        }
+                        if ids.is_empty() {
+                            buckets.remove(&key);
+                        }
                     }
                 }
-            }
+            },
+            Backend::Text(text) => text.remove(doc.id(), &field_values(&self.fields, doc)),
+            Backend::Vector(vectors) => vectors.remove(doc.id()),
+            Backend::Geo(grid) => grid.remove(doc.id()),
         }
         
         Ok(())
     }
     
     pub fn clear(&mut self) {
-        self.single_index.clear();
-        self.multi_index.clear();
+        self.multikey.fill(false);
+        match &mut self.backend {
+            Backend::Unique(ids) => ids.clear(),
+            Backend::Buckets(buckets, entries) => {
+                buckets.clear();
+                *entries = 0;
+            },
+            Backend::Text(text) => text.clear(),
+            Backend::Vector(vectors) => vectors.clear(),
+            Backend::Geo(grid) => grid.clear(),
+        }
     }
     
     pub fn can_use_for_query(&self, query: &Query) -> Option<&str> {
-        // Check if any of the indexed fields are used in the query
-        for field in &self.fields {
-            // Check for simple equality
-            if query.has_simple_equality(field) {
-                return Some(field);
+        // A partial index only holds the documents matching its filter
+        if let Some(filter) = &self.options.partial_filter_expression {
+            if !query.implies(filter) {
+                return None;
             }
-            
-            // Check for $eq operator
-            if query.has_equality_operator(field).is_some() {
-                return Some(field);
+        }
+        
+        // Text indexes only answer $text, which nothing else can
+        if self.index_type == IndexType::Text {
+            return query.text_search().map(|_| self.fields[0].as_str());
+        }
+        
+        // Vector indexes are only searched through `Collection::vector_search`
+        if self.index_type == IndexType::Vector {
+            return None;
+        }
+        
+        // Geo indexes answer the geo operators on their field
+        if self.index_type == IndexType::Geo {
+            let field = &self.fields[0];
+            return query.geo_condition(field).map(|_| field.as_str());
+        }
+        
+        // Other indexes need the query to constrain their leading field
+        self.key_plan(query).map(|_| self.fields[0].as_str())
+    }
+    
+    // Why `can_use_for_query` rejects the query
+    pub fn unusable_reason(&self, query: &Query) -> String {
+        if let Some(filter) = &self.options.partial_filter_expression {
+            if !query.implies(filter) {
+                return "query does not imply the partial filter expression".to_string();
             }
         }
         
-        None
+        let field = &self.fields[0];
+        
+        match self.index_type {
+            IndexType::Text => "text indexes only answer $text queries".to_string(),
+            IndexType::Vector => "vector indexes are only used by vectorSearch".to_string(),
+            IndexType::Geo => format!("query has no geo operator on '{}'", field),
+            _ if self.options.sparse && query.equality_values(field).is_some_and(|values| values.iter().any(|value| value.is_null())) => {
+                format!("sparse index leaves out documents missing '{}', which equality to null matches", field)
+            },
+            IndexType::Ordered => format!("query has no equality, $in or range on leading field '{}'", field),
+            _ => format!("query has no equality or $in on leading field '{}'", field),
+        }
     }
     
-    pub fn query(&self, query: &Query) -> Vec<Document> {
+    pub fn stats(&self) -> IndexStats {
+        let (distinct_keys, entries) = match &self.backend {
+            Backend::Unique(ids) => (ids.len(), ids.len()),
+            Backend::Buckets(buckets, entries) => (buckets.len(), *entries),
+            Backend::Text(text) => text.stats(),
+            Backend::Vector(vectors) => (vectors.len(), vectors.len()),
+            Backend::Geo(grid) => grid.stats(),
+        };
+        
+        IndexStats {
+            distinct_keys,
+            entries,
+            avg_bucket_size: if distinct_keys == 0 { 0.0 } else { entries as f64 / distinct_keys as f64 },
+        }
+    }
+    
+    // Expected number of ids `query` returns for a query the index can be
+    // used for. Equalities on k of n fields are assumed to narrow the entries
+    // as much as k/n of a full key does.
+    pub fn estimate_rows(&self, query: &Query) -> f64 {
+        match &self.backend {
+            Backend::Text(text) => {
+                return query.text_search()
+                    .map(|search| text.estimate(search) as f64)
+                    .unwrap_or(0.0);
+            },
+            Backend::Geo(grid) => {
+                return match query.geo_condition(&self.fields[0]) {
+                    Some(geo) => grid.estimate(geo.bounds()),
+                    None => 0.0,
+                };
+            },
+            Backend::Vector(_) => return 0.0,
+            Backend::Unique(_) | Backend::Buckets(..) => {},
+        }
+        
+        let plan = match self.key_plan(query) {
+            Some(plan) => plan,
+            None => return 0.0,
+        };
+        
+        let stats = self.stats();
+        if stats.distinct_keys == 0 {
+            return 0.0;
+        }
+        
+        // Each $in value is a separate lookup
+        let lookups: usize = plan.prefix.iter().map(Vec::len).product();
+        let pinned = plan.prefix.len() as f64 / self.fields.len() as f64;
+        let mut rows = (lookups as f64 * stats.entries as f64 / (stats.distinct_keys as f64).powf(pinned))
+            .min(stats.entries as f64);
+        
+        if let Some(range) = plan.range {
+            rows *= match range {
+                (Bound::Unbounded, _) | (_, Bound::Unbounded) => OPEN_RANGE_SELECTIVITY,
+                _ => CLOSED_RANGE_SELECTIVITY,
+            };
+        }
+        
+        rows
+    }
+    
+    // Returns the ids of the documents whose key matches the query's equality,
+    // $in or range conditions; ordered indexes return them in key order. Callers
+    // resolve the ids and apply the full query as a post-filter.
+    pub fn query(&self, query: &Query) -> Vec<&str> {
+        match &self.backend {
+            Backend::Text(text) => {
+                return query.text_search()
+                    .map(|search| text.search(search))
+                    .unwrap_or_default();
+            },
+            Backend::Geo(grid) => {
+                return match query.geo_condition(&self.fields[0]) {
+                    Some(geo) => grid.candidates(geo.bounds()),
+                    None => Vec::new(),
+                };
+            },
+            Backend::Vector(_) => return Vec::new(),
+            Backend::Unique(_) | Backend::Buckets(..) => {},
+        }
+        
+        let plan = match self.key_plan(query) {
+            Some(plan) => plan,
+            None => return Vec::new(),
+        };
+        
+        // One prefix per combination of the fields' values, sorted so ordered
+        // indexes still return ids in key order
+        let mut prefixes: Vec<Vec<u8>> = vec![Vec::new()];
+        for values in &plan.prefix {
+            prefixes = prefixes.iter()
+                .flat_map(|prefix| values.iter().map(move |value| [prefix.as_slice(), &key::encode_value(value)].concat()))
+                .collect();
+        }
+        prefixes.sort_unstable();
+        prefixes.dedup();
+        
         let mut results = Vec::new();
         
-        // Find the field we can use
-        for field in &self.fields {
-            let field_value = if query.has_simple_equality(field) {
-                query.get_field_value(field)
-            } else if let Some(eq_value) = query.has_equality_operator(field) {
-                Some(eq_value)
-            } else {
-                None
+        for prefix in prefixes {
+            // Every key starting with the encoded equality values matches them,
+            // since each encoded value is self-delimiting
+            let range = match plan.range {
+                Some((lower, upper)) => key_range(&prefix, lower, upper),
+                None => Some((Bound::Included(prefix.clone()), prefix_end(&prefix))),
             };
             
-            if let Some(value) = field_value {
-                let key = self.value_to_string(value);
-                
-                match self.index_type {
-                    IndexType::Single | IndexType::Unique => {
-                        if let Some(doc_id) = self.single_index.get(&key) {
-                            // We would need to fetch the document by ID here
-                            // For now, we'll just create a placeholder
-                            let mut doc = Document::new();
-                            // In a real implementation, we would fetch the full document
-                            results.push(doc);
-                        }
-                    },
-                    IndexType::Multi => {
-                        if let Some(doc_ids) = self.multi_index.get(&key) {
-                            for doc_id in doc_ids {
-                                // We would need to fetch the document by ID here
-                                // For now, we'll just create a placeholder
-                                let mut doc = Document::new();
-                                // In a real implementation, we would fetch the full document
-                                results.push(doc);
+            let (start, end) = match range {
+                Some(range) => range,
+                None => continue,
+            };
+            
+            match &self.backend {
+                Backend::Unique(ids) => {
+                    results.extend(ids.range((start, end)).map(|(_, doc_id)| doc_id.as_str()));
+                },
+                Backend::Buckets(buckets, _) => {
+                    for (_, doc_ids) in buckets.range((start, end)) {
+                        results.extend(doc_ids.iter().map(String::as_str));
+                    }
+                },
+                Backend::Text(_) | Backend::Vector(_) | Backend::Geo(_) => unreachable!(),
+            }
+        }
+        
+        results
+    }
+    
+    // BM25 relevance of the documents matching the query's $text search
+    pub fn text_scores(&self, query: &Query) -> Vec<(&str, f64)> {
+        match (&self.backend, query.text_search()) {
+            (Backend::Text(text), Some(search)) => text.score(search),
+            _ => Vec::new(),
+        }
+    }
+    
+    // Whether the TTL option has expired the document at `now` (epoch
+    // milliseconds). Arrays of dates, or of subdocuments holding dates, expire
+    // with their earliest date.
+    pub fn is_expired(&self, doc: &Document, now: f64) -> bool {
+        let expire_after = match self.options.expire_after_seconds {
+            Some(seconds) => seconds * 1000.0,
+            None => return false,
+        };
+        
+        // Only the documents the index holds expire
+        if self.skips(doc) {
+            return false;
+        }
+        
+        let expires_from = doc.values(&self.fields[0]).into_iter()
+            .flat_map(|value| match value {
+                Value::Array(items) => items.iter().collect(),
+                value => vec![value],
+            })
+            .filter_map(date::to_millis)
+            .reduce(f64::min);
+        
+        expires_from.is_some_and(|millis| millis + expire_after <= now)
+    }
+    
+    pub fn fields(&self) -> &[String] {
+        &self.fields
+    }
+    
+    pub fn is_text(&self) -> bool {
+        self.index_type == IndexType::Text
+    }
+    
+    // Ids grouped by key in key order, reversed if `descending`, when walking
+    // them sorts the collection by the indexed fields: every one of its
+    // `documents` is indexed, under a single key
+    pub fn sorted_buckets(&self, descending: bool, documents: usize) -> Option<Box<dyn Iterator<Item = Vec<&str>> + '_>> {
+        if self.options.sparse || self.options.partial_filter_expression.is_some() {
+            return None;
+        }
+        
+        let buckets: Box<dyn DoubleEndedIterator<Item = Vec<&str>>> = match &self.backend {
+            Backend::Unique(ids) if ids.len() == documents => {
+                Box::new(ids.values().map(|id| vec![id.as_str()]))
+            },
+            Backend::Buckets(buckets, entries) if *entries == documents => {
+                Box::new(buckets.values().map(|ids| ids.iter().map(String::as_str).collect()))
+            },
+            _ => return None,
+        };
+        
+        Some(if descending { Box::new(buckets.rev()) } else { buckets })
+    }
+    
+    // The vector index over a field, if this is one
+    pub fn vector_index(&self, field: &str) -> Option<&VectorIndex> {
+        match &self.backend {
+            Backend::Vector(vectors) if self.fields[0] == field => Some(vectors),
+            _ => None,
+        }
+    }
+    
+    // Equality or $in values for the leading fields the query fixes, followed for
+    // ordered indexes by the range on the next field. `None` when the query
+    // leaves the first field unconstrained.
+    fn key_plan<'a>(&self, query: &'a Query) -> Option<KeyPlan<'a>> {
+        let mut prefix = Vec::new();
+        let mut range = None;
+        
+        for (i, field) in self.fields.iter().enumerate() {
+            match query.equality_values(field) {
+                Some(values) => prefix.push(values),
+                None => {
+                    // Ordered indexes can also answer range operators
+                    if self.index_type == IndexType::Ordered {
+                        range = query.range_bounds(field);
+                        
+                        // Different elements of an array may satisfy each
+                        // bound, so multikey indexes scan from the lower one
+                        if let Some((lower @ (Bound::Included(_) | Bound::Excluded(_)), Bound::Included(_) | Bound::Excluded(_))) = range {
+                            if self.multikey[i] {
+                                range = Some((lower, Bound::Unbounded));
                             }
                         }
                     }
+                    break;
                 }
-                
-                // We found a usable field, no need to check others
-                break;
             }
         }
         
-        // Filter results to match the full query
-        results.into_iter().filter(|doc| query.matches(doc)).collect()
-    }
-    
-    pub fn query_one(&self, query: &Query) -> Option<Document> {
-        self.query(query).into_iter().next()
+        if prefix.is_empty() && range.is_none() {
+            return None;
+        }
+        
+        // A sparse index leaves out the documents missing every field, and
+        // equality to null matches a missing field, so a prefix of nulls
+        // alone would lose them
+        if self.options.sparse && range.is_none() && prefix.iter().all(|values| values.iter().any(|value| value.is_null())) {
+            return None;
+        }
+        
+        Some(KeyPlan { prefix, range })
     }
     
-    fn get_index_key(&self, doc: &Document) -> Result<String, String> {
-        if self.fields.len() == 1 {
-            // Single field index
-            let field = &self.fields[0];
-            let value = doc.get(field);
+    // Keys a document is indexed under, none if the index skips it. Fields
+    // whose path resolves to several values or to an array are multikey: the
+    // document is indexed under each value, each array as a whole and each
+    // distinct element, so both whole-array and element equality lookups find
+    // it.
+    fn get_index_keys(&self, doc: &Document) -> Result<Vec<Vec<u8>>, String> {
+        if self.skips(doc) {
+            return Ok(Vec::new());
+        }
+        
+        let mut values = Vec::with_capacity(self.fields.len());
+        let mut array_field = None;
+        
+        for (i, field) in self.fields.iter().enumerate() {
+            let field_values = key_values(doc, field);
+            values.push(field_values[0]);
             
-            if let Some(value) = value {
-                Ok(self.value_to_string(value))
-            } else {
-                Err(format!("Field '{}' not found in document", field))
+            if field_values.len() > 1 {
+                if array_field.is_some() {
+                    return Err(format!("Cannot index parallel arrays in index '{}'", self.name));
+                }
+                array_field = Some((i, field_values));
             }
-        } else {
-            // Compound index
-            let mut key_parts = Vec::new();
-            
-            for field in &self.fields {
-                let value = doc.get(field);
-                
-                if let Some(value) = value {
-                    key_parts.push(self.value_to_string(value));
-                } else {
-                    key_parts.push("null".to_string());
+        }
+        
+        let mut keys = Vec::new();
+        
+        match array_field {
+            Some((i, field_values)) => {
+                for value in field_values {
+                    values[i] = value;
+                    keys.push(key::encode_values(values.iter().copied()));
                 }
+                
+                keys.sort_unstable();
+                keys.dedup();
+            },
+            None => keys.push(key::encode_values(values.iter().copied())),
+        }
+        
+        Ok(keys)
+    }
+    
+    // Whether the sparse or partial filter options leave the document out
+    fn skips(&self, doc: &Document) -> bool {
+        if let Some(filter) = &self.options.partial_filter_expression {
+            if !filter.matches(doc) {
+                return true;
             }
+        }
+        
+        self.options.sparse && self.fields.iter().all(|field| !doc.any_value(field, &mut |_| true))
+    }
+    
+    // Human-readable form of a document's key for error messages
+    fn describe_key(&self, doc: &Document) -> String {
+        let values: Vec<String> = self.fields.iter()
+            .map(|field| doc.get(field).unwrap_or(&Value::Null).to_string())
+            .collect();
+        values.join(", ")
+    }
+}
+
+// Every value the fields resolve to, which text indexes take words from
+fn field_values<'a>(fields: &[String], doc: &'a Document) -> Vec<&'a Value> {
+    fields.iter().flat_map(|field| doc.values(field)).collect()
+}
+
+// Values a field is keyed under: each value its path resolves to and the
+// elements of arrays among them, or null if it resolves to none
+fn key_values<'a>(doc: &'a Document, field: &str) -> Vec<&'a Value> {
+    let mut values = Vec::new();
+    
+    for value in doc.values(field) {
+        values.push(value);
+        if let Value::Array(items) = value {
+            values.extend(items);
+        }
+    }
+    
+    if values.is_empty() {
+        values.push(&Value::Null);
+    }
+    
+    values
+}
+
+// Convert query bounds on the field after `prefix` into encoded key bounds
+// covering every key that continues with a value in range. A missing bound is
+// limited to the type of the other one, since comparisons never match across
+// types. Returns `None` when the range is empty.
+fn key_range(prefix: &[u8], lower: Bound<&Value>, upper: Bound<&Value>) -> Option<KeyRange> {
+    let encode = |value: &Value| [prefix, &key::encode_value(value)].concat();
+    let type_bound = |value: &Value, end: bool| {
+        let bounds = key::type_bounds(&key::encode_value(value));
+        [prefix, if end { &bounds.1 } else { &bounds.0 }].concat()
+    };
+    
+    if let (Bound::Included(l) | Bound::Excluded(l), Bound::Included(u) | Bound::Excluded(u)) = (lower, upper) {
+        if key::type_bounds(&key::encode_value(l)) != key::type_bounds(&key::encode_value(u)) {
+            return None;
+        }
+    }
+    
+    // Keys continuing past an excluded lower or included upper value still
+    // share its encoding as a prefix, so step over all of them
+    let start = match (lower, upper) {
+        (Bound::Included(value), _) => Bound::Included(encode(value)),
+        (Bound::Excluded(value), _) => match prefix_end(&encode(value)) {
+            Bound::Excluded(key) => Bound::Included(key),
+            _ => return None,
+        },
+        (Bound::Unbounded, Bound::Included(value) | Bound::Excluded(value)) => Bound::Included(type_bound(value, false)),
+        (Bound::Unbounded, Bound::Unbounded) => Bound::Included(prefix.to_vec()),
+    };
+    
+    let end = match (lower, upper) {
+        (_, Bound::Included(value)) => prefix_end(&encode(value)),
+        (_, Bound::Excluded(value)) => Bound::Excluded(encode(value)),
+        (Bound::Included(value) | Bound::Excluded(value), Bound::Unbounded) => Bound::Excluded(type_bound(value, true)),
+        (Bound::Unbounded, Bound::Unbounded) => prefix_end(prefix),
+    };
+    
+    // BTreeMap::range panics on inverted or empty-exclusive ranges
+    if let (Bound::Included(s) | Bound::Excluded(s), Bound::Included(e) | Bound::Excluded(e)) = (&start, &end) {
+        match s.cmp(e) {
+            Ordering::Greater => return None,
+            Ordering::Equal if !matches!((&start, &end), (Bound::Included(_), Bound::Included(_))) => return None,
+            _ => {}
+        }
+    }
+    
+    Some((start, end))
+}
+
+// Exclusive upper bound of the keys starting with `prefix`: the prefix with
+// its last byte below 0xFF incremented and the rest dropped
+fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    
+    while let Some(last) = end.pop() {
+        if last < 0xFF {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    
+    Bound::Unbounded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::tests::{document, documents, query};
+    
+    fn index(fields: &[&str], index_type: IndexType, options: IndexOptions, documents: &[Document]) -> Index {
+        let fields: Vec<String> = fields.iter().map(|field| field.to_string()).collect();
+        let mut index = Index::new("test", &fields, index_type, options);
+        for doc in documents {
+            index.add_document(doc).unwrap();
+        }
+        index
+    }
+    
+    fn sorted(mut ids: Vec<&str>) -> Vec<&str> {
+        ids.sort_unstable();
+        ids
+    }
+    
+    #[test]
+    fn ordered_indexes_answer_ranges_in_key_order() {
+        let documents = documents(&[
+            json!({"id": "a", "age": 3}),
+            json!({"id": "b", "age": 1}),
+            json!({"id": "c", "age": 2}),
+            json!({"id": "d", "age": "10"}),
+            json!({"id": "e"}),
+        ]);
+        let index = index(&["age"], IndexType::Ordered, IndexOptions::default(), &documents);
+        
+        for (condition, expected) in [
+            (json!({"$gt": 1}), vec!["c", "a"]),
+            (json!({"$gte": 1, "$lt": 3}), vec!["b", "c"]),
+            (json!({"$lte": 2}), vec!["b", "c"]),
+            (json!({"$gte": 0}), vec!["b", "c", "a"]),
+            // Ranges stay within the type of their bound
+            (json!({"$gt": "1"}), vec!["d"]),
+            (json!({"$gt": 1, "$lt": "z"}), vec![]),
+            (json!({"$gt": 3, "$lt": 1}), vec![]),
+            (json!({"$gt": 3, "$lt": 3}), vec![]),
+            (json!(2), vec!["c"]),
+        ] {
+            let query = query(json!({"age": condition}));
+            assert!(index.can_use_for_query(&query).is_some(), "{:?}", query);
+            assert_eq!(index.query(&query), expected, "{:?}", query);
+        }
+        
+        let ascending: Vec<Vec<&str>> = index.sorted_buckets(false, documents.len()).unwrap().collect();
+        assert_eq!(ascending.concat(), ["e", "b", "c", "a", "d"]);
+    }
+    
+    #[test]
+    fn hash_indexes_refuse_ranges() {
+        let documents = documents(&[json!({"id": "a", "age": 3})]);
+        let index = index(&["age"], IndexType::Single, IndexOptions::default(), &documents);
+        
+        let range = query(json!({"age": {"$gt": 1}}));
+        assert!(index.can_use_for_query(&range).is_none());
+        assert_eq!(index.unusable_reason(&range), "query has no equality or $in on leading field 'age'");
+    }
+    
+    #[test]
+    fn arrays_are_keyed_whole_and_per_element() {
+        let documents = documents(&[
+            json!({"id": "a", "tags": ["rust", "db"], "n": [1, 10]}),
+            json!({"id": "b", "tags": "rust", "n": 3}),
+            json!({"id": "c", "tags": ["go"], "n": []}),
+        ]);
+        let tags = index(&["tags"], IndexType::Multi, IndexOptions::default(), &documents);
+        let n = index(&["n"], IndexType::Ordered, IndexOptions::default(), &documents);
+        
+        for (index, condition, expected) in [
+            (&tags, json!({"tags": "rust"}), vec!["a", "b"]),
+            (&tags, json!({"tags": {"$eq": "go"}}), vec!["c"]),
+            (&tags, json!({"tags": ["rust", "db"]}), vec!["a"]),
+            (&n, json!({"n": []}), vec!["c"]),
+            // Different elements may satisfy each bound
+            (&n, json!({"n": {"$gt": 5, "$lt": 2}}), vec!["a"]),
+            (&n, json!({"n": {"$gte": 3}}), vec!["a", "b"]),
+        ] {
+            let query = query(condition);
+            let matched: Vec<&str> = documents.iter()
+                .filter(|doc| query.matches(*doc))
+                .map(Document::id)
+                .collect();
+            assert_eq!(matched, expected, "{:?}", query);
             
-            Ok(key_parts.join("|"))
+            // Indexes may return a superset, but each document only once
+            let mut found = sorted(index.query(&query));
+            let mut unique = found.clone();
+            unique.dedup();
+            assert_eq!(found, unique, "{:?}", query);
+            found.retain(|id| matched.contains(id));
+            assert_eq!(found, expected, "{:?}", query);
         }
     }
     
-    fn value_to_string(&self, value: &Value) -> String {
-        match value {
-            Value::Null => "null".to_string(),
-            Value::Bool(b) => b.to_string(),
-            Value::Number(n) => n.to_string(),
-            Value::String(s) => s.clone(),
-            Value::Array(_) => serde_json::to_string(value).unwrap_or_else(|_| "[]".to_string()),
-            Value::Object(_) => serde_json::to_string(value).unwrap_or_else(|_| "{}".to_string()),
+    #[test]
+    fn removing_an_array_document_drops_every_key() {
+        let documents = documents(&[json!({"id": "a", "tags": ["x", "y", "x"]}), json!({"id": "b", "tags": ["y"]})]);
+        let mut index = index(&["tags"], IndexType::Multi, IndexOptions::default(), &documents);
+        
+        index.remove_document(&documents[0]).unwrap();
+        assert!(index.query(&query(json!({"tags": "x"}))).is_empty());
+        assert_eq!(index.query(&query(json!({"tags": "y"}))), ["b"]);
+    }
+    
+    #[test]
+    fn compound_indexes_refuse_parallel_arrays() {
+        let documents = documents(&[json!({"id": "a", "tags": ["x"], "n": [1, 2]})]);
+        let fields = ["tags".to_string(), "n".to_string()];
+        let mut index = Index::new("pair", &fields, IndexType::Multi, IndexOptions::default());
+        assert_eq!(index.add_document(&documents[0]).unwrap_err(), "Cannot index parallel arrays in index 'pair'");
+    }
+    
+    #[test]
+    fn null_equality_finds_missing_fields_like_the_matcher() {
+        let documents = documents(&[json!({"id": "a", "f": 1}), json!({"id": "b", "f": null}), json!({"id": "c"})]);
+        let index = index(&["f"], IndexType::Single, IndexOptions::default(), &documents);
+        
+        for (condition, expected) in [
+            (json!(null), vec!["b", "c"]),
+            (json!({"$eq": null}), vec!["b", "c"]),
+            (json!({"$in": [null, 1]}), vec!["a", "b", "c"]),
+        ] {
+            let query = query(json!({"f": condition}));
+            assert!(index.can_use_for_query(&query).is_some());
+            
+            let matched: Vec<&str> = documents.iter()
+                .filter(|doc| query.matches(*doc))
+                .map(Document::id)
+                .collect();
+            assert_eq!(matched, expected);
+            assert_eq!(sorted(index.query(&query)), expected);
+        }
+    }
+    
+    #[test]
+    fn sparse_unique_indexes_skip_missing_fields() {
+        let documents = documents(&[json!({"id": "a", "email": "a@x"}), json!({"id": "b"}), json!({"id": "c"})]);
+        let options = IndexOptions { sparse: true, ..IndexOptions::default() };
+        let index = index(&["email"], IndexType::Unique, options, &documents);
+        
+        assert_eq!(index.stats().entries, 1);
+        assert_eq!(index.query(&query(json!({"email": "a@x"}))), ["a"]);
+    }
+    
+    #[test]
+    fn partial_indexes_need_queries_implying_their_filter() {
+        let documents = documents(&[
+            json!({"id": "a", "name": "x", "age": 1}),
+            json!({"id": "b", "name": "y", "age": 2}),
+            json!({"id": "c", "name": "x", "age": 3}),
+        ]);
+        let options = IndexOptions {
+            partial_filter_expression: Some(query(json!({"age": {"$gte": 2}}))),
+            ..IndexOptions::default()
+        };
+        let index = index(&["name"], IndexType::Single, options, &documents);
+        
+        for (condition, usable) in [
+            (json!({"name": "x"}), false),
+            (json!({"name": "x", "age": 3}), true),
+            (json!({"name": "x", "age": 1}), false),
+            (json!({"name": "x", "age": {"$gt": 2}}), true),
+            (json!({"name": "x", "age": {"$gte": 2}}), true),
+            (json!({"name": "x", "age": {"$gt": 1}}), false),
+            (json!({"name": "x", "age": {"$gt": 1.5}}), false),
+        ] {
+            let query = query(condition);
+            assert_eq!(index.can_use_for_query(&query).is_some(), usable, "{:?}", query);
         }
+        
+        let query = query(json!({"name": "x"}));
+        assert_eq!(index.unusable_reason(&query), "query does not imply the partial filter expression");
+        assert_eq!(index.query(&query), ["c"]);
+    }
+    
+    #[test]
+    fn sparse_indexes_refuse_null_equality() {
+        let documents = documents(&[json!({"id": "a", "f": 1}), json!({"id": "b", "f": null}), json!({"id": "c"})]);
+        let options = IndexOptions { sparse: true, ..IndexOptions::default() };
+        let index = index(&["f"], IndexType::Single, options, &documents);
+        assert_eq!(index.query(&query(json!({"f": 1}))), ["a"]);
+        
+        for condition in [json!(null), json!({"$in": [null, 1]})] {
+            let query = query(json!({"f": condition}));
+            assert!(index.can_use_for_query(&query).is_none());
+            assert_eq!(index.unusable_reason(&query), "sparse index leaves out documents missing 'f', which equality to null matches");
+        }
+    }
+    
+    #[test]
+    fn ttl_indexes_expire_from_the_earliest_date() {
+        let documents = documents(&[
+            json!({"id": "a", "at": "1970-01-01T00:00:10Z"}),
+            json!({"id": "b", "at": 20000}),
+            json!({"id": "c", "at": [50000, 5000]}),
+            json!({"id": "d", "at": "nope"}),
+            json!({"id": "e"}),
+        ]);
+        let options = IndexOptions { expire_after_seconds: Some(10.0), ..IndexOptions::default() };
+        let ttl = index(&["at"], IndexType::Single, options.clone(), &documents);
+        
+        let expired = |now: f64| -> Vec<&str> {
+            documents.iter().filter(|doc| ttl.is_expired(doc, now)).map(Document::id).collect()
+        };
+        assert!(expired(14_999.0).is_empty());
+        assert_eq!(expired(15_000.0), ["c"]);
+        assert_eq!(expired(20_000.0), ["a", "c"]);
+        assert_eq!(expired(1e15), ["a", "b", "c"]);
+        
+        let plain = index(&["at"], IndexType::Single, IndexOptions::default(), &documents);
+        assert!(!plain.is_expired(&documents[0], 1e15));
+        
+        // Dates reached through an array of subdocuments
+        let event = document(json!({"id": "f", "log": [{"at": 30000}, {"at": [4000, 60000]}, {}]}));
+        let nested = index(&["log.at"], IndexType::Single, options, std::slice::from_ref(&event));
+        assert!(!nested.is_expired(&event, 13_999.0));
+        assert!(nested.is_expired(&event, 14_000.0));
+    }
+    
+    #[test]
+    fn options_reject_unusable_cell_sizes_and_hnsw_parameters() {
+        let options = |options: Value| serde_json::from_value::<IndexOptions>(options).unwrap();
+        
+        assert!(options(json!({})).validate().is_ok());
+        assert!(options(json!({"cellSize": 0.5})).validate().is_ok());
+        assert_eq!(options(json!({"cellSize": 0})).validate().unwrap_err(), "cellSize must be a positive number, got 0");
+        assert!(options(json!({"cellSize": -2})).validate().is_err());
+        
+        let infinite = IndexOptions { cell_size: Some(f64::INFINITY), ..IndexOptions::default() };
+        assert!(infinite.validate().is_err());
+        let nan = IndexOptions { cell_size: Some(f64::NAN), ..IndexOptions::default() };
+        assert!(nan.validate().is_err());
+        
+        assert!(options(json!({"hnsw": {}})).validate().is_ok());
+        assert!(options(json!({"hnsw": {"m": 2, "efConstruction": 1, "efSearch": 1}})).validate().is_ok());
+        assert_eq!(options(json!({"hnsw": {"m": 1}})).validate().unwrap_err(), "hnsw.m must be at least 2, got 1");
+        assert_eq!(options(json!({"hnsw": {"efConstruction": 0}})).validate().unwrap_err(), "hnsw.efConstruction must be at least 1");
+        assert_eq!(options(json!({"hnsw": {"efSearch": 0}})).validate().unwrap_err(), "hnsw.efSearch must be at least 1");
+        
+        assert!(options(json!({"expireAfterSeconds": 0})).validate().is_ok());
+        assert_eq!(options(json!({"expireAfterSeconds": -1})).validate().unwrap_err(), "expireAfterSeconds must be a non-negative number, got -1");
+        let forever = IndexOptions { expire_after_seconds: Some(f64::INFINITY), ..IndexOptions::default() };
+        assert!(forever.validate().is_err());
+        let nan = IndexOptions { expire_after_seconds: Some(f64::NAN), ..IndexOptions::default() };
+        assert!(nan.validate().is_err());
+    }
+    
+    fn compound_documents() -> Vec<Document> {
+        documents(&[
+            json!({"id": "d0", "a": 1, "b": "x", "c": 1}),
+            json!({"id": "d1", "a": 1, "b": "y", "c": 2}),
+            json!({"id": "d2", "a": 2, "b": "x", "c": 3}),
+            json!({"id": "d3", "a": 1, "b": "x", "c": 4}),
+            json!({"id": "d4", "a": 3, "b": "z", "c": 5}),
+            json!({"id": "m", "a": 1}),
+        ])
+    }
+    
+    #[test]
+    fn compound_indexes_answer_prefixes_of_their_fields() {
+        let documents = compound_documents();
+        let ab = index(&["a", "b"], IndexType::Single, IndexOptions::default(), &documents);
+        
+        assert_eq!(sorted(ab.query(&query(json!({"a": 1, "b": "x"})))), ["d0", "d3"]);
+        assert_eq!(sorted(ab.query(&query(json!({"a": 1})))), ["d0", "d1", "d3", "m"]);
+        assert_eq!(sorted(ab.query(&query(json!({"a": {"$in": [2, 3]}, "b": {"$in": ["x", "z"]}})))), ["d2", "d4"]);
+        assert!(ab.can_use_for_query(&query(json!({"b": "x"}))).is_none());
+        assert_eq!(ab.unusable_reason(&query(json!({"b": "x"}))), "query has no equality or $in on leading field 'a'");
+    }
+    
+    #[test]
+    fn ordered_compound_indexes_range_over_the_first_unbound_field() {
+        let documents = compound_documents();
+        let abc = index(&["a", "b", "c"], IndexType::Ordered, IndexOptions::default(), &documents);
+        
+        assert_eq!(abc.query(&query(json!({"a": 1, "b": "x", "c": {"$gt": 1, "$lte": 9}}))), ["d3"]);
+        assert_eq!(abc.query(&query(json!({"a": 1, "b": {"$gt": "x"}}))), ["d1"]);
+        assert_eq!(sorted(abc.query(&query(json!({"a": 1, "b": {"$lte": "x"}})))), ["d0", "d3"]);
+        assert_eq!(abc.query(&query(json!({"a": {"$gte": 2}}))), ["d2", "d4"]);
+        assert!(abc.query(&query(json!({"a": 1, "b": {"$gt": "x", "$lt": "x"}}))).is_empty());
+    }
+    
+    #[test]
+    fn unique_compound_indexes_reject_duplicate_combinations() {
+        let documents = compound_documents();
+        let mut bc = index(&["b", "c"], IndexType::Unique, IndexOptions::default(), &documents);
+        assert_eq!(bc.query(&query(json!({"b": "x", "c": 3}))), ["d2"]);
+        
+        // Only the combination has to be unique
+        bc.add_document(&document(json!({"id": "n", "b": "x", "c": 2}))).unwrap();
+        let error = bc.add_document(&document(json!({"id": "o", "b": "y", "c": 2}))).unwrap_err();
+        assert!(error.starts_with("Duplicate key"), "{}", error);
+        assert_eq!(bc.query(&query(json!({"b": "y", "c": 2}))), ["d1"]);
+    }
+    
+    fn status_documents() -> Vec<Document> {
+        let statuses = ["open", "pending", "closed", "archived"];
+        let mut docs: Vec<Value> = (0..40)
+            .map(|i| json!({"id": format!("d{}", i), "status": statuses[i % 4], "p": i % 10, "tags": [format!("t{}", i % 5), "all"]}))
+            .collect();
+        docs.push(json!({"id": "none", "p": 1}));
+        documents(&docs)
+    }
+    
+    #[test]
+    fn in_looks_up_every_value() {
+        let documents = status_documents();
+        let status = index(&["status"], IndexType::Single, IndexOptions::default(), &documents);
+        let tags = index(&["tags"], IndexType::Multi, IndexOptions::default(), &documents);
+        
+        assert_eq!(status.query(&query(json!({"status": {"$in": ["open", "pending"]}}))).len(), 20);
+        assert!(status.query(&query(json!({"status": {"$in": []}}))).is_empty());
+        assert_eq!(status.query(&query(json!({"status": {"$in": [null]}}))), ["none"]);
+        // Two lookups of the average bucket: 41 entries over 5 keys, null included
+        assert_eq!(status.estimate_rows(&query(json!({"status": {"$in": ["open", "pending"]}}))), 16.4);
+        
+        // A document holding several of the values is returned once per value,
+        // which callers collapse
+        assert_eq!(tags.query(&query(json!({"tags": {"$in": ["t1", "all"]}}))).len(), 48);
+    }
+    
+    #[test]
+    fn compound_in_looks_up_every_combination() {
+        let documents = status_documents();
+        let status_p = index(&["status", "p"], IndexType::Ordered, IndexOptions::default(), &documents);
+        
+        let ids = status_p.query(&query(json!({"status": {"$in": ["pending", "open"]}, "p": {"$in": [3, 1, 0]}})));
+        assert_eq!(sorted(ids), ["d0", "d1", "d13", "d20", "d21", "d33"]);
+        
+        let ids = status_p.query(&query(json!({"status": {"$in": ["pending", "open"]}, "p": {"$gte": 8}})));
+        assert_eq!(sorted(ids), ["d28", "d29", "d8", "d9"]);
+    }
+    
+    #[test]
+    fn in_must_stay_inside_partial_and_sparse_indexes() {
+        let documents = status_documents();
+        let options = IndexOptions {
+            partial_filter_expression: Some(query(json!({"p": {"$lt": 5}}))),
+            ..IndexOptions::default()
+        };
+        let partial = index(&["p"], IndexType::Single, options, &documents);
+        assert!(partial.can_use_for_query(&query(json!({"p": {"$in": [1, 3]}}))).is_some());
+        assert!(partial.can_use_for_query(&query(json!({"p": {"$in": [1, 7]}}))).is_none());
+        
+        let options = IndexOptions { sparse: true, ..IndexOptions::default() };
+        let sparse = index(&["status"], IndexType::Single, options, &documents);
+        assert!(sparse.can_use_for_query(&query(json!({"status": {"$in": ["open"]}}))).is_some());
+        assert!(sparse.can_use_for_query(&query(json!({"status": {"$in": ["open", null]}}))).is_none());
+    }
+    
+    #[test]
+    fn dot_paths_key_positions_and_fanned_out_values() {
+        let documents = documents(&[
+            json!({"id": "a", "items": [{"price": 5}, {"price": 20}], "o": {"list": [{"v": 1}, {"v": [2, 3]}]}}),
+            json!({"id": "b", "items": [{"price": 12}, {"price": 3}], "o": {"list": [7]}}),
+            json!({"id": "c", "items": {"price": 8}}),
+        ]);
+        let prices = index(&["items.price"], IndexType::Ordered, IndexOptions::default(), &documents);
+        let first = index(&["items.0.price"], IndexType::Single, IndexOptions::default(), &documents);
+        let list = index(&["o.list.v"], IndexType::Single, IndexOptions::default(), &documents);
+        
+        assert_eq!(prices.query(&query(json!({"items.price": 20}))), ["a"]);
+        assert_eq!(sorted(prices.query(&query(json!({"items.price": {"$in": [3, 8]}})))), ["b", "c"]);
+        assert_eq!(sorted(prices.query(&query(json!({"items.price": {"$gt": 10}})))), ["a", "b"]);
+        assert_eq!(first.query(&query(json!({"items.0.price": 12}))), ["b"]);
+        assert!(first.query(&query(json!({"items.0.price": 20}))).is_empty());
+        assert_eq!(list.query(&query(json!({"o.list.v": 3}))), ["a"]);
+    }
+    
+    #[test]
+    fn fanned_out_paths_count_as_arrays_in_compound_keys() {
+        let fields = ["items.price".to_string(), "o.list.v".to_string()];
+        let mut index = Index::new("fan", &fields, IndexType::Single, IndexOptions::default());
+        
+        let parallel = document(json!({"id": "a", "items": [{"price": 5}, {"price": 20}], "o": {"list": [{"v": 1}, {"v": 2}]}}));
+        assert_eq!(index.add_document(&parallel).unwrap_err(), "Cannot index parallel arrays in index 'fan'");
+        
+        let one_array = document(json!({"id": "b", "items": [{"price": 5}, {"price": 20}], "o": {"list": {"v": 1}}}));
+        index.add_document(&one_array).unwrap();
+        assert_eq!(index.query(&query(json!({"items.price": 20, "o.list.v": 1}))), ["b"]);
     }
 }
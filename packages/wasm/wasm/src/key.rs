@@ -0,0 +1,239 @@
+use std::cmp::Ordering;
+use serde_json::{Number, Value};
+
+// Index keys are encoded into bytes whose lexicographic order matches value
+// order: types sort as null < bool < number < string < array < object, and
+// every encoding is self-delimiting so compound keys can simply be concatenated.
+const TAG_END: u8 = 0x00;
+const TAG_NULL: u8 = 0x01;
+const TAG_BOOL: u8 = 0x02;
+const TAG_NUMBER: u8 = 0x03;
+const TAG_STRING: u8 = 0x04;
+const TAG_ARRAY: u8 = 0x05;
+const TAG_OBJECT: u8 = 0x06;
+
+// Marks the start of each object entry so it sorts after the end of the object
+const OBJECT_ENTRY: u8 = 0x01;
+
+pub fn encode_value(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_value(value, &mut out);
+    out
+}
+
+pub fn encode_values<'a>(values: impl IntoIterator<Item = &'a Value>) -> Vec<u8> {
+    let mut out = Vec::new();
+    for value in values {
+        write_value(value, &mut out);
+    }
+    out
+}
+
+// Bounds of the key space holding values of the same type as `key`: every
+// encoding of that type is >= the first bound and < the second
+pub fn type_bounds(key: &[u8]) -> (Vec<u8>, Vec<u8>) {
+    let tag = key.first().copied().unwrap_or(TAG_NULL);
+    (vec![tag], vec![tag + 1])
+}
+
+fn write_value(value: &Value, out: &mut Vec<u8>) {
+    match value {
+        Value::Null => out.push(TAG_NULL),
+        Value::Bool(b) => {
+            out.push(TAG_BOOL);
+            out.push(*b as u8);
+        },
+        Value::Number(n) => {
+            out.push(TAG_NUMBER);
+            write_number(n, out);
+        },
+        Value::String(s) => {
+            out.push(TAG_STRING);
+            write_bytes(s.as_bytes(), out);
+        },
+        Value::Array(arr) => {
+            out.push(TAG_ARRAY);
+            for item in arr {
+                write_value(item, out);
+            }
+            out.push(TAG_END);
+        },
+        Value::Object(obj) => {
+            out.push(TAG_OBJECT);
+            for (key, item) in obj {
+                out.push(OBJECT_ENTRY);
+                write_bytes(key.as_bytes(), out);
+                write_value(item, out);
+            }
+            out.push(TAG_END);
+        },
+    }
+}
+
+// Order two values the way their encodings order: by type, then numbers by
+// value, strings and object keys by their UTF-8 bytes, arrays element by
+// element and objects entry by entry, a prefix before anything longer
+pub fn compare(a: &Value, b: &Value) -> Ordering {
+    match (a, b) {
+        (Value::Bool(a), Value::Bool(b)) => a.cmp(b),
+        (Value::Number(a), Value::Number(b)) => {
+            let ((a, a_offset), (b, b_offset)) = (number_parts(a), number_parts(b));
+            a.partial_cmp(&b).unwrap_or(Ordering::Equal).then(a_offset.cmp(&b_offset))
+        },
+        (Value::String(a), Value::String(b)) => a.as_bytes().cmp(b.as_bytes()),
+        (Value::Array(a), Value::Array(b)) => a.iter()
+            .zip(b)
+            .map(|(a, b)| compare(a, b))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Value::Object(a), Value::Object(b)) => a.iter()
+            .zip(b)
+            .map(|((a_key, a_value), (b_key, b_value))| a_key.as_bytes().cmp(b_key.as_bytes()).then_with(|| compare(a_value, b_value)))
+            .find(|ordering| ordering.is_ne())
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        _ => type_tag(a).cmp(&type_tag(b)),
+    }
+}
+
+// Whether two values are equal under `compare`, so 1 and 1.0 are
+pub fn equal(a: &Value, b: &Value) -> bool {
+    compare(a, b) == Ordering::Equal
+}
+
+// Whether two values are of the same type, the only ones range operators
+// compare
+pub fn same_type(a: &Value, b: &Value) -> bool {
+    type_tag(a) == type_tag(b)
+}
+
+fn type_tag(value: &Value) -> u8 {
+    match value {
+        Value::Null => TAG_NULL,
+        Value::Bool(_) => TAG_BOOL,
+        Value::Number(_) => TAG_NUMBER,
+        Value::String(_) => TAG_STRING,
+        Value::Array(_) => TAG_ARRAY,
+        Value::Object(_) => TAG_OBJECT,
+    }
+}
+
+// A number as the nearest f64 and how far an integer lies above it. Integers
+// beyond 2^53 round to an f64 they share with their neighbours, and the
+// offset, at most 2^10 either way for 64-bit integers, tells them apart.
+fn number_parts(n: &Number) -> (f64, i16) {
+    let exact = match (n.as_i64(), n.as_u64()) {
+        (Some(i), _) => i as i128,
+        (None, Some(u)) => u as i128,
+        (None, None) => return (n.as_f64().unwrap_or(0.0), 0),
+    };
+    let rounded = exact as f64;
+    (rounded, (exact - rounded as i128) as i16)
+}
+
+// Flip the sign bit of positive numbers and every bit of negative ones so the
+// big-endian bytes compare like the numbers do, then break ties between
+// integers sharing an f64 by their offset from it
+fn write_number(n: &Number, out: &mut Vec<u8>) {
+    let (n, offset) = number_parts(n);
+    // Treat -0.0 and 0.0 as the same key
+    let n = if n == 0.0 { 0.0 } else { n };
+    let bits = n.to_bits();
+    let ordered = if bits >> 63 == 1 { !bits } else { bits | (1 << 63) };
+    out.extend_from_slice(&ordered.to_be_bytes());
+    out.extend_from_slice(&((offset as u16) ^ 0x8000).to_be_bytes());
+}
+
+// Escape 0x00 as 0x00 0xFF and terminate with 0x00 0x00, so a string sorts
+// before any longer string it is a prefix of
+fn write_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    for &b in bytes {
+        out.push(b);
+        if b == 0x00 {
+            out.push(0xFF);
+        }
+    }
+    out.push(0x00);
+    out.push(0x00);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    // Values in ascending order
+    fn ordered_values() -> Vec<Value> {
+        vec![
+            json!(null), json!(false), json!(true),
+            json!(-1e300), json!(i64::MIN), json!(-9007199254740993i64), json!(-9007199254740992i64), json!(-2), json!(-0.5),
+            json!(0), json!(1), json!(1.5), json!(9007199254740992u64), json!(9007199254740993u64), json!(i64::MAX),
+            json!(9223372036854775808u64), json!(u64::MAX), json!(1e300),
+            json!(""), json!("a"), json!("a\u{0}"), json!("a\u{1}"), json!("ab"), json!("a|b"), json!("b"), json!("é"),
+            json!([]), json!([null]), json!([1]), json!([1, 2]), json!([1, "a"]), json!([2]), json!([[1]]),
+            json!({}), json!({"a": 1}), json!({"a": 1, "b": 0}), json!({"a": 2}), json!({"a": [1]}), json!({"b": 0}),
+        ]
+    }
+    
+    #[test]
+    fn encodings_sort_like_values() {
+        for pair in ordered_values().windows(2) {
+            assert!(encode_value(&pair[0]) < encode_value(&pair[1]), "{} < {}", pair[0], pair[1]);
+        }
+    }
+    
+    #[test]
+    fn encodings_keep_types_apart() {
+        assert_ne!(encode_value(&json!(1)), encode_value(&json!("1")));
+        assert_ne!(encode_value(&json!(null)), encode_value(&json!("null")));
+        assert_ne!(encode_value(&json!(false)), encode_value(&json!(0)));
+        assert_eq!(encode_value(&json!(-0.0)), encode_value(&json!(0)));
+        assert_eq!(encode_value(&json!(1)), encode_value(&json!(1.0)));
+    }
+    
+    #[test]
+    fn integers_beyond_f64_precision_stay_distinct() {
+        assert_ne!(encode_value(&json!(9007199254740993u64)), encode_value(&json!(9007199254740992u64)));
+        assert_ne!(encode_value(&json!(u64::MAX)), encode_value(&json!(u64::MAX - 1)));
+        assert!(!equal(&json!(9007199254740993u64), &json!(9007199254740992u64)));
+        
+        // Integers an f64 holds exactly still equal that f64
+        assert_eq!(encode_value(&json!(9007199254740992u64)), encode_value(&json!(9007199254740992.0)));
+        assert!(equal(&json!(-9007199254740992i64), &json!(-9007199254740992.0)));
+    }
+    
+    #[test]
+    fn compound_keys_are_self_delimiting() {
+        let split = encode_values([&json!("a"), &json!("b")]);
+        let joined = encode_values([&json!("a|b")]);
+        assert_ne!(split, joined);
+        
+        // A key sorts by its first value before its second
+        assert!(encode_values([&json!("a"), &json!("z")]) < encode_values([&json!("a\u{0}"), &json!("a")]));
+        assert!(encode_values([&json!(1), &json!("z")]) < encode_values([&json!(2), &json!(null)]));
+    }
+    
+    #[test]
+    fn type_bounds_cover_one_type() {
+        let (lower, upper) = type_bounds(&encode_value(&json!("m")));
+        for value in ordered_values() {
+            let key = encode_value(&value);
+            assert_eq!(key >= lower && key < upper, value.is_string(), "{}", value);
+        }
+    }
+    
+    #[test]
+    fn compare_agrees_with_encodings() {
+        let values = ordered_values();
+        for a in &values {
+            for b in &values {
+                assert_eq!(compare(a, b), encode_value(a).cmp(&encode_value(b)), "{} vs {}", a, b);
+            }
+        }
+        
+        assert!(equal(&json!(1), &json!(1.0)));
+        assert!(equal(&json!(-0.0), &json!(0)));
+        assert!(!equal(&json!(1), &json!("1")));
+        assert!(same_type(&json!(1), &json!(2.5)));
+        assert!(!same_type(&json!(null), &json!(false)));
+    }
+}
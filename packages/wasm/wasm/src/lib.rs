@@ -2,81 +2,131 @@ mod utils;
 mod query;
 mod document;
 mod index;
+mod key;
+mod text;
+mod vector;
+mod geo;
+mod date;
+mod expr;
+mod planner;
+mod find;
+mod pattern;
+mod aggregate;
 
 use wasm_bindgen::prelude::*;
-use serde::{Serialize, Deserialize};
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::{Rc, Weak};
 use query::Query;
 use document::Document;
-use index::{Index, IndexType};
+use index::{Index, IndexOptions, IndexStats, IndexType};
+use find::{Direction, FindOptions, Page, Projection, SortSpec};
+use aggregate::{LookupSpec, PipelineStage};
+use expr::Fields;
+use planner::{Explain, ExecutionStats, ExplainOptions, PlanCandidate, RejectedPlan, Stage, Verbosity};
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global allocator.
 #[cfg(feature = "wee_alloc")]
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+// Handle to a collection. Clones share the same documents, so the handle a
+// `Database` returns sees writes made through any other handle.
 #[wasm_bindgen]
-extern "C" {
-    #[wasm_bindgen(js_namespace = console)]
-    fn log(s: &str);
+#[derive(Clone)]
+pub struct Collection {
+    state: Rc<RefCell<CollectionState>>,
 }
 
-#[wasm_bindgen]
-pub struct Collection {
+struct CollectionState {
     name: String,
     documents: Vec<Document>,
+    // Document id -> position in `documents`
+    slots: HashMap<String, usize>,
     indexes: HashMap<String, Index>,
+    // Collections of the Database this one belongs to, which $lookup joins with
+    database: Weak<Collections>,
 }
 
+type Collections = RefCell<HashMap<String, Collection>>;
+
 #[wasm_bindgen]
 impl Collection {
     #[wasm_bindgen(constructor)]
     pub fn new(name: &str) -> Collection {
         utils::set_panic_hook();
         Collection {
-            name: name.to_string(),
-            documents: Vec::new(),
-            indexes: HashMap::new(),
+            state: Rc::new(RefCell::new(CollectionState {
+                name: name.to_string(),
+                documents: Vec::new(),
+                slots: HashMap::new(),
+                indexes: HashMap::new(),
+                database: Weak::new(),
+            })),
         }
     }
 
     pub fn name(&self) -> String {
-        self.name.clone()
+        self.state.borrow().name.clone()
     }
 
     pub fn count(&self) -> usize {
-        self.documents.len()
+        self.state.borrow().documents.len()
     }
 
     pub fn insert(&mut self, doc_str: &str) -> Result<String, JsValue> {
+        let state = &mut *self.state.borrow_mut();
         let mut doc: Document = serde_json::from_str(doc_str)
             .map_err(|e| JsValue::from_str(&format!("Failed to parse document: {}", e)))?;
         
         // Generate ID if not present
         if !doc.has_id() {
+            if doc.get("id").is_some_and(|id| !id.is_null()) {
+                return Err(JsValue::from_str("Document ID must be a string"));
+            }
             doc.generate_id();
         }
         
         let id = doc.id().to_string();
         
         // Check if document with this ID already exists
-        if self.documents.iter().any(|d| d.id() == id) {
+        if state.slots.contains_key(&id) {
             return Err(JsValue::from_str(&format!("Document with ID {} already exists", id)));
         }
         
         // Update indexes
-        for (name, index) in &mut self.indexes {
-            index.add_document(&doc)
-                .map_err(|e| JsValue::from_str(&format!("Failed to update index {}: {}", name, e)))?;
-        }
+        state.index_document(&doc).map_err(|e| JsValue::from_str(&e))?;
         
         // Add document
-        self.documents.push(doc);
+        state.slots.insert(id.clone(), state.documents.len());
+        state.documents.push(doc);
         
         Ok(id)
     }
 
-    pub fn find(&self, query_str: &str) -> Result<String, JsValue> {
+    pub fn find(&self, query_str: &str, options_str: Option<String>) -> Result<String, JsValue> {
+        let state = &*self.state.borrow();
+        let query: Query = if query_str.is_empty() {
+            Query::empty()
+        } else {
+            serde_json::from_str(query_str)
+                .map_err(|e| JsValue::from_str(&format!("Failed to parse query: {}", e)))?
+        };
+        
+        let options = FindOptions::parse(options_str.as_deref()).map_err(|e| JsValue::from_str(&e))?;
+        
+        let (slots, scores) = state.find_slots(&query, &options).map_err(|e| JsValue::from_str(&e))?;
+        
+        state.serialize_results(&slots, &scores, options.projection.as_ref())
+            .map_err(|e| JsValue::from_str(&format!("Failed to serialize results: {}", e)))
+    }
+
+    // One page of results as {"documents": [...], "next": token}, where
+    // passing `next` back as the `after` option returns the following page
+    // and `next` is null on the last page
+    pub fn find_page(&self, query_str: &str, options_str: Option<String>) -> Result<String, JsValue> {
+        let state = &*self.state.borrow();
         let query: Query = if query_str.is_empty() {
             Query::empty()
         } else {
@@ -84,80 +134,96 @@ impl Collection {
                 .map_err(|e| JsValue::from_str(&format!("Failed to parse query: {}", e)))?
         };
         
-        // Use index if possible
-        let results = if let Some((index_name, field)) = self.find_usable_index(&query) {
-            log(&format!("Using index {} for field {}", index_name, field));
-            let index = &self.indexes[index_name];
-            index.query(&query)
+        let options = FindOptions::parse(options_str.as_deref()).map_err(|e| JsValue::from_str(&e))?;
+        
+        let page = state.find_page(&query, &options).map_err(|e| JsValue::from_str(&e))?;
+        
+        let documents = state.serialize_results(&page.slots, &page.scores, options.projection.as_ref())
+            .map_err(|e| JsValue::from_str(&format!("Failed to serialize results: {}", e)))?;
+        let next = serde_json::to_string(&page.next)
+            .map_err(|e| JsValue::from_str(&format!("Failed to serialize results: {}", e)))?;
+        
+        Ok(format!("{{\"documents\":{},\"next\":{}}}", documents, next))
+    }
+
+    pub fn find_cursor(&self, query_str: &str, options_str: Option<String>) -> Result<Cursor, JsValue> {
+        let state = &*self.state.borrow();
+        let query: Query = if query_str.is_empty() {
+            Query::empty()
         } else {
-            // Full scan
-            self.documents.iter()
-                .filter(|doc| query.matches(doc))
-                .cloned()
-                .collect::<Vec<Document>>()
+            serde_json::from_str(query_str)
+                .map_err(|e| JsValue::from_str(&format!("Failed to parse query: {}", e)))?
         };
         
-        serde_json::to_string(&results)
-            .map_err(|e| JsValue::from_str(&format!("Failed to serialize results: {}", e)))
+        let options = FindOptions::parse(options_str.as_deref()).map_err(|e| JsValue::from_str(&e))?;
+        
+        let (slots, scores) = state.find_slots(&query, &options).map_err(|e| JsValue::from_str(&e))?;
+        
+        Ok(Cursor::new(self.state.clone(), state, &slots, &scores, options.projection))
     }
 
-    pub fn find_one(&self, query_str: &str) -> Result<String, JsValue> {
+    pub fn find_one(&self, query_str: &str, options_str: Option<String>) -> Result<String, JsValue> {
+        let state = &*self.state.borrow();
         let query: Query = serde_json::from_str(query_str)
             .map_err(|e| JsValue::from_str(&format!("Failed to parse query: {}", e)))?;
         
-        // Use index if possible
-        let result = if let Some((index_name, field)) = self.find_usable_index(&query) {
-            log(&format!("Using index {} for field {}", index_name, field));
-            let index = &self.indexes[index_name];
-            index.query_one(&query)
-        } else {
-            // Full scan
-            self.documents.iter()
-                .find(|doc| query.matches(doc))
-                .cloned()
-        };
+        let mut options = FindOptions::parse(options_str.as_deref()).map_err(|e| JsValue::from_str(&e))?;
+        options.limit = 1;
         
-        match result {
-            Some(doc) => serde_json::to_string(&doc)
+        let (slots, scores) = state.find_slots(&query, &options).map_err(|e| JsValue::from_str(&e))?;
+        
+        match slots.first() {
+            Some(&slot) => state.result_value(slot, &scores, options.projection.as_ref())
+                .and_then(|doc| serde_json::to_string(&doc).map_err(|e| e.to_string()))
                 .map_err(|e| JsValue::from_str(&format!("Failed to serialize document: {}", e))),
             None => Ok("null".to_string())
         }
     }
 
+    pub fn aggregate(&self, pipeline_str: &str) -> Result<String, JsValue> {
+        let state = &*self.state.borrow();
+        let pipeline: Vec<PipelineStage> = serde_json::from_str(pipeline_str)
+            .map_err(|e| JsValue::from_str(&format!("Failed to parse pipeline: {}", e)))?;
+        aggregate::check_text_stages(&pipeline, true)
+            .map_err(|e| JsValue::from_str(&format!("Failed to parse pipeline: {}", e)))?;
+        
+        let results = state.aggregate(&pipeline).map_err(|e| JsValue::from_str(&e))?;
+        
+        serde_json::to_string(&results)
+            .map_err(|e| JsValue::from_str(&format!("Failed to serialize results: {}", e)))
+    }
+
     pub fn update(&mut self, query_str: &str, update_str: &str) -> Result<usize, JsValue> {
+        let state = &mut *self.state.borrow_mut();
         let query: Query = serde_json::from_str(query_str)
             .map_err(|e| JsValue::from_str(&format!("Failed to parse query: {}", e)))?;
         
         let update: serde_json::Value = serde_json::from_str(update_str)
             .map_err(|e| JsValue::from_str(&format!("Failed to parse update: {}", e)))?;
+        let pulls = Document::pull_filters(&update)
+            .map_err(|e| JsValue::from_str(&format!("Failed to parse update: {}", e)))?;
         
         let mut count = 0;
         
         // Find matching documents
-        let matching_docs: Vec<usize> = self.documents.iter()
-            .enumerate()
-            .filter(|(_, doc)| query.matches(doc))
-            .map(|(i, _)| i)
-            .collect();
+        let matching_docs = state.matching_slots(&query).map_err(|e| JsValue::from_str(&e))?;
         
         // Update documents
         for i in matching_docs {
-            // Remove from indexes
-            for (name, index) in &mut self.indexes {
-                index.remove_document(&self.documents[i])
-                    .map_err(|e| JsValue::from_str(&format!("Failed to update index {}: {}", name, e)))?;
-            }
-            
-            // Apply update
-            self.documents[i].apply_update(&update)
+            // Apply update to a copy so a failed update leaves the stored document untouched
+            let mut updated = state.documents[i].clone();
+            updated.apply_update(&update, &pulls)
                 .map_err(|e| JsValue::from_str(&format!("Failed to apply update: {}", e)))?;
             
-            // Add back to indexes
-            for (name, index) in &mut self.indexes {
-                index.add_document(&self.documents[i])
-                    .map_err(|e| JsValue::from_str(&format!("Failed to update index {}: {}", name, e)))?;
+            // Swap the old version out of the indexes and the new one in
+            state.unindex_document(i).map_err(|e| JsValue::from_str(&e))?;
+            if let Err(e) = state.index_document(&updated) {
+                state.index_document(&state.documents[i].clone())
+                    .map_err(|e| JsValue::from_str(&e))?;
+                return Err(JsValue::from_str(&e));
             }
             
+            state.documents[i] = updated;
             count += 1;
         }
         
@@ -165,111 +231,862 @@ impl Collection {
     }
 
     pub fn delete(&mut self, query_str: &str) -> Result<usize, JsValue> {
+        let state = &mut *self.state.borrow_mut();
         let query: Query = serde_json::from_str(query_str)
             .map_err(|e| JsValue::from_str(&format!("Failed to parse query: {}", e)))?;
         
-        let mut count = 0;
-        
         // Find matching documents
-        let matching_docs: Vec<usize> = self.documents.iter()
+        let matching_docs = state.matching_slots(&query).map_err(|e| JsValue::from_str(&e))?;
+        
+        state.remove_slots(&matching_docs).map_err(|e| JsValue::from_str(&e))
+    }
+
+    // Delete the documents whose TTL indexes have expired them at `now`, in
+    // epoch milliseconds
+    pub fn expire(&mut self, now: f64) -> Result<usize, JsValue> {
+        let state = &mut *self.state.borrow_mut();
+        
+        let expired: Vec<usize> = state.documents.iter()
             .enumerate()
-            .filter(|(_, doc)| query.matches(doc))
+            .filter(|(_, doc)| state.indexes.values().any(|index| index.is_expired(doc, now)))
             .map(|(i, _)| i)
             .collect();
         
-        // Delete documents in reverse order to avoid index issues
-        for i in matching_docs.into_iter().rev() {
-            // Remove from indexes
-            for (name, index) in &mut self.indexes {
-                index.remove_document(&self.documents[i])
-                    .map_err(|e| JsValue::from_str(&format!("Failed to update index {}: {}", name, e)))?;
-            }
-            
-            // Remove document
-            self.documents.remove(i);
-            count += 1;
-        }
-        
-        Ok(count)
+        state.remove_slots(&expired).map_err(|e| JsValue::from_str(&e))
     }
 
-    pub fn create_index(&mut self, name: &str, fields: &str, index_type_str: &str) -> Result<(), JsValue> {
+    pub fn create_index(&mut self, name: &str, fields: &str, index_type_str: &str, options_str: Option<String>) -> Result<(), JsValue> {
+        let state = &mut *self.state.borrow_mut();
         let fields: Vec<String> = serde_json::from_str(fields)
             .map_err(|e| JsValue::from_str(&format!("Failed to parse fields: {}", e)))?;
         
+        let options: IndexOptions = match options_str.as_deref() {
+            Some(options_str) if !options_str.is_empty() => serde_json::from_str(options_str)
+                .map_err(|e| JsValue::from_str(&format!("Failed to parse index options: {}", e)))?,
+            _ => IndexOptions::default(),
+        };
+        options.validate()
+            .map_err(|e| JsValue::from_str(&format!("Invalid index options: {}", e)))?;
+        
         let index_type = match index_type_str {
             "single" => IndexType::Single,
             "unique" => IndexType::Unique,
             "multi" => IndexType::Multi,
+            "ordered" => IndexType::Ordered,
+            "text" => IndexType::Text,
+            "vector" => IndexType::Vector,
+            "2d" | "2dsphere" => IndexType::Geo,
             _ => return Err(JsValue::from_str(&format!("Invalid index type: {}", index_type_str)))
         };
         
-        let mut index = Index::new(name, &fields, index_type);
+        if matches!(index_type, IndexType::Vector | IndexType::Geo) && fields.len() != 1 {
+            return Err(JsValue::from_str(&format!("{} indexes must cover exactly one field", index_type_str)));
+        }
+        
+        if options.expire_after_seconds.is_some() && fields.len() != 1 {
+            return Err(JsValue::from_str("TTL indexes must cover exactly one field"));
+        }
+        
+        if index_type == IndexType::Text && state.indexes.values().any(Index::is_text) {
+            return Err(JsValue::from_str("Collection already has a text index"));
+        }
+        
+        let mut index = Index::new(name, &fields, index_type, options);
         
         // Add existing documents to index
-        for doc in &self.documents {
+        for doc in &state.documents {
             index.add_document(doc)
                 .map_err(|e| JsValue::from_str(&format!("Failed to add document to index: {}", e)))?;
         }
         
-        self.indexes.insert(name.to_string(), index);
+        state.indexes.insert(name.to_string(), index);
         
         Ok(())
     }
 
+    // The k documents whose vector field is closest to `vector_str`, among those
+    // matching the optional filter query. Results are ordered closest first and
+    // carry their similarity in a `$vectorScore` field.
+    pub fn vector_search(&self, field: &str, vector_str: &str, k: usize, filter_str: Option<String>) -> Result<String, JsValue> {
+        let state = &*self.state.borrow();
+        let vector: serde_json::Value = serde_json::from_str(vector_str)
+            .map_err(|e| JsValue::from_str(&format!("Failed to parse vector: {}", e)))?;
+        let vector = vector::to_vector(&vector)
+            .ok_or_else(|| JsValue::from_str("Vector must be a non-empty array of numbers"))?;
+        
+        let filter: Query = match filter_str.as_deref() {
+            Some(filter_str) if !filter_str.is_empty() => serde_json::from_str(filter_str)
+                .map_err(|e| JsValue::from_str(&format!("Failed to parse query: {}", e)))?,
+            _ => Query::empty(),
+        };
+        
+        let results = state.vector_matches(field, &vector, k, &filter)
+            .map_err(|e| JsValue::from_str(&e))?
+            .into_iter()
+            .map(|(slot, score)| {
+                let mut doc = serde_json::to_value(&state.documents[slot])?;
+                doc["$vectorScore"] = score.into();
+                Ok(doc)
+            })
+            .collect::<Result<Vec<serde_json::Value>, serde_json::Error>>()
+            .map_err(|e| JsValue::from_str(&format!("Failed to serialize results: {}", e)))?;
+        
+        serde_json::to_string(&results)
+            .map_err(|e| JsValue::from_str(&format!("Failed to serialize results: {}", e)))
+    }
+
     pub fn drop_index(&mut self, name: &str) -> Result<bool, JsValue> {
-        Ok(self.indexes.remove(name).is_some())
+        let state = &mut *self.state.borrow_mut();
+        Ok(state.indexes.remove(name).is_some())
     }
 
     pub fn get_indexes(&self) -> Result<String, JsValue> {
-        let index_names: Vec<String> = self.indexes.keys().cloned().collect();
+        let state = &*self.state.borrow();
+        let index_names: Vec<String> = state.indexes.keys().cloned().collect();
         serde_json::to_string(&index_names)
             .map_err(|e| JsValue::from_str(&format!("Failed to serialize indexes: {}", e)))
     }
 
+    // Describe how `find` answers the query: the chosen plan, the plans passed
+    // over and why, and unless the verbosity is "queryPlanner" the work done
+    // running it
+    pub fn explain(&self, query_str: &str, options_str: Option<String>) -> Result<String, JsValue> {
+        let state = &*self.state.borrow();
+        let query: Query = if query_str.is_empty() {
+            Query::empty()
+        } else {
+            serde_json::from_str(query_str)
+                .map_err(|e| JsValue::from_str(&format!("Failed to parse query: {}", e)))?
+        };
+        
+        let options: ExplainOptions = match options_str {
+            Some(options_str) => serde_json::from_str(&options_str)
+                .map_err(|e| JsValue::from_str(&format!("Failed to parse explain options: {}", e)))?,
+            None => ExplainOptions::default(),
+        };
+        
+        state.check_query(&query).map_err(|e| JsValue::from_str(&e))?;
+        
+        let candidates = state.plan_candidates(&query);
+        
+        // Indexes no plan could use, in name order
+        let mut unusable: Vec<(&String, &Index)> = state.indexes.iter()
+            .filter(|(name, _)| !candidates.iter().any(|plan| plan.uses(name)))
+            .collect();
+        unusable.sort_by_key(|(name, _)| name.as_str());
+        
+        let mut candidates = candidates.into_iter();
+        let winning_plan = match candidates.next() {
+            Some(plan) => plan,
+            None => return Err(JsValue::from_str("No plan can answer the query")),
+        };
+        
+        let mut rejected_plans: Vec<RejectedPlan> = candidates
+            .map(|plan| RejectedPlan {
+                reason: format!("estimated cost {:.2} exceeds {:.2} of the winning plan", plan.cost, winning_plan.cost),
+                plan: Some(plan),
+                index: None,
+            })
+            .collect();
+        
+        rejected_plans.extend(unusable.into_iter().map(|(name, index)| RejectedPlan {
+            plan: None,
+            index: Some(name),
+            reason: if query.has_text() && index.can_use_for_query(&query).is_some() {
+                "$text queries can only use the text index".to_string()
+            } else {
+                index.unusable_reason(&query)
+            },
+        }));
+        
+        let execution_stats = match options.verbosity {
+            Verbosity::QueryPlanner => None,
+            Verbosity::ExecutionStats => {
+                let mut stats = ExecutionStats::default();
+                let start = utils::now();
+                state.execute(&query, &mut stats).map_err(|e| JsValue::from_str(&e))?;
+                stats.execution_time_millis = utils::now() - start;
+                Some(stats)
+            },
+        };
+        
+        let explain = Explain {
+            winning_plan,
+            rejected_plans,
+            execution_stats,
+        };
+        
+        serde_json::to_string(&explain)
+            .map_err(|e| JsValue::from_str(&format!("Failed to serialize explain: {}", e)))
+    }
+
+    // Size statistics of each index, keyed by index name
+    pub fn get_index_stats(&self) -> Result<String, JsValue> {
+        let state = &*self.state.borrow();
+        let stats: HashMap<&str, IndexStats> = state.indexes.iter()
+            .map(|(name, index)| (name.as_str(), index.stats()))
+            .collect();
+        serde_json::to_string(&stats)
+            .map_err(|e| JsValue::from_str(&format!("Failed to serialize index stats: {}", e)))
+    }
+
     pub fn to_json(&self) -> Result<String, JsValue> {
-        serde_json::to_string(&self.documents)
+        let state = &*self.state.borrow();
+        serde_json::to_string(&state.documents)
             .map_err(|e| JsValue::from_str(&format!("Failed to serialize collection: {}", e)))
     }
 
     pub fn from_json(&mut self, json: &str) -> Result<(), JsValue> {
+        let state = &mut *self.state.borrow_mut();
         let docs: Vec<Document> = serde_json::from_str(json)
             .map_err(|e| JsValue::from_str(&format!("Failed to parse JSON: {}", e)))?;
         
+        state.load(docs).map_err(|e| JsValue::from_str(&e))
+    }
+}
+
+// Results of a `find_cursor` query, returned in batches. Each document is
+// read when its batch is fetched, so updates made in the meantime are seen
+// and documents deleted in the meantime are skipped.
+#[wasm_bindgen]
+pub struct Cursor {
+    state: Rc<RefCell<CollectionState>>,
+    // Ids of the results in order, with their $text relevance
+    results: Vec<(String, Option<f64>)>,
+    // Position of the next result to return
+    position: usize,
+    projection: Option<Projection>,
+    closed: bool,
+}
+
+impl Cursor {
+    fn new(shared: Rc<RefCell<CollectionState>>, state: &CollectionState, slots: &[usize], scores: &HashMap<usize, f64>, projection: Option<Projection>) -> Cursor {
+        let results = slots.iter()
+            .map(|slot| (state.documents[*slot].id().to_string(), scores.get(slot).copied()))
+            .collect();
+        
+        Cursor {
+            state: shared,
+            results,
+            position: 0,
+            projection,
+            closed: false,
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Cursor {
+    // JSON array of up to `size` more results
+    pub fn next_batch(&mut self, size: usize) -> Result<String, JsValue> {
+        if self.closed {
+            return Err(JsValue::from_str("Cursor is closed"));
+        }
+        
+        let state = &*self.state.borrow();
+        let mut slots = Vec::new();
+        let mut scores = HashMap::new();
+        
+        while slots.len() < size && self.position < self.results.len() {
+            let (id, score) = &self.results[self.position];
+            self.position += 1;
+            
+            // Skip documents deleted since the cursor was opened
+            if let Some(&slot) = state.slots.get(id) {
+                if let Some(score) = score {
+                    scores.insert(slot, *score);
+                }
+                slots.push(slot);
+            }
+        }
+        
+        state.serialize_results(&slots, &scores, self.projection.as_ref())
+            .map_err(|e| JsValue::from_str(&format!("Failed to serialize results: {}", e)))
+    }
+
+    pub fn has_next(&self) -> bool {
+        if self.closed {
+            return false;
+        }
+        
+        let state = self.state.borrow();
+        self.results[self.position..].iter().any(|(id, _)| state.slots.contains_key(id))
+    }
+
+    // Release the results. Fetching more from a closed cursor is an error.
+    pub fn close(&mut self) {
+        self.closed = true;
+        self.results = Vec::new();
+        self.position = 0;
+    }
+}
+
+impl CollectionState {
+    // Every way of answering the query, cheapest first
+    fn plan_candidates<'a>(&'a self, query: &'a Query) -> Vec<PlanCandidate<'a>> {
+        // $text can only be answered by the text index
+        if query.has_text() {
+            return self.index_plans(query);
+        }
+        
+        let mut candidates = self.conjunction_plans(query);
+        if let Some(plan) = self.intersection_plan(&candidates) {
+            candidates.push(plan);
+        }
+        candidates.push(PlanCandidate::full_scan(self.documents.len()));
+        
+        planner::rank(&mut candidates);
+        candidates
+    }
+    
+    // Scans of every index usable for the query, in index name order
+    fn index_plans<'a>(&'a self, query: &'a Query) -> Vec<PlanCandidate<'a>> {
+        let mut plans: Vec<PlanCandidate> = self.indexes.iter()
+            .filter(|(_, index)| index.is_text() || !query.has_text())
+            .filter(|(_, index)| index.can_use_for_query(query).is_some())
+            .map(|(name, index)| PlanCandidate::index(name, index.fields(), Cow::Borrowed(query), &index.stats(), index.estimate_rows(query)))
+            .collect();
+        plans.sort_by_key(|plan| plan.index);
+        plans
+    }
+    
+    // Plans returning a superset of the query's matches from indexes alone:
+    // index scans over the query or any of its $and branches, and the union
+    // of index plans over its $or branches
+    fn conjunction_plans<'a>(&'a self, query: &'a Query) -> Vec<PlanCandidate<'a>> {
+        let mut plans = self.index_plans(query);
+        
+        for branch in query.and_branches() {
+            plans.extend(self.conjunction_plans(branch));
+        }
+        
+        if let Some(plan) = self.union_plan(query) {
+            plans.push(plan);
+        }
+        
+        plans
+    }
+    
+    // Union of the cheapest index plan of each $or branch, if they all have one
+    fn union_plan<'a>(&'a self, query: &'a Query) -> Option<PlanCandidate<'a>> {
+        let inputs = query.or_branches()?
+            .iter()
+            .map(|branch| {
+                let mut plans = self.conjunction_plans(branch);
+                if let Some(plan) = self.intersection_plan(&plans) {
+                    plans.push(plan);
+                }
+                planner::rank(&mut plans);
+                plans.into_iter().next()
+            })
+            .collect::<Option<Vec<PlanCandidate>>>()?;
+        
+        Some(PlanCandidate::union(self.documents.len(), inputs))
+    }
+    
+    // Intersect the most selective plans for as long as adding one lowers the
+    // cost, skipping indexes over fields an earlier input covers
+    fn intersection_plan<'a>(&self, plans: &[PlanCandidate<'a>]) -> Option<PlanCandidate<'a>> {
+        let mut by_rows: Vec<&PlanCandidate> = plans.iter().collect();
+        by_rows.sort_by(|a, b| a.estimated_rows.total_cmp(&b.estimated_rows));
+        
+        let mut inputs: Vec<PlanCandidate> = Vec::new();
+        let mut cost = f64::INFINITY;
+        
+        for plan in by_rows {
+            if inputs.iter().any(|input| input.fields.iter().any(|field| plan.fields.contains(field))) {
+                continue;
+            }
+            
+            let mut trial = inputs.clone();
+            trial.push(plan.clone());
+            let trial_cost = match trial.len() {
+                1 => plan.cost,
+                _ => PlanCandidate::intersection(self.documents.len(), trial.clone()).cost,
+            };
+            
+            if trial_cost < cost {
+                inputs = trial;
+                cost = trial_cost;
+            }
+        }
+        
+        if inputs.len() < 2 {
+            return None;
+        }
+        
+        Some(PlanCandidate::intersection(self.documents.len(), inputs))
+    }
+    
+    // Ids of the documents a plan other than a collection scan returns
+    fn plan_ids(&self, plan: &PlanCandidate, stats: &mut ExecutionStats) -> HashSet<&str> {
+        match plan.stage {
+            Stage::IndexScan => {
+                let index = plan.index.and_then(|name| self.indexes.get(name));
+                let ids = match (index, &plan.query) {
+                    (Some(index), Some(query)) => index.query(query),
+                    _ => Vec::new(),
+                };
+                stats.keys_examined += ids.len();
+                ids.into_iter().collect()
+            },
+            Stage::Intersection => {
+                let mut sets: Vec<HashSet<&str>> = plan.inputs.iter()
+                    .map(|input| self.plan_ids(input, stats))
+                    .collect();
+                sets.sort_by_key(HashSet::len);
+                
+                let mut sets = sets.into_iter();
+                let mut ids = sets.next().unwrap_or_default();
+                for set in sets {
+                    ids.retain(|id| set.contains(id));
+                }
+                ids
+            },
+            Stage::Union => plan.inputs.iter()
+                .flat_map(|input| self.plan_ids(input, stats))
+                .collect(),
+            Stage::CollectionScan => unreachable!(),
+        }
+    }
+
+    // Reject queries no plan can answer
+    fn check_query(&self, query: &Query) -> Result<(), String> {
+        if query.has_text() && self.plan_candidates(query).is_empty() {
+            return Err("$text query requires a text index".to_string());
+        }
+        
+        Ok(())
+    }
+    
+    // Positions of the documents matching the query, in insertion order
+    fn matching_slots(&self, query: &Query) -> Result<Vec<usize>, String> {
+        self.execute(query, &mut ExecutionStats::default())
+    }
+    
+    // Run the cheapest plan for the query, counting the work done in `stats`
+    fn execute(&self, query: &Query, stats: &mut ExecutionStats) -> Result<Vec<usize>, String> {
+        self.check_query(query)?;
+        
+        let plan = self.plan_candidates(query).into_iter().next();
+        
+        let slots: Vec<usize> = match plan {
+            Some(plan) if plan.stage != Stage::CollectionScan => {
+                let mut slots: Vec<usize> = self.plan_ids(&plan, stats)
+                    .into_iter()
+                    .filter_map(|id| self.slots.get(id).copied())
+                    .collect();
+                slots.sort_unstable();
+                
+                stats.docs_examined += slots.len();
+                slots.retain(|&slot| query.matches(&self.documents[slot]));
+                slots
+            },
+            _ => {
+                // Full scan
+                stats.docs_examined += self.documents.len();
+                self.documents.iter()
+                    .enumerate()
+                    .filter(|(_, doc)| query.matches(*doc))
+                    .map(|(i, _)| i)
+                    .collect()
+            }
+        };
+        
+        stats.n_returned = slots.len();
+        Ok(slots)
+    }
+    
+    // Matching positions in result order with skip and limit applied, and the
+    // relevance of each $text match. $near results come nearest first and
+    // $text results most relevant first unless a sort is given.
+    fn find_slots(&self, query: &Query, options: &FindOptions) -> Result<(Vec<usize>, HashMap<usize, f64>), String> {
+        if options.after.is_some() {
+            return self.find_page(query, options).map(|page| (page.slots, page.scores));
+        }
+        
+        Self::check_find_options(query, options)?;
+        
+        let mut slots = self.matching_slots(query)?;
+        let mut scores = HashMap::new();
+        
+        match &options.sort {
+            Some(sort) => {
+                if query.has_text() {
+                    scores = self.text_scores(query);
+                }
+                find::sort_slots(&mut slots, &self.documents, sort, &scores, options.needed());
+            },
+            None => {
+                if query.has_text() {
+                    scores = self.rank_text_matches(query, &mut slots);
+                }
+                if query.has_near() {
+                    self.rank_near_matches(query, &mut slots);
+                }
+            },
+        }
+        
+        Ok((options.page(slots), scores))
+    }
+
+    // A page of matching positions ordered by the sort then document id,
+    // starting after `options.after`, with the token for the next page
+    fn find_page(&self, query: &Query, options: &FindOptions) -> Result<Page, String> {
+        Self::check_find_options(query, options)?;
+        
+        let mut slots = self.matching_slots(query)?;
+        let scores = if query.has_text() {
+            self.text_scores(query)
+        } else {
+            HashMap::new()
+        };
+        
+        let next = find::page_slots(&mut slots, &self.documents, options, &scores)?;
+        Ok(Page { slots, scores, next })
+    }
+
+    fn check_find_options(query: &Query, options: &FindOptions) -> Result<(), String> {
+        let wants_score = options.sort.as_ref().is_some_and(SortSpec::uses_text_score)
+            || options.projection.as_ref().is_some_and(Projection::uses_text_score);
+        if wants_score && !query.has_text() {
+            return Err("$meta textScore requires a $text query".to_string());
+        }
+        
+        Ok(())
+    }
+    
+    // Run a pipeline. A leading $match and a $sort right after it work on
+    // the stored documents, so they can use indexes like `find` does.
+    fn aggregate(&self, pipeline: &[PipelineStage]) -> Result<Vec<serde_json::Value>, String> {
+        let mut stages = pipeline;
+        
+        let mut slots = match stages.first() {
+            Some(PipelineStage::Match(query)) => {
+                stages = &stages[1..];
+                self.matching_slots(query)?
+            },
+            _ => (0..self.documents.len()).collect(),
+        };
+        
+        if let Some(PipelineStage::Sort(sort)) = stages.first() {
+            stages = &stages[1..];
+            let needed = aggregate::leading_limit(stages);
+            match self.index_sorted_slots(&slots, sort, needed) {
+                Some(sorted) => slots = sorted,
+                None => find::sort_slots(&mut slots, &self.documents, sort, &HashMap::new(), needed),
+            }
+        }
+        
+        aggregate::run(stages, self.document_values(&slots)?, &|spec, docs| self.lookup(spec, docs))
+    }
+
+    // Store in each document the documents of a sibling collection a $lookup
+    // joins it with. Equality joins are planned as queries on that
+    // collection, so they use its indexes.
+    fn lookup(&self, spec: &LookupSpec, docs: Vec<serde_json::Value>) -> Result<Vec<serde_json::Value>, String> {
+        let collections = self.database.upgrade()
+            .ok_or("$lookup is only available on collections of a Database")?;
+        let collection = collections.borrow().get(&spec.from).cloned();
+        
+        // A collection that does not exist joins nothing, like an empty one
+        let foreign = collection.as_ref().map(|collection| collection.state.borrow());
+        let join = |spec: &LookupSpec, docs| match &foreign {
+            Some(foreign) => foreign.lookup(spec, docs),
+            None => Ok(docs),
+        };
+        
+        // The same for every document unless it depends on the document
+        let shared = match (&foreign, &spec.pipeline) {
+            (Some(foreign), Some(pipeline)) if spec.is_uncorrelated() => {
+                let all: Vec<usize> = (0..foreign.documents.len()).collect();
+                Some(aggregate::run(pipeline, foreign.document_values(&all)?, &join)?)
+            },
+            _ => None,
+        };
+        
+        let mut joined = Vec::with_capacity(docs.len());
+        
+        for mut doc in docs {
+            if !doc.is_object() {
+                joined.push(doc);
+                continue;
+            }
+            
+            let matches = match (&foreign, &shared) {
+                (_, Some(shared)) => shared.clone(),
+                (None, None) => Vec::new(),
+                (Some(foreign), None) => {
+                    let slots = match (&spec.local_field, &spec.foreign_field) {
+                        (Some(local_field), Some(foreign_field)) => {
+                            // Arrays join on any of their elements; other values,
+                            // objects included, are matched with $eq
+                            let condition = match doc.field(local_field) {
+                                Some(serde_json::Value::Array(items)) => serde_json::json!({ "$in": items }),
+                                Some(value) => serde_json::json!({ "$eq": value }),
+                                None => serde_json::json!({ "$eq": null }),
+                            };
+                            let query: Query = serde_json::from_value(serde_json::json!({ foreign_field.as_str(): condition }))
+                                .map_err(|e| format!("Failed to build $lookup query: {}", e))?;
+                            foreign.matching_slots(&query)?
+                        },
+                        _ => (0..foreign.documents.len()).collect(),
+                    };
+                    
+                    let foreign_docs = foreign.document_values(&slots)?;
+                    match &spec.pipeline {
+                        Some(_) => aggregate::run(&spec.bound_pipeline(&doc)?, foreign_docs, &join)?,
+                        None => foreign_docs,
+                    }
+                },
+            };
+            
+            if let serde_json::Value::Object(obj) = &mut doc {
+                find::set_path(obj, &spec.output, serde_json::Value::Array(matches));
+            }
+            joined.push(doc);
+        }
+        
+        Ok(joined)
+    }
+
+    fn document_values(&self, slots: &[usize]) -> Result<Vec<serde_json::Value>, String> {
+        slots.iter()
+            .map(|&slot| serde_json::to_value(&self.documents[slot]))
+            .collect::<Result<Vec<serde_json::Value>, serde_json::Error>>()
+            .map_err(|e| format!("Failed to serialize document: {}", e))
+    }
+
+    // The first `needed` of the slots in sort order, read off an index whose
+    // key order is the sort's, if there is one. Ties keep insertion order.
+    fn index_sorted_slots(&self, slots: &[usize], sort: &SortSpec, needed: Option<usize>) -> Option<Vec<usize>> {
+        let (paths, direction) = sort.paths()?;
+        
+        let mut names: Vec<&String> = self.indexes.keys().collect();
+        names.sort();
+        let buckets = names.into_iter()
+            .map(|name| &self.indexes[name])
+            .filter(|index| index.fields().iter().map(String::as_str).eq(paths.iter().copied()))
+            .find_map(|index| index.sorted_buckets(direction == Direction::Descending, self.documents.len()))?;
+        
+        let mut wanted = vec![false; self.documents.len()];
+        for &slot in slots {
+            wanted[slot] = true;
+        }
+        
+        let limit = needed.map_or(slots.len(), |needed| needed.min(slots.len()));
+        let mut sorted = Vec::with_capacity(limit);
+        
+        for bucket in buckets {
+            if sorted.len() >= limit {
+                break;
+            }
+            
+            let mut bucket_slots: Vec<usize> = bucket.into_iter()
+                .filter_map(|id| self.slots.get(id).copied())
+                .filter(|&slot| wanted[slot])
+                .collect();
+            bucket_slots.sort_unstable();
+            sorted.extend(bucket_slots);
+        }
+        
+        sorted.truncate(limit);
+        Some(sorted)
+    }
+
+    // JSON array of the documents at the slots
+    fn serialize_results(&self, slots: &[usize], scores: &HashMap<usize, f64>, projection: Option<&Projection>) -> Result<String, String> {
+        // Documents serialize as they are unless they need reshaping
+        if projection.is_none() && scores.is_empty() {
+            let results: Vec<&Document> = slots.iter()
+                .map(|&slot| &self.documents[slot])
+                .collect();
+            return serde_json::to_string(&results).map_err(|e| e.to_string());
+        }
+        
+        let results = slots.iter()
+            .map(|&slot| self.result_value(slot, scores, projection))
+            .collect::<Result<Vec<serde_json::Value>, String>>()?;
+        serde_json::to_string(&results).map_err(|e| e.to_string())
+    }
+    
+    // A result document, projected and with its $textScore for $text matches
+    fn result_value(&self, slot: usize, scores: &HashMap<usize, f64>, projection: Option<&Projection>) -> Result<serde_json::Value, String> {
+        let mut doc = serde_json::to_value(&self.documents[slot]).map_err(|e| e.to_string())?;
+        let score = scores.get(&slot).copied();
+        
+        if let Some(projection) = projection {
+            doc = projection.apply(doc, score)?;
+        }
+        if let Some(score) = score {
+            doc["$textScore"] = score.into();
+        }
+        
+        Ok(doc)
+    }
+    
+    // Order $near matches by ascending distance, ties in insertion order
+    fn rank_near_matches(&self, query: &Query, slots: &mut [usize]) {
+        let distance = |slot: usize| query.near_distance(&self.documents[slot]).unwrap_or(f64::INFINITY);
+        slots.sort_by(|&a, &b| distance(a).total_cmp(&distance(b)).then(a.cmp(&b)));
+    }
+
+    // Positions and scores of the k nearest documents matching the filter
+    fn vector_matches(&self, field: &str, vector: &[f64], k: usize, filter: &Query) -> Result<Vec<(usize, f64)>, String> {
+        let index = self.indexes.values()
+            .find_map(|index| index.vector_index(field))
+            .ok_or_else(|| format!("No vector index on field '{}'", field))?;
+        
+        // Only pay for evaluating the filter when there is one
+        let allowed: Option<HashSet<usize>> = if filter.is_empty() {
+            None
+        } else {
+            Some(self.matching_slots(filter)?.into_iter().collect())
+        };
+        
+        let nearest = index.search(vector, k, |id| {
+            match (&allowed, self.slots.get(id)) {
+                (_, None) => false,
+                (None, Some(_)) => true,
+                (Some(allowed), Some(slot)) => allowed.contains(slot),
+            }
+        })?;
+        
+        Ok(nearest.into_iter()
+            .filter_map(|(id, score)| Some((*self.slots.get(id)?, score)))
+            .collect())
+    }
+
+    // Order $text matches by descending relevance, ties in insertion order
+    fn rank_text_matches(&self, query: &Query, slots: &mut [usize]) -> HashMap<usize, f64> {
+        let scores = self.text_scores(query);
+        slots.sort_by(|a, b| scores[b].total_cmp(&scores[a]).then(a.cmp(b)));
+        scores
+    }
+    
+    // BM25 relevance of each document matching the query's $text search
+    fn text_scores(&self, query: &Query) -> HashMap<usize, f64> {
+        self.indexes.values()
+            .filter(|index| index.is_text())
+            .flat_map(|index| index.text_scores(query))
+            .filter_map(|(id, score)| Some((*self.slots.get(id)?, score)))
+            .collect()
+    }
+
+    // Add a document to every index, undoing the partial work if one of them rejects it
+    fn index_document(&mut self, doc: &Document) -> Result<(), String> {
+        let mut added: Vec<String> = Vec::new();
+        let mut failure = None;
+        
+        for (name, index) in &mut self.indexes {
+            if let Err(e) = index.add_document(doc) {
+                failure = Some(format!("Failed to update index {}: {}", name, e));
+                break;
+            }
+            added.push(name.clone());
+        }
+        
+        match failure {
+            Some(e) => {
+                for name in added {
+                    if let Some(index) = self.indexes.get_mut(&name) {
+                        let _ = index.remove_document(doc);
+                    }
+                }
+                Err(e)
+            },
+            None => Ok(()),
+        }
+    }
+
+    fn unindex_document(&mut self, slot: usize) -> Result<(), String> {
+        let doc = &self.documents[slot];
+        
+        for (name, index) in &mut self.indexes {
+            index.remove_document(doc)
+                .map_err(|e| format!("Failed to update index {}: {}", name, e))?;
+        }
+        
+        Ok(())
+    }
+
+    // Replace the documents with `docs`, which must carry distinct string
+    // ids since generating one calls into JS
+    fn load(&mut self, docs: Vec<Document>) -> Result<(), String> {
+        let mut ids = HashSet::new();
+        for doc in &docs {
+            if !doc.has_id() {
+                return Err("Document ID must be a string".to_string());
+            }
+            if !ids.insert(doc.id()) {
+                return Err(format!("Document with ID {} already exists", doc.id()));
+            }
+        }
+        
         // Clear existing documents and indexes
         self.documents.clear();
-        for (_, index) in &mut self.indexes {
+        self.slots.clear();
+        for index in self.indexes.values_mut() {
             index.clear();
         }
         
         // Add documents
         for doc in docs {
-            self.documents.push(doc.clone());
-            
-            // Update indexes
             for (name, index) in &mut self.indexes {
                 index.add_document(&doc)
-                    .map_err(|e| JsValue::from_str(&format!("Failed to update index {}: {}", name, e)))?;
+                    .map_err(|e| format!("Failed to update index {}: {}", name, e))?;
             }
+            
+            self.slots.insert(doc.id().to_string(), self.documents.len());
+            self.documents.push(doc);
         }
         
         Ok(())
     }
 
-    // Find an index that can be used for this query
-    fn find_usable_index(&self, query: &Query) -> Option<(&str, &str)> {
-        for (name, index) in &self.indexes {
-            if let Some(field) = index.can_use_for_query(query) {
-                return Some((name, field));
-            }
+    // Delete the documents at the given ascending positions
+    fn remove_slots(&mut self, slots: &[usize]) -> Result<usize, String> {
+        if slots.is_empty() {
+            return Ok(0);
+        }
+        
+        for &i in slots {
+            self.unindex_document(i)?;
         }
-        None
+        
+        // Compact the survivors in one pass, so sweeps that expire most of
+        // a collection stay linear
+        let mut removed = slots.iter().peekable();
+        let mut position = 0;
+        self.documents.retain(|_| {
+            let keep = removed.next_if_eq(&&position).is_none();
+            position += 1;
+            keep
+        });
+        
+        self.rebuild_slots();
+        
+        Ok(slots.len())
+    }
+
+    fn rebuild_slots(&mut self) {
+        self.slots = self.documents.iter()
+            .enumerate()
+            .map(|(i, doc)| (doc.id().to_string(), i))
+            .collect();
     }
 }
 
 #[wasm_bindgen]
 pub struct Database {
-    collections: HashMap<String, Collection>,
+    // Shared with the collections, which look up their siblings through it
+    collections: Rc<Collections>,
+}
+
+impl Default for Database {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[wasm_bindgen]
@@ -278,38 +1095,84 @@ impl Database {
     pub fn new() -> Database {
         utils::set_panic_hook();
         Database {
-            collections: HashMap::new(),
+            collections: Rc::new(RefCell::new(HashMap::new())),
         }
     }
 
     pub fn collection(&mut self, name: &str) -> Collection {
-        if let Some(collection) = self.collections.get(name) {
+        if let Some(collection) = self.collections.borrow().get(name) {
             return collection.clone();
         }
         
         let collection = Collection::new(name);
-        self.collections.insert(name.to_string(), collection.clone());
+        collection.state.borrow_mut().database = Rc::downgrade(&self.collections);
+        self.collections.borrow_mut().insert(name.to_string(), collection.clone());
         collection
     }
 
     pub fn has_collection(&self, name: &str) -> bool {
-        self.collections.contains_key(name)
+        self.collections.borrow().contains_key(name)
     }
 
     pub fn drop_collection(&mut self, name: &str) -> bool {
-        self.collections.remove(name).is_some()
+        self.collections.borrow_mut().remove(name).is_some()
     }
 
     pub fn get_collections(&self) -> Result<String, JsValue> {
-        let collection_names: Vec<String> = self.collections.keys().cloned().collect();
+        let collection_names: Vec<String> = self.collections.borrow().keys().cloned().collect();
         serde_json::to_string(&collection_names)
             .map_err(|e| JsValue::from_str(&format!("Failed to serialize collections: {}", e)))
     }
 
+    // Run `Collection::expire` on every collection, returning the total number
+    // of documents removed
+    pub fn sweep_expired(&mut self, now: f64) -> Result<usize, JsValue> {
+        let mut count = 0;
+        
+        for collection in self.collections.borrow_mut().values_mut() {
+            count += collection.expire(now)?;
+        }
+        
+        Ok(count)
+    }
+
+    // `find` on a collection with its results joined by a $lookup spec, or
+    // an array of them applied in order
+    pub fn find_with_lookup(&self, collection: &str, query_str: &str, lookup_str: &str) -> Result<String, JsValue> {
+        let collection = self.collections.borrow().get(collection).cloned()
+            .ok_or_else(|| JsValue::from_str(&format!("Collection {} does not exist", collection)))?;
+        
+        let query: Query = if query_str.is_empty() {
+            Query::empty()
+        } else {
+            serde_json::from_str(query_str)
+                .map_err(|e| JsValue::from_str(&format!("Failed to parse query: {}", e)))?
+        };
+        
+        let lookups: Vec<LookupSpec> = if lookup_str.trim_start().starts_with('[') {
+            serde_json::from_str(lookup_str)
+        } else {
+            serde_json::from_str(lookup_str).map(|lookup| vec![lookup])
+        }
+        .map_err(|e| JsValue::from_str(&format!("Failed to parse lookup: {}", e)))?;
+        
+        let mut pipeline = vec![PipelineStage::Match(query)];
+        for lookup in lookups {
+            lookup.validate().map_err(|e| JsValue::from_str(&e))?;
+            pipeline.push(PipelineStage::Lookup(lookup));
+        }
+        
+        let state = &*collection.state.borrow();
+        let results = state.aggregate(&pipeline).map_err(|e| JsValue::from_str(&e))?;
+        
+        serde_json::to_string(&results)
+            .map_err(|e| JsValue::from_str(&format!("Failed to serialize results: {}", e)))
+    }
+
     pub fn to_json(&self) -> Result<String, JsValue> {
         let mut data = HashMap::new();
         
-        for (name, collection) in &self.collections {
+        for (name, collection) in self.collections.borrow().iter() {
             let docs_json = collection.to_json()?;
             data.insert(name.clone(), serde_json::from_str::<serde_json::Value>(&docs_json)
                 .map_err(|e| JsValue::from_str(&format!("Failed to parse collection JSON: {}", e)))?);
@@ -323,18 +1186,824 @@ impl Database {
         let data: HashMap<String, Vec<Document>> = serde_json::from_str(json)
             .map_err(|e| JsValue::from_str(&format!("Failed to parse JSON: {}", e)))?;
         
-        // Clear existing collections
-        self.collections.clear();
+        // Drop collections missing from the data
+        self.collections.borrow_mut().retain(|name, _| data.contains_key(name));
         
-        // Add collections
+        // Refill collections in place, so handles already given out stay valid
         for (name, docs) in data {
-            let mut collection = Collection::new(&name);
+            let mut collection = self.collection(&name);
             let docs_json = serde_json::to_string(&docs)
                 .map_err(|e| JsValue::from_str(&format!("Failed to serialize documents: {}", e)))?;
             collection.from_json(&docs_json)?;
-            self.collections.insert(name, collection);
         }
         
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    // Documents need explicit ids: generating one calls into JS
+    fn collection(docs: &[Value]) -> Collection {
+        let mut collection = Collection::new("test");
+        for doc in docs {
+            collection.insert(&doc.to_string()).unwrap();
+        }
+        collection
+    }
+
+    // Fixtures from here to `query` are shared with the other modules' tests
+    pub(crate) fn document(doc: Value) -> Document {
+        serde_json::from_value(doc).unwrap()
+    }
+
+    pub(crate) fn documents(docs: &[Value]) -> Vec<Document> {
+        docs.iter().cloned().map(document).collect()
+    }
+
+    pub(crate) fn query(query: Value) -> Query {
+        serde_json::from_value(query).unwrap()
+    }
+
+    // Ids of the documents a query matches, in insertion order
+    fn ids(collection: &Collection, filter: Value) -> Vec<String> {
+        let state = collection.state.borrow();
+        state.matching_slots(&query(filter)).unwrap()
+            .into_iter()
+            .map(|slot| state.documents[slot].id().to_string())
+            .collect()
+    }
+
+    // Index the cheapest plan for a query looks up, if it uses a single one
+    fn planned_index(collection: &Collection, filter: Value) -> Option<String> {
+        let state = collection.state.borrow();
+        let query = query(filter);
+        let plan = state.plan_candidates(&query).into_iter().next()?;
+        plan.index.map(str::to_string)
+    }
+
+    fn explain(collection: &Collection, filter: Value, options: Option<Value>) -> Value {
+        let explain = collection.explain(&filter.to_string(), options.map(|options| options.to_string())).unwrap();
+        serde_json::from_str(&explain).unwrap()
+    }
+
+    // Ids of the documents a query matches when tested one by one, without
+    // any index
+    fn scanned(collection: &Collection, filter: Value) -> Vec<String> {
+        let query = query(filter);
+        collection.state.borrow().documents.iter()
+            .filter(|doc| query.matches(*doc))
+            .map(|doc| doc.id().to_string())
+            .collect()
+    }
+
+    fn find(collection: &Collection, filter: Value, options: Value) -> Vec<Value> {
+        let results = collection.find(&filter.to_string(), Some(options.to_string())).unwrap();
+        serde_json::from_str(&results).unwrap()
+    }
+
+    // Each result's id with the ids of the documents joined into `field`
+    fn joined(results: &str, field: &str) -> Vec<String> {
+        let results: Vec<Value> = serde_json::from_str(results).unwrap();
+        results.iter()
+            .map(|doc| {
+                let ids: Vec<&str> = doc[field].as_array().unwrap().iter().map(|joined| joined["id"].as_str().unwrap()).collect();
+                format!("{}:{}", doc["id"].as_str().unwrap(), ids.join(","))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn index_hits_resolve_to_stored_documents() {
+        let mut people = collection(&[
+            json!({"id": "a", "name": "x", "age": 1}),
+            json!({"id": "b", "name": "y", "age": 2}),
+            json!({"id": "c", "name": "x", "age": 3}),
+        ]);
+        people.create_index("name", r#"["name"]"#, "single", None).unwrap();
+        
+        assert_eq!(ids(&people, json!({"name": "x"})), ["a", "c"]);
+        assert_eq!(ids(&people, json!({"name": "x", "age": 3})), ["c"]);
+        assert_eq!(find(&people, json!({"name": "y"}), json!({})), [json!({"id": "b", "name": "y", "age": 2})]);
+        
+        assert_eq!(people.update(r#"{"id": "a"}"#, r#"{"$set": {"name": "y"}}"#).unwrap(), 1);
+        assert_eq!(ids(&people, json!({"name": "y"})), ["a", "b"]);
+        
+        // Removing documents moves the later ones down, and index hits must
+        // still find them
+        assert_eq!(people.delete(r#"{"id": "a"}"#).unwrap(), 1);
+        assert_eq!(ids(&people, json!({"name": "x"})), ["c"]);
+        assert_eq!(find(&people, json!({"name": "x"}), json!({})), [json!({"id": "c", "name": "x", "age": 3})]);
+        assert_eq!(ids(&people, json!({"id": "c"})), ["c"]);
+        assert_eq!(people.count(), 2);
+    }
+
+    #[test]
+    fn loaded_documents_need_distinct_string_ids() {
+        let people = collection(&[json!({"id": "a", "name": "x"})]);
+        let mut state = people.state.borrow_mut();
+        
+        for docs in [json!([{"name": "x"}]), json!([{"id": 5}]), json!([{"id": "b"}, {"id": "b"}])] {
+            let docs: Vec<Document> = serde_json::from_value(docs).unwrap();
+            assert!(state.load(docs).is_err());
+        }
+        
+        // A rejected load leaves the collection as it was
+        assert_eq!(state.documents.len(), 1);
+        assert_eq!(state.slots.get("a"), Some(&0));
+        
+        state.load(documents(&[json!({"id": "b"}), json!({"id": "c"})])).unwrap();
+        assert_eq!(state.slots.get("c"), Some(&1));
+    }
+
+    #[test]
+    fn text_searches_rank_by_relevance() {
+        let mut posts = collection(&[
+            json!({"id": "a", "title": "Indexing in Rust", "body": "rust rust rust indexes"}),
+            json!({"id": "b", "title": "Cooking", "body": "the best pasta is indexed nowhere"}),
+            json!({"id": "c", "title": "Rust notes", "body": "ownership and borrowing"}),
+        ]);
+        assert!(posts.state.borrow().matching_slots(&query(json!({"$text": {"$search": "rust"}}))).is_err());
+        
+        posts.create_index("text", r#"["title", "body"]"#, "text", Some(r#"{"stemming": true}"#.to_string())).unwrap();
+        
+        let results = find(&posts, json!({"$text": {"$search": "rust"}}), json!({}));
+        let ranked: Vec<&str> = results.iter().map(|doc| doc["id"].as_str().unwrap()).collect();
+        assert_eq!(ranked, ["a", "c"]);
+        assert!(results[0]["$textScore"].as_f64() > results[1]["$textScore"].as_f64());
+        
+        assert_eq!(ids(&posts, json!({"$text": {"$search": "index -rust"}})), ["b"]);
+        assert_eq!(ids(&posts, json!({"$text": {"$search": "rust"}, "title": "Rust notes"})), ["c"]);
+        assert!(ids(&posts, json!({"$text": {"$search": "the"}})).is_empty());
+        
+        posts.delete(r#"{"id": "a"}"#).unwrap();
+        assert_eq!(ids(&posts, json!({"$text": {"$search": "rust"}})), ["c"]);
+    }
+
+    #[test]
+    fn vector_search_returns_filtered_nearest_documents() {
+        let mut points = collection(&[
+            json!({"id": "a", "embedding": [1, 0], "kind": "x"}),
+            json!({"id": "b", "embedding": [0, 1], "kind": "y"}),
+            json!({"id": "c", "embedding": [1, 1], "kind": "x"}),
+            json!({"id": "d", "kind": "x"}),
+        ]);
+        points.create_index("embedding", r#"["embedding"]"#, "vector", Some(r#"{"metric": "cosine"}"#.to_string())).unwrap();
+        
+        let search = |points: &Collection, filter: Option<&str>| -> Vec<Value> {
+            let results = points.vector_search("embedding", "[1, 0.1]", 2, filter.map(str::to_string)).unwrap();
+            serde_json::from_str(&results).unwrap()
+        };
+        let results = search(&points, None);
+        assert_eq!(results.iter().map(|doc| doc["id"].as_str().unwrap()).collect::<Vec<_>>(), ["a", "c"]);
+        assert!(results[0]["$vectorScore"].as_f64().unwrap() > 0.99);
+        
+        let results = search(&points, Some(r#"{"kind": "y"}"#));
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0]["id"], "b");
+        
+        assert!(points.state.borrow().vector_matches("embedding", &[1.0], 2, &Query::empty()).is_err());
+        assert!(points.state.borrow().vector_matches("kind", &[1.0, 0.0], 2, &Query::empty()).is_err());
+        
+        points.delete(r#"{"id": "a"}"#).unwrap();
+        let results = search(&points, None);
+        assert_eq!(results.iter().map(|doc| doc["id"].as_str().unwrap()).collect::<Vec<_>>(), ["c", "b"]);
+    }
+
+    #[test]
+    fn geo_indexes_agree_with_scans() {
+        let mut places = collection(&[
+            json!({"id": "paris", "loc": {"type": "Point", "coordinates": [2.35, 48.85]}}),
+            json!({"id": "london", "loc": {"type": "Point", "coordinates": [-0.12, 51.5]}}),
+            json!({"id": "nyc", "loc": {"type": "Point", "coordinates": [-74.0, 40.7]}}),
+            json!({"id": "legacy", "loc": [2.0, 48.0]}),
+            json!({"id": "area", "loc": {"type": "Polygon", "coordinates": [[[0, 45], [5, 45], [5, 50], [0, 50], [0, 45]]]}}),
+            json!({"id": "none", "name": "x"}),
+        ]);
+        let filters = [
+            json!({"loc": {"$geoWithin": {"$box": [[-1, 40], [3, 52]]}}}),
+            json!({"loc": {"$geoWithin": {"$centerSphere": [[-74, 40], 0.02]}}}),
+            json!({"loc": {"$geoIntersects": {"$geometry": {"type": "Point", "coordinates": [1, 46]}}}}),
+            json!({"loc": {"$near": [2, 48], "$maxDistance": 1}}),
+        ];
+        let scanned: Vec<Vec<String>> = filters.iter().map(|filter| ids(&places, filter.clone())).collect();
+        assert_eq!(scanned, [vec!["paris", "london", "legacy"], vec!["nyc"], vec!["area"], vec!["paris", "legacy"]]);
+        
+        places.create_index("loc", r#"["loc"]"#, "2dsphere", None).unwrap();
+        let indexed: Vec<Vec<String>> = filters.iter().map(|filter| ids(&places, filter.clone())).collect();
+        assert_eq!(indexed, scanned);
+        
+        // $near results come back closest first
+        let near = json!({"loc": {"$near": {"$geometry": {"type": "Point", "coordinates": [2.3, 48.8]}, "$maxDistance": 400000}}});
+        let results = find(&places, near, json!({}));
+        let ranked: Vec<&str> = results.iter().map(|doc| doc["id"].as_str().unwrap()).collect();
+        assert_eq!(ranked, ["paris", "legacy", "london"]);
+    }
+
+    #[test]
+    fn geo_indexes_reach_through_arrays_of_subdocuments() {
+        // Enough documents without stops for the index to beat a scan
+        let mut docs = vec![
+            json!({"id": "europe", "stops": [{"loc": [2.35, 48.85]}, {"loc": [-0.12, 51.5]}]}),
+            json!({"id": "atlantic", "stops": [{"loc": [-74.0, 40.7]}, {"loc": [-0.1, 51.4]}]}),
+            json!({"id": "america", "stops": [{"loc": [-74.0, 40.7]}, {"name": "x"}]}),
+        ];
+        docs.extend((0..20).map(|i| json!({"id": format!("none{}", i), "stops": []})));
+        let mut trips = collection(&docs);
+        let filters = [
+            json!({"stops.loc": {"$geoWithin": {"$box": [[-1, 48], [3, 52]]}}}),
+            json!({"stops.loc": {"$geoWithin": {"$center": [[-74, 40], 1]}}}),
+            json!({"stops.loc": {"$near": [0, 51], "$maxDistance": 3}}),
+        ];
+        let expected = [vec!["europe", "atlantic"], vec!["atlantic", "america"], vec!["europe", "atlantic"]];
+        for (filter, expected) in filters.iter().zip(&expected) {
+            assert_eq!(ids(&trips, filter.clone()), *expected);
+            assert_eq!(scanned(&trips, filter.clone()), *expected);
+        }
+
+        trips.create_index("stops", r#"["stops.loc"]"#, "2d", None).unwrap();
+        for (filter, expected) in filters.iter().zip(&expected) {
+            assert_eq!(planned_index(&trips, filter.clone()).as_deref(), Some("stops"));
+            assert_eq!(ids(&trips, filter.clone()), *expected);
+        }
+
+        // $near ranks each document by its closest stop
+        let results = find(&trips, json!({"stops.loc": {"$near": [-0.1, 51.4]}}), json!({}));
+        let ranked: Vec<&str> = results.iter().map(|doc| doc["id"].as_str().unwrap()).collect();
+        assert_eq!(ranked, ["atlantic", "europe", "america"]);
+    }
+
+    #[test]
+    fn expired_documents_are_swept_from_every_collection() {
+        let mut database = Database::new();
+        let mut sessions = database.collection("sessions");
+        for doc in [json!({"id": "a", "at": 1000}), json!({"id": "b", "at": 5000}), json!({"id": "c"})] {
+            sessions.insert(&doc.to_string()).unwrap();
+        }
+        sessions.create_index("at", r#"["at"]"#, "single", Some(r#"{"expireAfterSeconds": 10}"#.to_string())).unwrap();
+        sessions.create_index("id", r#"["id"]"#, "unique", None).unwrap();
+        
+        assert_eq!(database.sweep_expired(10_999.0).unwrap(), 0);
+        assert_eq!(database.sweep_expired(11_000.0).unwrap(), 1);
+        assert_eq!(sessions.expire(20_000.0).unwrap(), 1);
+        assert_eq!(ids(&sessions, json!({})), ["c"]);
+        
+        // The documents are gone from the other indexes too
+        assert!(ids(&sessions, json!({"id": "a"})).is_empty());
+        sessions.insert(r#"{"id": "a"}"#).unwrap();
+        assert_eq!(sessions.count(), 2);
+    }
+
+    #[test]
+    fn partial_ttl_indexes_only_expire_documents_they_hold() {
+        let mut events = collection(&[
+            json!({"id": "a", "kind": "session", "at": 0}),
+            json!({"id": "b", "kind": "user", "at": 0}),
+            json!({"id": "c", "at": 0}),
+        ]);
+        events.create_index("at", r#"["at"]"#, "ordered", Some(r#"{"expireAfterSeconds": 1, "partialFilterExpression": {"kind": "session"}}"#.to_string())).unwrap();
+        
+        assert_eq!(events.expire(1e12).unwrap(), 1);
+        assert_eq!(ids(&events, json!({})), ["b", "c"]);
+    }
+
+    #[test]
+    fn plans_follow_index_statistics() {
+        let docs: Vec<Value> = (0..200)
+            .map(|i| json!({"id": format!("d{}", i), "a": i % 100, "b": i % 3, "t": format!("w{} common", i)}))
+            .collect();
+        let mut numbers = collection(&docs);
+        numbers.create_index("a", r#"["a"]"#, "single", None).unwrap();
+        numbers.create_index("b", r#"["b"]"#, "single", None).unwrap();
+        numbers.create_index("ab", r#"["a", "b"]"#, "single", None).unwrap();
+        numbers.create_index("text", r#"["t"]"#, "text", None).unwrap();
+        
+        let stats = numbers.state.borrow().indexes["a"].stats();
+        assert_eq!((stats.distinct_keys, stats.entries, stats.avg_bucket_size), (100, 200, 2.0));
+        
+        assert_eq!(planned_index(&numbers, json!({"a": 5})).as_deref(), Some("a"));
+        assert_eq!(planned_index(&numbers, json!({"a": 5, "b": 1})).as_deref(), Some("ab"));
+        // A third of the collection is still cheaper to read through the index
+        assert_eq!(planned_index(&numbers, json!({"b": 1})).as_deref(), Some("b"));
+        assert_eq!(planned_index(&numbers, json!({"a": {"$ne": 1}})), None);
+        assert_eq!(planned_index(&numbers, json!({"$text": {"$search": "w5"}, "a": 5})).as_deref(), Some("text"));
+        
+        numbers.delete(r#"{"a": {"$gte": 50}}"#).unwrap();
+        let stats = numbers.state.borrow().indexes["a"].stats();
+        assert_eq!((stats.distinct_keys, stats.entries), (50, 100));
+        assert_eq!(ids(&numbers, json!({"$text": {"$search": "w5"}, "a": 5})), ["d5"]);
+    }
+
+    #[test]
+    fn explain_reports_every_plan_and_the_work_done() {
+        let docs: Vec<Value> = (0..50)
+            .map(|i| json!({"id": format!("d{}", i), "a": i % 10, "tags": [format!("x{}", i % 5), "y"], "t": format!("w{}", i)}))
+            .collect();
+        let mut items = collection(&docs);
+        items.create_index("a", r#"["a"]"#, "single", None).unwrap();
+        items.create_index("tags", r#"["tags"]"#, "multi", None).unwrap();
+        items.create_index("sparse", r#"["z"]"#, "single", Some(r#"{"sparse": true}"#.to_string())).unwrap();
+        items.create_index("text", r#"["t"]"#, "text", None).unwrap();
+        items.create_index("partial", r#"["a"]"#, "ordered", Some(r#"{"partialFilterExpression": {"a": {"$gt": 3}}}"#.to_string())).unwrap();
+        
+        let report = explain(&items, json!({"a": 3}), None);
+        assert_eq!(report["winningPlan"]["stage"], "IXSCAN");
+        assert_eq!(report["winningPlan"]["index"], "a");
+        assert_eq!(report["executionStats"]["keysExamined"], 5);
+        assert_eq!(report["executionStats"]["docsExamined"], 5);
+        assert_eq!(report["executionStats"]["nReturned"], 5);
+        
+        let rejected = report["rejectedPlans"].as_array().unwrap();
+        assert_eq!(rejected[0]["stage"], "COLLSCAN");
+        assert!(rejected[0]["reason"].as_str().unwrap().starts_with("estimated cost"));
+        let reasons: Vec<(&str, &str)> = rejected[1..].iter()
+            .map(|plan| (plan["index"].as_str().unwrap(), plan["reason"].as_str().unwrap()))
+            .collect();
+        assert_eq!(reasons, [
+            ("partial", "query does not imply the partial filter expression"),
+            ("sparse", "query has no equality or $in on leading field 'z'"),
+            ("tags", "query has no equality or $in on leading field 'tags'"),
+            ("text", "text indexes only answer $text queries"),
+        ]);
+        
+        let report = explain(&items, json!({"tags": "x1", "z": null}), None);
+        assert_eq!(report["winningPlan"]["index"], "tags");
+        let sparse = report["rejectedPlans"].as_array().unwrap().iter().find(|plan| plan["index"] == "sparse").unwrap();
+        assert_eq!(sparse["reason"], "sparse index leaves out documents missing 'z', which equality to null matches");
+        
+        let report = explain(&items, json!({"$text": {"$search": "w3"}, "a": 3}), None);
+        assert_eq!(report["winningPlan"]["index"], "text");
+        let a = report["rejectedPlans"].as_array().unwrap().iter().find(|plan| plan["index"] == "a").unwrap();
+        assert_eq!(a["reason"], "$text queries can only use the text index");
+        assert_eq!(report["executionStats"]["nReturned"], 1);
+        
+        let report = explain(&items, json!({"tags": "y"}), Some(json!({"verbosity": "queryPlanner"})));
+        assert!(report.get("executionStats").is_none());
+        
+        let plain = collection(&docs[..1]);
+        let error = plain.state.borrow().check_query(&query(json!({"$text": {"$search": "w3"}}))).unwrap_err();
+        assert_eq!(error, "$text query requires a text index");
+    }
+
+    #[test]
+    fn intersections_and_unions_match_scans() {
+        let docs: Vec<Value> = (0..1000)
+            .map(|i| json!({"id": format!("d{}", i), "a": i % 10, "b": i % 7, "c": i}))
+            .collect();
+        let mut numbers = collection(&docs);
+        numbers.create_index("a", r#"["a"]"#, "single", None).unwrap();
+        numbers.create_index("b", r#"["b"]"#, "single", None).unwrap();
+        numbers.create_index("c", r#"["c"]"#, "unique", None).unwrap();
+        
+        for (filter, stage) in [
+            (json!({"a": 3, "b": 4}), "AND_HASH"),
+            (json!({"$and": [{"a": 3}, {"b": 4}]}), "AND_HASH"),
+            (json!({"$or": [{"c": 3}, {"c": 500}, {"a": 1, "b": 1}]}), "OR"),
+            (json!({"$or": [{"c": 3}, {"x": 1}]}), "COLLSCAN"),
+            (json!({"a": 3, "$or": [{"c": 3}, {"c": 13}]}), "OR"),
+            (json!({"$and": [{"$or": [{"c": 3}, {"c": 13}]}, {"a": 3}]}), "OR"),
+        ] {
+            assert_eq!(ids(&numbers, filter.clone()), scanned(&numbers, filter.clone()), "{}", filter);
+            assert_eq!(explain(&numbers, filter.clone(), None)["winningPlan"]["stage"], stage, "{}", filter);
+        }
+        
+        let report = explain(&numbers, json!({"a": 3, "b": 4}), None);
+        let inputs: Vec<&str> = report["winningPlan"]["inputs"].as_array().unwrap().iter()
+            .map(|input| input["index"].as_str().unwrap())
+            .collect();
+        assert_eq!(inputs, ["a", "b"]);
+        assert_eq!(report["executionStats"]["docsExamined"], report["executionStats"]["nReturned"]);
+    }
+
+    #[test]
+    fn in_queries_match_scans() {
+        let statuses = ["open", "pending", "closed", "archived"];
+        let mut docs: Vec<Value> = (0..400)
+            .map(|i| json!({"id": format!("d{}", i), "status": statuses[i % 4], "p": i % 20, "tags": [format!("t{}", i % 10), "all"]}))
+            .collect();
+        docs.push(json!({"id": "none", "p": 1}));
+        let mut tasks = collection(&docs);
+        tasks.create_index("status", r#"["status"]"#, "single", None).unwrap();
+        tasks.create_index("status_p", r#"["status", "p"]"#, "ordered", None).unwrap();
+        tasks.create_index("tags", r#"["tags"]"#, "multi", None).unwrap();
+        
+        for filter in [
+            json!({"status": {"$in": ["open", "pending"]}}),
+            json!({"status": {"$in": ["open", "pending"]}, "p": {"$in": [1, 2, 5]}}),
+            json!({"status": {"$in": ["open", "closed"]}, "p": {"$gte": 16}}),
+            json!({"tags": {"$in": ["t1", "t2"]}}),
+            json!({"status": {"$in": []}}),
+            json!({"status": {"$in": [null]}}),
+            json!({"$or": [{"status": {"$in": ["open"]}}, {"tags": "t3"}]}),
+        ] {
+            assert_eq!(ids(&tasks, filter.clone()), scanned(&tasks, filter.clone()), "{}", filter);
+            assert_ne!(explain(&tasks, filter.clone(), None)["winningPlan"]["stage"], "COLLSCAN", "{}", filter);
+        }
+    }
+
+    #[test]
+    fn null_equality_only_uses_partial_indexes_holding_missing_fields() {
+        let docs = [json!({"id": "a", "a": 1}), json!({"id": "b", "a": null}), json!({"id": "c"})];
+        let mut existing = collection(&docs);
+        existing.create_index("a", r#"["a"]"#, "single", Some(r#"{"partialFilterExpression": {"a": {"$exists": true}}}"#.to_string())).unwrap();
+        let mut not_five = collection(&docs);
+        not_five.create_index("a", r#"["a"]"#, "single", Some(r#"{"partialFilterExpression": {"a": {"$ne": 5}}}"#.to_string())).unwrap();
+        
+        for filter in [json!({"a": null}), json!({"a": {"$eq": null}}), json!({"a": {"$in": [null, 1]}})] {
+            assert_eq!(planned_index(&existing, filter.clone()), None, "{}", filter);
+            assert_eq!(ids(&existing, filter.clone()), scanned(&existing, filter.clone()), "{}", filter);
+            assert_eq!(ids(&not_five, filter.clone()), scanned(&not_five, filter.clone()), "{}", filter);
+        }
+        assert_eq!(ids(&existing, json!({"a": null})), ["b", "c"]);
+        assert_eq!(planned_index(&existing, json!({"a": 1})).as_deref(), Some("a"));
+        
+        // A filter that also holds documents missing the field still serves the query
+        assert_eq!(planned_index(&not_five, json!({"a": null})).as_deref(), Some("a"));
+    }
+
+    #[test]
+    fn find_sorts_pages_and_projects() {
+        let mut notes = collection(&[
+            json!({"id": "a", "n": 3, "s": "x", "t": "apple"}),
+            json!({"id": "b", "n": 1, "s": "y"}),
+            json!({"id": "c", "n": 3, "s": "a", "t": "apple pie"}),
+            json!({"id": "d", "s": "z", "t": "apple apple"}),
+        ]);
+        
+        let results = find(&notes, json!({}), json!({"sort": {"n": -1, "s": 1}, "skip": 1, "limit": 2, "projection": {"n": 1}}));
+        assert_eq!(results, [json!({"id": "a", "n": 3}), json!({"id": "b", "n": 1})]);
+        assert!(find(&notes, json!({}), json!({"sort": {"n": 1}, "skip": 10, "limit": 2})).is_empty());
+        
+        let options = FindOptions::parse(Some(r#"{"sort": {"score": {"$meta": "textScore"}}}"#)).unwrap();
+        let error = notes.state.borrow().find_slots(&query(json!({"n": 1})), &options).unwrap_err();
+        assert_eq!(error, "$meta textScore requires a $text query");
+        
+        notes.create_index("text", r#"["t"]"#, "text", None).unwrap();
+        let results = find(&notes, json!({"$text": {"$search": "apple"}}), json!({
+            "sort": {"score": {"$meta": "textScore"}},
+            "projection": {"s": 1, "score": {"$meta": "textScore"}},
+        }));
+        let ranked: Vec<&str> = results.iter().map(|doc| doc["s"].as_str().unwrap()).collect();
+        assert_eq!(ranked, ["z", "x", "a"]);
+        assert!(results[0]["score"].as_f64() > results[2]["score"].as_f64());
+        
+        // An explicit sort replaces the relevance order
+        let results = find(&notes, json!({"$text": {"$search": "apple"}}), json!({"sort": {"s": 1}, "projection": {"s": 1}}));
+        let sorted: Vec<&str> = results.iter().map(|doc| doc["s"].as_str().unwrap()).collect();
+        assert_eq!(sorted, ["a", "x", "z"]);
+    }
+
+    #[test]
+    fn cursors_read_documents_as_they_are_when_fetched() {
+        let docs: Vec<Value> = (0..10).map(|i| json!({"id": format!("d{}", i), "v": i})).collect();
+        let mut numbers = collection(&docs);
+        let batch_ids = |cursor: &mut Cursor, size: usize| -> Vec<String> {
+            let batch: Vec<Value> = serde_json::from_str(&cursor.next_batch(size).unwrap()).unwrap();
+            batch.iter().map(|doc| doc["id"].as_str().unwrap().to_string()).collect()
+        };
+        
+        let mut cursor = numbers.find_cursor("", Some(r#"{"sort": {"v": -1}, "projection": {"v": 0}}"#.to_string())).unwrap();
+        assert!(cursor.has_next());
+        assert_eq!(batch_ids(&mut cursor, 3), ["d9", "d8", "d7"]);
+        
+        // Later batches skip deleted documents, see updates and leave out
+        // documents inserted after the cursor was opened
+        numbers.delete(r#"{"v": {"$in": [5, 6]}}"#).unwrap();
+        numbers.update(r#"{"id": "d4"}"#, r#"{"$set": {"w": 1}}"#).unwrap();
+        numbers.insert(r#"{"id": "z", "v": 100}"#).unwrap();
+        assert_eq!(cursor.next_batch(2).unwrap(), r#"[{"id":"d4","w":1},{"id":"d3"}]"#);
+        assert_eq!(batch_ids(&mut cursor, 10), ["d2", "d1", "d0"]);
+        assert!(!cursor.has_next());
+        assert_eq!(cursor.next_batch(10).unwrap(), "[]");
+        
+        // has_next looks past results deleted since
+        let mut cursor = numbers.find_cursor("", Some(r#"{"sort": {"v": 1}, "skip": 6}"#.to_string())).unwrap();
+        numbers.delete(r#"{"id": {"$in": ["d9", "z"]}}"#).unwrap();
+        assert!(cursor.has_next());
+        numbers.delete(r#"{"id": "d8"}"#).unwrap();
+        assert!(!cursor.has_next());
+        
+        cursor.close();
+        assert!(!cursor.has_next());
+    }
+
+    #[test]
+    fn pages_resume_after_their_token() {
+        let docs: Vec<Value> = (0..20)
+            .map(|i| json!({"id": format!("d{:02}", i), "v": i % 4, "s": if i % 2 == 0 { "b" } else { "a" }}))
+            .collect();
+        let mut numbers = collection(&docs);
+        let page = |numbers: &Collection, options: String| -> (Vec<String>, Option<String>) {
+            let page: Value = serde_json::from_str(&numbers.find_page("", Some(options)).unwrap()).unwrap();
+            let ids = page["documents"].as_array().unwrap().iter().map(|doc| doc["id"].as_str().unwrap().to_string()).collect();
+            (ids, page["next"].as_str().map(str::to_string))
+        };
+        
+        // Walking every page gives the sorted order, ties broken by id. The
+        // options are written out since `json!` would reorder the sort keys.
+        let mut walked = Vec::new();
+        let mut after = String::new();
+        loop {
+            let (ids, next) = page(&numbers, format!(r#"{{"sort": {{"v": -1, "s": 1}}, "limit": 3{}}}"#, after));
+            walked.extend(ids);
+            match next {
+                Some(next) => after = format!(r#", "after": "{}""#, next),
+                None => break,
+            }
+        }
+        let mut expected: Vec<(i64, String)> = (0..20).map(|i| (-(i % 4), format!("d{:02}", i))).collect();
+        expected.sort();
+        assert_eq!(walked, expected.into_iter().map(|(_, id)| id).collect::<Vec<_>>());
+        
+        // A token marks a position in the order, so documents changed before
+        // it are not seen and deleted ones do not shift the next page
+        let (first, next) = page(&numbers, json!({"sort": {"v": 1}, "limit": 4}).to_string());
+        assert_eq!(first, ["d00", "d04", "d08", "d12"]);
+        numbers.insert(r#"{"id": "d01a", "v": 0}"#).unwrap();
+        numbers.delete(r#"{"id": {"$in": ["d12", "d16"]}}"#).unwrap();
+        let (second, _) = page(&numbers, json!({"sort": {"v": 1}, "limit": 4, "after": next.unwrap()}).to_string());
+        assert_eq!(second, ["d01", "d05", "d09", "d13"]);
+        
+        // The last page has no token
+        assert!(page(&numbers, json!({"limit": 19}).to_string()).1.is_none());
+        assert!(page(&numbers, json!({"limit": 18}).to_string()).1.is_some());
+        
+        let state = numbers.state.borrow();
+        for (after, error) in [("zz", "Invalid continuation token"), ("abc", "Invalid continuation token")] {
+            let options = FindOptions::parse(Some(&json!({"after": after}).to_string())).unwrap();
+            let message = state.find_page(&Query::empty(), &options).err().unwrap();
+            assert!(message.contains(error), "{}", message);
+        }
+    }
+
+    #[test]
+    fn pipeline_sorts_can_walk_indexes() {
+        let docs: Vec<Value> = (0..200)
+            .map(|i| json!({"id": format!("d{}", i), "v": (i * 37) % 23, "w": i % 3, "g": i % 2}))
+            .collect();
+        let mut numbers = collection(&docs);
+        let pipelines = [
+            r#"[{"$sort": {"v": 1}}]"#,
+            r#"[{"$sort": {"v": -1}}, {"$limit": 7}]"#,
+            r#"[{"$match": {"g": 1}}, {"$sort": {"v": -1, "w": -1}}, {"$skip": 3}, {"$limit": 5}]"#,
+            r#"[{"$match": {"g": 0}}, {"$sort": {"v": 1, "w": 1}}]"#,
+            r#"[{"$sort": {"v": 1, "w": -1}}, {"$limit": 9}]"#,
+        ];
+        let in_memory: Vec<String> = pipelines.iter().map(|pipeline| numbers.aggregate(pipeline).unwrap()).collect();
+        
+        numbers.create_index("v", r#"["v"]"#, "ordered", None).unwrap();
+        numbers.create_index("vw", r#"["v", "w"]"#, "single", None).unwrap();
+        let all: Vec<usize> = (0..200).collect();
+        let sort = |spec: &str| -> SortSpec { serde_json::from_str(spec).unwrap() };
+        {
+            let state = numbers.state.borrow();
+            assert!(state.index_sorted_slots(&all, &sort(r#"{"v": -1}"#), Some(3)).is_some());
+            assert!(state.index_sorted_slots(&all, &sort(r#"{"v": 1, "w": 1}"#), None).is_some());
+            // Mixed directions can't walk the index
+            assert!(state.index_sorted_slots(&all, &sort(r#"{"v": 1, "w": -1}"#), None).is_none());
+        }
+        let indexed: Vec<String> = pipelines.iter().map(|pipeline| numbers.aggregate(pipeline).unwrap()).collect();
+        assert_eq!(indexed, in_memory);
+        
+        // An array gives a document several keys, so the index no longer
+        // holds one entry per document
+        numbers.insert(r#"{"id": "arr", "v": [1, 30], "w": 0, "g": 0}"#).unwrap();
+        assert!(numbers.state.borrow().index_sorted_slots(&all, &sort(r#"{"v": -1}"#), None).is_none());
+    }
+
+    #[test]
+    fn lookups_join_sibling_collections() {
+        let mut database = Database::new();
+        let mut users = database.collection("users");
+        let mut orders = database.collection("orders");
+        for user in [
+            json!({"id": "u1", "name": "Ann", "friends": ["u2", "u3"], "max": 50}),
+            json!({"id": "u2", "name": "Bob", "friends": [], "max": 10}),
+            json!({"id": "u3", "name": "Cy"}),
+        ] {
+            users.insert(&user.to_string()).unwrap();
+        }
+        for order in [
+            json!({"id": "o1", "user": "u1", "total": 30, "item": "b"}),
+            json!({"id": "o2", "user": "u1", "total": 70, "item": "a"}),
+            json!({"id": "o3", "user": "u2", "total": 5, "item": "a"}),
+            json!({"id": "o4", "total": 1, "item": "c"}),
+        ] {
+            orders.insert(&order.to_string()).unwrap();
+        }
+        orders.create_index("user", r#"["user"]"#, "single", None).unwrap();
+        
+        let results = users.aggregate(r#"[{"$lookup": {"from": "orders", "localField": "id", "foreignField": "user", "as": "orders"}}]"#).unwrap();
+        assert_eq!(joined(&results, "orders"), ["u1:o1,o2", "u2:o3", "u3:"]);
+        
+        // Arrays join on each element, and a collection can join itself
+        let results = users.aggregate(r#"[{"$lookup": {"from": "users", "localField": "friends", "foreignField": "id", "as": "friends"}}]"#).unwrap();
+        assert_eq!(joined(&results, "friends"), ["u1:u2,u3", "u2:", "u3:"]);
+        
+        // A missing local field joins documents missing the foreign one
+        let results = users.aggregate(r#"[{"$match": {"id": "u3"}}, {"$lookup": {"from": "orders", "localField": "nope", "foreignField": "user", "as": "orders"}}]"#).unwrap();
+        assert_eq!(joined(&results, "orders"), ["u3:o4"]);
+        
+        let results = users.aggregate(r#"[
+            {"$lookup": {
+                "from": "orders",
+                "let": {"uid": "$id", "cap": "$max"},
+                "pipeline": [
+                    {"$match": {"user": "$$uid", "total": {"$lte": "$$cap"}}},
+                    {"$sort": {"item": 1, "total": -1}},
+                    {"$project": {"id": 1, "u": "$$uid"}}
+                ],
+                "as": "small"
+            }},
+            {"$project": {"small": 1, "id": 0}}
+        ]"#).unwrap();
+        assert_eq!(results, r#"[{"small":[{"id":"o1","u":"u1"}]},{"small":[{"id":"o3","u":"u2"}]},{"small":[]}]"#);
+        
+        // Missing collections join nothing
+        let results = users.aggregate(r#"[{"$lookup": {"from": "ghost", "localField": "id", "foreignField": "x", "as": "ghosts"}}]"#).unwrap();
+        assert_eq!(joined(&results, "ghosts"), ["u1:", "u2:", "u3:"]);
+        
+        let results = database.find_with_lookup("orders", r#"{"id": "o3"}"#, r#"[
+            {"from": "users", "localField": "user", "foreignField": "id", "as": "buyer"},
+            {"from": "orders", "localField": "user", "foreignField": "user", "as": "same"}
+        ]"#).unwrap();
+        assert_eq!(joined(&results, "buyer"), ["o3:u2"]);
+        assert_eq!(joined(&results, "same"), ["o3:o3"]);
+        
+        let pipeline: Vec<PipelineStage> = serde_json::from_str(r#"[{"$lookup": {"from": "orders", "pipeline": [], "as": "orders"}}]"#).unwrap();
+        let error = Collection::new("lone").state.borrow().aggregate(&pipeline).unwrap_err();
+        assert_eq!(error, "$lookup is only available on collections of a Database");
+        
+        database.drop_collection("orders");
+        let results = users.aggregate(r#"[{"$lookup": {"from": "orders", "localField": "id", "foreignField": "user", "as": "orders"}}]"#).unwrap();
+        assert_eq!(joined(&results, "orders"), ["u1:", "u2:", "u3:"]);
+    }
+
+    #[test]
+    fn expressions_compare_fields_of_a_document() {
+        let accounts = collection(&[
+            json!({"id": "a", "spent": 120, "budget": 100, "name": "Ann"}),
+            json!({"id": "b", "spent": 50, "budget": 100, "name": "bob"}),
+            json!({"id": "c", "spent": 100, "budget": null, "name": "Cy"}),
+        ]);
+        
+        assert_eq!(ids(&accounts, json!({"$expr": {"$gt": ["$spent", "$budget"]}})), ["a", "c"]);
+        assert_eq!(ids(&accounts, json!({"$expr": {"$lt": [{"$multiply": ["$spent", 2]}, {"$add": ["$budget", 1]}]}})), ["b"]);
+        assert_eq!(ids(&accounts, json!({"spent": {"$gte": 100}, "$expr": {"$eq": ["$$ROOT.id", "c"]}})), ["c"]);
+        assert!(serde_json::from_value::<Query>(json!({"$expr": {"$bogus": 1}})).is_err());
+        
+        let results = find(&accounts, json!({"id": "a"}), json!({"projection": {"over": {"$subtract": ["$spent", "$budget"]}, "name": {"$toLower": "$name"}}}));
+        assert_eq!(results, [json!({"id": "a", "over": 20, "name": "ann"})]);
+        
+        let results = accounts.aggregate(r#"[
+            {"$addFields": {"ratio": {"$cond": [{"$eq": ["$budget", null]}, null, {"$divide": ["$spent", "$budget"]}]}}},
+            {"$group": {"id": {"$gt": ["$ratio", 1]}, "n": {"$sum": 1}}},
+            {"$sort": {"n": -1}}
+        ]"#).unwrap();
+        assert_eq!(results, r#"[{"id":false,"n":2},{"id":true,"n":1}]"#);
+    }
+
+    #[test]
+    fn regex_prefixes_use_ordered_indexes() {
+        let mut people = collection(&[
+            json!({"id": "a", "name": "Alice", "tags": ["rust", "wasm"]}),
+            json!({"id": "b", "name": "alfred", "tags": ["go"]}),
+            json!({"id": "c", "name": "Bob", "tags": ["rusty"]}),
+            json!({"id": "d", "name": "Al"}),
+            json!({"id": "e", "name": "Alz"}),
+            json!({"id": "f", "name": 7}),
+        ]);
+        let filters = [
+            json!({"name": {"$regex": "^Al"}}),
+            json!({"name": {"$regex": "^Ali?"}}),
+            json!({"name": {"$regex": "^Alz+"}}),
+            json!({"name": {"$regex": "^al", "$options": "i"}}),
+            json!({"name": {"$regex": "^A|B"}}),
+            json!({"tags": {"$regex": "^rust$"}}),
+        ];
+        let scanned: Vec<Vec<String>> = filters.iter().map(|filter| ids(&people, filter.clone())).collect();
+        assert_eq!(scanned, [vec!["a", "d", "e"], vec!["a", "d", "e"], vec!["e"], vec!["a", "b", "d", "e"], vec!["a", "c", "d", "e"], vec!["a"]]);
+        
+        people.create_index("name", r#"["name"]"#, "ordered", None).unwrap();
+        let indexed: Vec<Vec<String>> = filters.iter().map(|filter| ids(&people, filter.clone())).collect();
+        assert_eq!(indexed, scanned);
+        
+        assert_eq!(planned_index(&people, json!({"name": {"$regex": "^Al"}})).as_deref(), Some("name"));
+        for filter in [json!({"name": {"$regex": "^al", "$options": "i"}}), json!({"name": {"$regex": "^A|B"}}), json!({"name": {"$regex": "l"}})] {
+            assert_eq!(planned_index(&people, filter.clone()), None, "{}", filter);
+        }
+        
+        assert_eq!(people.update(r#"{"id": "a"}"#, r#"{"$pull": {"tags": {"$regex": "^R", "$options": "i"}}}"#).unwrap(), 1);
+        assert_eq!(find(&people, json!({"id": "a"}), json!({"projection": {"tags": 1}})), [json!({"id": "a", "tags": ["wasm"]})]);
+    }
+
+    #[test]
+    fn ranges_compare_within_one_type() {
+        let mut values = collection(&[
+            json!({"id": "a", "s": "apple", "b": true, "n": 1, "arr": [1, 5], "d": "2024-01-05T00:00:00.000Z"}),
+            json!({"id": "b", "s": "banana", "b": false, "n": 1.0, "arr": [2], "d": "2023-12-31T00:00:00.000Z"}),
+            json!({"id": "c", "s": "cherry", "n": "1", "arr": ["x"], "d": 1700000000000i64}),
+            json!({"id": "d", "s": ["zed", "aa"], "b": null, "n": 2, "arr": [[0]]}),
+        ]);
+        let filters = [
+            (json!({"s": {"$gt": "b"}}), vec!["b", "c", "d"]),
+            (json!({"s": {"$gte": "apple", "$lt": "c"}}), vec!["a", "b", "d"]),
+            (json!({"s": {"$lte": "ab"}}), vec!["d"]),
+            (json!({"b": {"$gt": false}}), vec!["a"]),
+            (json!({"b": {"$gte": false}}), vec!["a", "b"]),
+            (json!({"b": {"$lte": null}}), vec!["d"]),
+            (json!({"n": {"$gte": 1, "$lte": 1}}), vec!["a", "b"]),
+            (json!({"n": {"$in": [1.0]}}), vec!["a", "b"]),
+            (json!({"n": {"$gt": "0"}}), vec!["c"]),
+            (json!({"d": {"$gte": "2024-01-01"}}), vec!["a"]),
+            (json!({"d": {"$lt": 1800000000000i64}}), vec!["c"]),
+            (json!({"arr": {"$gt": [1]}}), vec!["a", "b", "c", "d"]),
+            (json!({"arr": {"$gt": 2, "$lt": 4}}), vec!["a"]),
+            (json!({"arr": {"$lt": [1]}}), vec!["d"]),
+        ];
+        for (filter, expected) in &filters {
+            assert_eq!(&ids(&values, filter.clone()), expected, "{}", filter);
+        }
+        
+        for field in ["s", "b", "n", "d", "arr"] {
+            values.create_index(field, &format!(r#"["{}"]"#, field), "ordered", None).unwrap();
+        }
+        assert_eq!(planned_index(&values, json!({"b": {"$gt": false}})).as_deref(), Some("b"));
+        for (filter, expected) in &filters {
+            assert_eq!(&ids(&values, filter.clone()), expected, "{}", filter);
+        }
+        
+        let results = find(&values, json!({}), json!({"sort": {"s": 1}, "projection": {"s": 1, "id": 0}}));
+        assert_eq!(results, [json!({"s": ["zed", "aa"]}), json!({"s": "apple"}), json!({"s": "banana"}), json!({"s": "cherry"})]);
+    }
+
+    #[test]
+    fn array_queries_agree_with_multikey_indexes() {
+        let mut tagged = collection(&[
+            json!({"id": "a", "tags": ["a", "b", "c"]}),
+            json!({"id": "b", "tags": ["b"]}),
+            json!({"id": "c", "tags": "a"}),
+            json!({"id": "d", "tags": []}),
+        ]);
+        let filters = [
+            (json!({"tags": "a"}), vec!["a", "c"]),
+            (json!({"tags": {"$all": ["a", "c"]}}), vec!["a"]),
+            (json!({"tags": {"$in": ["b"]}}), vec!["a", "b"]),
+            (json!({"tags": ["b"]}), vec!["b"]),
+            (json!({"tags": {"$size": 0}}), vec!["d"]),
+            (json!({"tags": {"$elemMatch": {"$regex": "^c"}}}), vec!["a"]),
+        ];
+        for (filter, expected) in &filters {
+            assert_eq!(&ids(&tagged, filter.clone()), expected, "{}", filter);
+        }
+        
+        tagged.create_index("t", r#"["tags"]"#, "single", None).unwrap();
+        assert_eq!(planned_index(&tagged, json!({"tags": "a"})).as_deref(), Some("t"));
+        for (filter, expected) in &filters {
+            assert_eq!(&ids(&tagged, filter.clone()), expected, "{}", filter);
+        }
+    }
+
+    #[test]
+    fn dot_path_queries_agree_with_indexes() {
+        let mut orders = collection(&[
+            json!({"id": "a", "items": [{"price": 5, "tags": ["x"]}, {"price": 20}], "o": {"list": [{"v": 1}, {"v": [2, 3]}]}}),
+            json!({"id": "b", "items": [{"price": 12}, {"price": 3, "name": "n"}], "o": {"list": [7]}}),
+            json!({"id": "c", "items": {"price": 8}, "o": {"list": []}}),
+            json!({"id": "d", "items": [[{"price": 100}]]}),
+        ]);
+        let filters = [
+            (json!({"items.price": 5}), vec!["a"]),
+            (json!({"items.price": {"$gt": 10}}), vec!["a", "b"]),
+            (json!({"items.price": {"$gt": 6, "$lt": 10}}), vec!["a", "b", "c"]),
+            (json!({"items.price": {"$in": [3, 8]}}), vec!["b", "c"]),
+            (json!({"items.price": {"$ne": 5}}), vec!["b", "c", "d"]),
+            (json!({"items.price": 100}), vec![]),
+            (json!({"items.0.price": {"$gte": 5}}), vec!["a", "b", "d"]),
+            (json!({"items.1.price": {"$lt": 10}}), vec!["b"]),
+            (json!({"items.0.0.price": 100}), vec!["d"]),
+            (json!({"items.name": {"$exists": true}}), vec!["b"]),
+            (json!({"items.tags": "x"}), vec!["a"]),
+            (json!({"o.list.v": 3}), vec!["a"]),
+            (json!({"o.list.0": 7}), vec!["b"]),
+        ];
+        for (filter, expected) in &filters {
+            assert_eq!(&ids(&orders, filter.clone()), expected, "{}", filter);
+        }
+        
+        orders.create_index("ip", r#"["items.price"]"#, "ordered", None).unwrap();
+        orders.create_index("i0", r#"["items.0.price"]"#, "single", None).unwrap();
+        orders.create_index("ol", r#"["o.list.v"]"#, "single", None).unwrap();
+        assert_eq!(planned_index(&orders, json!({"items.price": 5})).as_deref(), Some("ip"));
+        assert_eq!(planned_index(&orders, json!({"items.0.price": 12})).as_deref(), Some("i0"));
+        for (filter, expected) in &filters {
+            assert_eq!(&ids(&orders, filter.clone()), expected, "{}", filter);
+        }
+        
+        let results = find(&orders, json!({"items.price": {"$lt": 4}}), json!({"projection": {"items.price": 1}}));
+        assert_eq!(results, [json!({"id": "b", "items": [{"price": 12}, {"price": 3}]})]);
+    }
+}
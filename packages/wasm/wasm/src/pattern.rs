@@ -0,0 +1,158 @@
+use std::ops::Bound;
+use regex::{Regex, RegexBuilder};
+use serde_json::Value;
+
+// A compiled $regex condition
+#[derive(Debug)]
+pub struct Pattern {
+    regex: Regex,
+    // Bounds of the strings a pattern anchored to a literal prefix can match
+    prefix_range: Option<(Value, Option<Value>)>,
+}
+
+impl Pattern {
+    // Compile a pattern with $options flags: `i` ignores case, `m` lets ^ and
+    // $ match at line breaks, `s` lets . match line breaks and `x` ignores
+    // whitespace and # comments in the pattern
+    pub fn compile(pattern: &str, options: &str) -> Result<Pattern, String> {
+        let mut builder = RegexBuilder::new(pattern);
+        
+        for flag in options.chars() {
+            match flag {
+                'i' => builder.case_insensitive(true),
+                'm' => builder.multi_line(true),
+                's' => builder.dot_matches_new_line(true),
+                'x' => builder.ignore_whitespace(true),
+                _ => return Err(format!("Invalid $options flag '{}'", flag)),
+            };
+        }
+        
+        let regex = builder.build().map_err(|e| format!("Invalid $regex /{}/: {}", pattern, e))?;
+        
+        // Case folding, line anchors and free spacing all change which
+        // strings a literal prefix stands for
+        let prefix_range = if options.is_empty() {
+            literal_prefix(pattern).map(|prefix| {
+                let end = prefix_successor(&prefix).map(Value::String);
+                (Value::String(prefix), end)
+            })
+        } else {
+            None
+        };
+        
+        Ok(Pattern { regex, prefix_range })
+    }
+    
+    // Only strings match, anywhere in the string unless the pattern is anchored
+    pub fn is_match(&self, value: &Value) -> bool {
+        matches!(value, Value::String(s) if self.regex.is_match(s))
+    }
+    
+    // Range holding every string the pattern matches, for patterns such as
+    // `^abc` that begin with a literal prefix
+    pub fn prefix_range(&self) -> Option<(Bound<&Value>, Bound<&Value>)> {
+        let (start, end) = self.prefix_range.as_ref()?;
+        Some((Bound::Included(start), end.as_ref().map_or(Bound::Unbounded, Bound::Excluded)))
+    }
+}
+
+// The literal text a `^`-anchored pattern requires its matches to start with,
+// stopping at the first character with a special meaning. A character
+// followed by a quantifier is optional, so it is not part of the prefix.
+fn literal_prefix(pattern: &str) -> Option<String> {
+    let rest = pattern.strip_prefix('^')?;
+    
+    // Alternatives may not share the prefix
+    if rest.contains('|') {
+        return None;
+    }
+    
+    let mut prefix = String::new();
+    for c in rest.chars() {
+        match c {
+            '?' | '*' | '{' => {
+                prefix.pop();
+                break;
+            },
+            '.' | '^' | '$' | '+' | '(' | ')' | '[' | ']' | '}' | '\\' => break,
+            c => prefix.push(c),
+        }
+    }
+    
+    if prefix.is_empty() {
+        None
+    } else {
+        Some(prefix)
+    }
+}
+
+// The least string greater than every string starting with `prefix`: the
+// prefix with its last character incremented, `None` if there is none
+fn prefix_successor(prefix: &str) -> Option<String> {
+    let mut chars: Vec<char> = prefix.chars().collect();
+    
+    while let Some(last) = chars.pop() {
+        // Step over the surrogate range, which holds no characters
+        let next = (last as u32 + 1..=char::MAX as u32).find_map(char::from_u32);
+        if let Some(next) = next {
+            chars.push(next);
+            return Some(chars.into_iter().collect());
+        }
+    }
+    
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    fn matches(pattern: &str, options: &str, value: Value) -> bool {
+        Pattern::compile(pattern, options).unwrap().is_match(&value)
+    }
+    
+    #[test]
+    fn options_change_what_matches() {
+        assert!(matches("^Al", "", json!("Alice")));
+        assert!(!matches("^Al", "", json!("alfred")));
+        assert!(matches("^al", "i", json!("Alice")));
+        assert!(!matches("^Hello", "", json!("line1\nHello")));
+        assert!(matches("^Hello", "m", json!("line1\nHello")));
+        assert!(!matches("1.Hello", "", json!("line1\nHello")));
+        assert!(matches("1.Hello", "s", json!("line1\nHello")));
+        assert!(matches(r"x \. y # dot", "x", json!("x.y")));
+        
+        // Only strings match
+        assert!(!matches("7", "", json!(7)));
+        assert!(!matches("a", "", json!(["a"])));
+    }
+    
+    #[test]
+    fn rejects_invalid_patterns_and_flags() {
+        assert!(Pattern::compile("(a", "").unwrap_err().starts_with("Invalid $regex /(a/"));
+        assert_eq!(Pattern::compile("a", "q").unwrap_err(), "Invalid $options flag 'q'");
+    }
+    
+    #[test]
+    fn literal_prefixes_bound_the_matches() {
+        assert_eq!(literal_prefix("^Al"), Some("Al".to_string()));
+        assert_eq!(literal_prefix("^Ali?"), Some("Al".to_string()));
+        assert_eq!(literal_prefix("^Alz+"), Some("Alz".to_string()));
+        assert_eq!(literal_prefix("^ab{2}"), Some("a".to_string()));
+        assert_eq!(literal_prefix("^a.c"), Some("a".to_string()));
+        assert_eq!(literal_prefix("^A|B"), None);
+        assert_eq!(literal_prefix("^."), None);
+        assert_eq!(literal_prefix("Al"), None);
+        
+        assert_eq!(prefix_successor("Al"), Some("Am".to_string()));
+        assert_eq!(prefix_successor("a\u{d7ff}"), Some("a\u{e000}".to_string()));
+        assert_eq!(prefix_successor("a\u{10ffff}"), Some("b".to_string()));
+        assert_eq!(prefix_successor("\u{10ffff}"), None);
+        
+        let pattern = Pattern::compile("^Al", "").unwrap();
+        let (start, end) = (json!("Al"), json!("Am"));
+        assert_eq!(pattern.prefix_range(), Some((Bound::Included(&start), Bound::Excluded(&end))));
+        assert!(Pattern::compile("^Al", "i").unwrap().prefix_range().is_none());
+    }
+}
@@ -0,0 +1,244 @@
+use std::borrow::Cow;
+use serde::{Deserialize, Serialize};
+use crate::index::IndexStats;
+use crate::query::Query;
+
+// Costs are in units of reading one document and testing it against the query
+const DOCUMENT_COST: f64 = 1.0;
+// Resolving an id returned by an index to its document
+const LOOKUP_COST: f64 = 0.2;
+// Reading one index entry, or combining one id into an intersection or union
+const KEY_COST: f64 = 0.1;
+// Descending one level of an index's sorted keys to the first match
+const SEEK_COST: f64 = 0.1;
+
+// Fraction of an index's entries a range condition is assumed to keep,
+// when bounded on one side or on both
+pub const OPEN_RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+pub const CLOSED_RANGE_SELECTIVITY: f64 = 1.0 / 4.0;
+
+// Ordered by preference between plans of equal cost
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    #[serde(rename = "IXSCAN")]
+    IndexScan,
+    // Ids returned by every input
+    #[serde(rename = "AND_HASH")]
+    Intersection,
+    // Ids returned by any input
+    #[serde(rename = "OR")]
+    Union,
+    #[serde(rename = "COLLSCAN")]
+    CollectionScan,
+}
+
+// A way of answering a query: scanning every document, looking up an index,
+// or combining the ids of other plans. Every plan but a collection scan
+// returns a superset of the matches, which the full query then filters.
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PlanCandidate<'a> {
+    pub stage: Stage,
+    pub index: Option<&'a str>,
+    pub estimated_rows: f64,
+    pub cost: f64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub inputs: Vec<PlanCandidate<'a>>,
+    // The query an index scan looks up: the full query, or one of its $and
+    // or $or branches
+    #[serde(skip)]
+    pub query: Option<Cow<'a, Query>>,
+    // Fields of an index scan's index, so intersections skip indexes over
+    // fields another input already covers
+    #[serde(skip)]
+    pub fields: &'a [String],
+    // Cost of producing the ids, before their documents are fetched
+    #[serde(skip)]
+    scan_cost: f64,
+}
+
+impl<'a> PlanCandidate<'a> {
+    pub fn full_scan(documents: usize) -> Self {
+        PlanCandidate {
+            stage: Stage::CollectionScan,
+            index: None,
+            estimated_rows: documents as f64,
+            cost: documents as f64 * DOCUMENT_COST,
+            inputs: Vec::new(),
+            query: None,
+            fields: &[],
+            scan_cost: 0.0,
+        }
+    }
+    
+    // Every id the index returns is looked up and post-filtered
+    pub fn index(name: &'a str, fields: &'a [String], query: Cow<'a, Query>, stats: &IndexStats, estimated_rows: f64) -> Self {
+        let scan_cost = (stats.distinct_keys as f64 + 1.0).log2() * SEEK_COST + estimated_rows * KEY_COST;
+        
+        PlanCandidate {
+            stage: Stage::IndexScan,
+            index: Some(name),
+            estimated_rows,
+            cost: scan_cost + fetch_cost(estimated_rows),
+            inputs: Vec::new(),
+            query: Some(query),
+            fields,
+            scan_cost,
+        }
+    }
+    
+    // Inputs are assumed to select documents independently of each other
+    pub fn intersection(documents: usize, inputs: Vec<PlanCandidate<'a>>) -> Self {
+        let total = documents.max(1) as f64;
+        let estimated_rows = inputs.iter().fold(total, |rows, input| rows * (input.estimated_rows / total).min(1.0));
+        Self::combine(Stage::Intersection, estimated_rows, inputs)
+    }
+    
+    pub fn union(documents: usize, inputs: Vec<PlanCandidate<'a>>) -> Self {
+        let estimated_rows = inputs.iter().map(|input| input.estimated_rows).sum::<f64>().min(documents as f64);
+        Self::combine(Stage::Union, estimated_rows, inputs)
+    }
+    
+    // Whether the plan looks up the index, directly or through its inputs
+    pub fn uses(&self, name: &str) -> bool {
+        self.index == Some(name) || self.inputs.iter().any(|input| input.uses(name))
+    }
+    
+    fn combine(stage: Stage, estimated_rows: f64, inputs: Vec<PlanCandidate<'a>>) -> Self {
+        let scan_cost = inputs.iter()
+            .map(|input| input.scan_cost + input.estimated_rows * KEY_COST)
+            .sum();
+        
+        PlanCandidate {
+            stage,
+            index: None,
+            estimated_rows,
+            cost: scan_cost + fetch_cost(estimated_rows),
+            inputs,
+            query: None,
+            fields: &[],
+            scan_cost,
+        }
+    }
+}
+
+fn fetch_cost(rows: f64) -> f64 {
+    rows * (LOOKUP_COST + DOCUMENT_COST)
+}
+
+// Sort candidates cheapest first. Equal costs prefer simpler stages, then the
+// index with the smaller name, so plan choice never depends on map iteration
+// order.
+pub fn rank(candidates: &mut [PlanCandidate]) {
+    candidates.sort_by(|a, b| {
+        a.cost.total_cmp(&b.cost)
+            .then_with(|| a.stage.cmp(&b.stage))
+            .then_with(|| a.index.cmp(&b.index))
+    });
+}
+
+// A plan `explain` passed over: a costed plan that lost, or an index no plan
+// could use
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct RejectedPlan<'a> {
+    #[serde(flatten)]
+    pub plan: Option<PlanCandidate<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub index: Option<&'a str>,
+    pub reason: String,
+}
+
+// Work done running a query
+#[derive(Serialize, Default, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ExecutionStats {
+    // Index entries read, counting multikey documents once per matching key
+    pub keys_examined: usize,
+    // Documents tested against the full query
+    pub docs_examined: usize,
+    pub n_returned: usize,
+    pub execution_time_millis: f64,
+}
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Verbosity {
+    // Only choose the plan
+    QueryPlanner,
+    // Also run it and report the work done
+    #[default]
+    ExecutionStats,
+}
+
+// Options accepted by `Collection::explain`
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ExplainOptions {
+    pub verbosity: Verbosity,
+}
+
+// Report returned by `Collection::explain`
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Explain<'a> {
+    pub winning_plan: PlanCandidate<'a>,
+    pub rejected_plans: Vec<RejectedPlan<'a>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub execution_stats: Option<ExecutionStats>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    fn stats(distinct_keys: usize, entries: usize) -> IndexStats {
+        IndexStats {
+            distinct_keys,
+            entries,
+            avg_bucket_size: entries as f64 / distinct_keys as f64,
+        }
+    }
+    
+    fn scan(name: &'static str, rows: f64) -> PlanCandidate<'static> {
+        PlanCandidate::index(name, &[], Cow::Owned(Query::empty()), &stats(100, 200), rows)
+    }
+    
+    #[test]
+    fn index_scans_beat_full_scans_when_selective() {
+        let full = PlanCandidate::full_scan(200);
+        assert_eq!(full.cost, 200.0);
+        assert!(scan("a", 2.0).cost < full.cost);
+        assert!(scan("a", 200.0).cost > full.cost);
+    }
+    
+    #[test]
+    fn combined_plans_estimate_their_rows() {
+        let intersection = PlanCandidate::intersection(200, vec![scan("a", 20.0), scan("b", 100.0)]);
+        assert_eq!(intersection.stage, Stage::Intersection);
+        assert_eq!(intersection.estimated_rows, 10.0);
+        assert!(intersection.uses("b"));
+        assert!(!intersection.uses("c"));
+        
+        let union = PlanCandidate::union(200, vec![scan("a", 20.0), scan("b", 100.0)]);
+        assert_eq!(union.estimated_rows, 120.0);
+        assert_eq!(PlanCandidate::union(200, vec![scan("a", 150.0), scan("b", 100.0)]).estimated_rows, 200.0);
+        
+        // Combining pays for every input's keys on top of fetching the result
+        assert!(intersection.cost > scan("a", 10.0).cost);
+    }
+    
+    #[test]
+    fn ranking_breaks_ties_by_stage_then_name() {
+        let mut candidates = vec![
+            PlanCandidate::full_scan(2),
+            scan("b", 1.0),
+            scan("a", 1.0),
+            scan("c", 0.5),
+        ];
+        rank(&mut candidates);
+        
+        let order: Vec<Option<&str>> = candidates.iter().map(|plan| plan.index).collect();
+        assert_eq!(order, [Some("c"), Some("a"), Some("b"), None]);
+    }
+}
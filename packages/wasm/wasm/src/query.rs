@@ -1,268 +1,949 @@
 use serde::{Serialize, Deserialize};
-use serde_json::Value;
-use std::collections::HashMap;
+use serde_json::{Map, Value};
+use std::cmp::Ordering;
+use std::ops::Bound;
+use std::rc::Rc;
 use crate::document::Document;
+use crate::expr::{self, Expr, Fields};
+use crate::find;
+use crate::geo::{self, GeoCondition};
+use crate::key;
+use crate::pattern::Pattern;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(try_from = "Map<String, Value>")]
 pub struct Query {
     #[serde(flatten)]
-    conditions: HashMap<String, Value>,
+    conditions: Map<String, Value>,
+    // The conditions compiled for matching, shared by clones
+    #[serde(skip)]
+    filter: Rc<Filter>,
+}
+
+impl TryFrom<Map<String, Value>> for Query {
+    type Error = String;
+    
+    fn try_from(conditions: Map<String, Value>) -> Result<Self, String> {
+        Query::compile(conditions, true)
+    }
 }
 
 impl Query {
     pub fn empty() -> Self {
         Query {
-            conditions: HashMap::new(),
+            conditions: Map::new(),
+            filter: Rc::default(),
         }
     }
     
-    pub fn matches(&self, doc: &Document) -> bool {
-        // Empty query matches everything
-        if self.conditions.is_empty() {
-            return true;
-        }
+    // Compile a query, or a nested one where $text is not allowed since only
+    // the top level is answered by the text index
+    fn compile(conditions: Map<String, Value>, top_level: bool) -> Result<Query, String> {
+        let filter = Filter::compile(&conditions, top_level)?;
+        Ok(Query {
+            conditions,
+            filter: Rc::new(filter),
+        })
+    }
+    
+    pub fn matches(&self, doc: &dyn Fields) -> bool {
+        self.filter.matches(doc)
+    }
+    
+    pub fn is_empty(&self) -> bool {
+        self.conditions.is_empty()
+    }
+    
+    pub fn has_text(&self) -> bool {
+        self.filter.text.is_some()
+    }
+    
+    // The search string of a {$text: {$search: "..."}} condition
+    pub fn text_search(&self) -> Option<&str> {
+        self.filter.text.as_deref()
+    }
+    
+    // The first geo operator on a field
+    pub fn geo_condition(&self, field: &str) -> Option<&GeoCondition> {
+        self.filter.field_condition(field)?.operators().iter().find_map(Operator::as_geo)
+    }
+    
+    // Distance from the query's $near/$nearSphere center to the document's
+    // closest geometry on the field
+    pub fn near_distance(&self, doc: &Document) -> Option<f64> {
+        self.filter.geo_conditions().find_map(|(field, geo)| {
+            doc.values(field).into_iter().filter_map(|value| geo.near_distance(value)).reduce(f64::min)
+        })
+    }
+    
+    pub fn has_near(&self) -> bool {
+        self.filter.geo_conditions().any(|(_, geo)| geo.is_near())
+    }
+    
+    // Sub-queries of a top-level $and, compiled with this query
+    pub fn and_branches(&self) -> impl Iterator<Item = &Query> {
+        self.filter.clauses.iter()
+            .filter_map(|clause| match clause {
+                Clause::And(branches) => Some(branches),
+                _ => None,
+            })
+            .flatten()
+    }
+    
+    // Sub-queries of a top-level $or, compiled with this query
+    pub fn or_branches(&self) -> Option<&[Query]> {
+        self.filter.clauses.iter().find_map(|clause| match clause {
+            Clause::Or(branches) => Some(branches.as_slice()),
+            _ => None,
+        })
+    }
+    
+    // Copy of the query with each "$$name" string replaced by the value of
+    // that variable, failing if a bound value makes the query malformed
+    pub fn bind(&self, variables: &Map<String, Value>) -> Result<Query, String> {
+        Query::try_from(self.conditions.iter()
+            .map(|(field, condition)| (field.clone(), bind_variables(condition, variables, false)))
+            .collect::<Map<String, Value>>())
+    }
+    
+    // Values a field must equal, or hold as an array element, under a literal,
+    // $eq or $in condition
+    pub fn equality_values(&self, field: &str) -> Option<Vec<&Value>> {
+        self.filter.equality_values(field)
+    }
+    
+    // Conservatively decide whether every document matching this query also
+    // matches `filter`
+    pub fn implies(&self, filter: &Query) -> bool {
+        self.filter.implies(&filter.filter)
+    }
+    
+    // Bounds of the range operators on a field, if it has any
+    pub fn range_bounds(&self, field: &str) -> Option<(Bound<&Value>, Bound<&Value>)> {
+        self.filter.range_bounds(field)
+    }
+}
+
+// A query's conditions compiled into typed clauses, all of which must hold,
+// so documents are checked without reinterpreting the JSON
+#[derive(Debug, Default)]
+struct Filter {
+    clauses: Vec<Clause>,
+    // The $text search, answered by the collection's text index
+    text: Option<String>,
+}
+
+#[derive(Debug)]
+enum Clause {
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Filter),
+    Expr(Expr),
+    Field(String, Condition),
+}
+
+impl Filter {
+    fn compile(conditions: &Map<String, Value>, top_level: bool) -> Result<Filter, String> {
+        let mut clauses = Vec::with_capacity(conditions.len());
+        let mut text = None;
         
-        // Check each condition
-        for (key, value) in &self.conditions {
-            match key.as_str() {
-                "$and" => {
-                    if let Value::Array(and_conditions) = value {
-                        for condition in and_conditions {
-                            if let Value::Object(cond_obj) = condition {
-                                let sub_query = Query {
-                                    conditions: cond_obj.clone(),
-                                };
-                                if !sub_query.matches(doc) {
-                                    return false;
-                                }
-                            }
-                        }
-                    }
+        for (key, value) in conditions {
+            let clause = match key.as_str() {
+                "$and" => Clause::And(compile_branches(key, value)?),
+                "$or" => Clause::Or(compile_branches(key, value)?),
+                "$not" => match value {
+                    Value::Object(obj) => Clause::Not(Filter::compile(obj, false)?),
+                    _ => return Err("$not requires an object".to_string()),
                 },
-                "$or" => {
-                    if let Value::Array(or_conditions) = value {
-                        if or_conditions.is_empty() {
-                            return true;
-                        }
-                        
-                        let mut matches_any = false;
-                        for condition in or_conditions {
-                            if let Value::Object(cond_obj) = condition {
-                                let sub_query = Query {
-                                    conditions: cond_obj.clone(),
-                                };
-                                if sub_query.matches(doc) {
-                                    matches_any = true;
-                                    break;
-                                }
-                            }
-                        }
-                        
-                        if !matches_any {
-                            return false;
-                        }
+                "$text" => {
+                    if !top_level {
+                        return Err("$text is only allowed at the top level of a query".to_string());
                     }
-                },
-                "$not" => {
-                    if let Value::Object(not_condition) = value {
-                        let sub_query = Query {
-                            conditions: not_condition.clone(),
-                        };
-                        if sub_query.matches(doc) {
-                            return false;
-                        }
+                    match value.get("$search") {
+                        Some(Value::String(search)) => text = Some(search.clone()),
+                        _ => return Err("$text requires a $search string".to_string()),
                     }
+                    continue;
                 },
-                _ => {
-                    // Regular field condition
-                    if !self.field_matches(key, value, doc) {
-                        return false;
-                    }
-                }
+                "$expr" => Clause::Expr(Expr::parse(value).map_err(|e| format!("Invalid $expr: {}", e))?),
+                op if op.starts_with('$') => return Err(format!("Unknown operator '{}'", op)),
+                field => Clause::Field(field.to_string(), Condition::compile(value)?),
+            };
+            clauses.push(clause);
+        }
+        
+        Ok(Filter { clauses, text })
+    }
+    
+    // Check the clauses against a document, or against a subdocument for
+    // $elemMatch and $pull
+    fn matches(&self, doc: &dyn Fields) -> bool {
+        self.clauses.iter().all(|clause| match clause {
+            Clause::And(branches) => branches.iter().all(|branch| branch.matches(doc)),
+            Clause::Or(branches) => branches.iter().any(|branch| branch.matches(doc)),
+            Clause::Not(filter) => !filter.matches(doc),
+            // Expressions that fail to evaluate match nothing
+            Clause::Expr(expr) => expr.evaluate(doc).is_ok_and(|result| expr::is_truthy(result.as_deref())),
+            Clause::Field(field, condition) => condition.matches(Candidates::Path(doc, field)),
+        })
+    }
+    
+    fn field_condition(&self, field: &str) -> Option<&Condition> {
+        self.clauses.iter().find_map(|clause| match clause {
+            Clause::Field(path, condition) if path == field => Some(condition),
+            _ => None,
+        })
+    }
+    
+    // The geo operators of the field conditions, with their fields
+    fn geo_conditions(&self) -> impl Iterator<Item = (&str, &GeoCondition)> {
+        self.clauses.iter()
+            .filter_map(|clause| match clause {
+                Clause::Field(field, condition) => Some((field, condition)),
+                _ => None,
+            })
+            .flat_map(|(field, condition)| {
+                condition.operators().iter().filter_map(Operator::as_geo).map(move |geo| (field.as_str(), geo))
+            })
+    }
+    
+    fn equality_values(&self, field: &str) -> Option<Vec<&Value>> {
+        match self.field_condition(field)? {
+            Condition::Equals(value) => Some(vec![value]),
+            Condition::Operators(operators) => operators.iter()
+                .find_map(|operator| match operator {
+                    Operator::Eq(value) => Some(vec![value]),
+                    _ => None,
+                })
+                .or_else(|| operators.iter().find_map(|operator| match operator {
+                    Operator::In(values) => Some(values.iter().collect()),
+                    _ => None,
+                })),
+        }
+    }
+    
+    fn range_bounds(&self, field: &str) -> Option<(Bound<&Value>, Bound<&Value>)> {
+        let condition = self.field_condition(field)?;
+        
+        let mut lower = Bound::Unbounded;
+        let mut upper = Bound::Unbounded;
+        
+        for operator in condition.operators() {
+            match operator {
+                Operator::Compare(Comparison::Gt, operand) => lower = Bound::Excluded(operand),
+                Operator::Compare(Comparison::Gte, operand) => lower = Bound::Included(operand),
+                Operator::Compare(Comparison::Lt, operand) => upper = Bound::Excluded(operand),
+                Operator::Compare(Comparison::Lte, operand) => upper = Bound::Included(operand),
+                _ => {}
             }
         }
         
-        true
+        if matches!((lower, upper), (Bound::Unbounded, Bound::Unbounded)) {
+            // Patterns anchored to a literal prefix match a range of strings
+            condition.prefix_range()
+        } else {
+            Some((lower, upper))
+        }
+    }
+    
+    // Whether every document matching this filter also matches `other`,
+    // checking each of its clauses against what this filter requires
+    fn implies(&self, other: &Filter) -> bool {
+        (other.text.is_none() || self.text == other.text)
+            && other.clauses.iter().all(|clause| self.implies_clause(clause))
+    }
+    
+    fn implies_clause(&self, clause: &Clause) -> bool {
+        let implied = match clause {
+            Clause::And(branches) => branches.iter().all(|branch| self.implies(&branch.filter)),
+            Clause::Or(branches) => {
+                // Implying any branch implies them all together, as does a
+                // disjunction of this filter's whose every branch implies it
+                branches.iter().any(|branch| self.implies(&branch.filter))
+                    || self.clauses.iter().any(|own| matches!(own, Clause::Or(own) if own.iter().all(|branch| branch.filter.implies_clause(clause))))
+            },
+            Clause::Not(_) | Clause::Expr(_) => false,
+            Clause::Field(field, condition) => self.field_implies(field, condition),
+        };
+        
+        // So does any branch of a conjunction of this filter's
+        implied || self.clauses.iter().any(|own| match own {
+            Clause::And(branches) => branches.iter().any(|branch| branch.filter.implies_clause(clause)),
+            _ => false,
+        })
     }
     
-    pub fn field_matches(&self, field: &str, condition: &Value, doc: &Document) -> bool {
-        let doc_value = doc.get(field);
+    fn field_implies(&self, field: &str, condition: &Condition) -> bool {
+        // Fixed values imply the condition exactly when they all satisfy it.
+        // Equality to null also matches documents missing the field, so the
+        // condition has to accept those too.
+        if let Some(values) = self.equality_values(field) {
+            let empty = Value::Object(Map::new());
+            return values.into_iter().all(|value| {
+                condition.matches(Candidates::Value(value))
+                    && (!value.is_null() || condition.matches(Candidates::Path(&empty, field)))
+            });
+        }
+        
+        let own = match self.field_condition(field) {
+            Some(own) => own,
+            None => return false,
+        };
+        let operators = match condition {
+            Condition::Operators(operators) => operators,
+            Condition::Equals(_) => return false,
+        };
+        let (lower, upper) = self.range_bounds(field).unwrap_or((Bound::Unbounded, Bound::Unbounded));
         
+        operators.iter().all(|operator| match operator {
+            Operator::Compare(comparison @ (Comparison::Gt | Comparison::Gte), operand) => match lower {
+                Bound::Included(b) => compare_values(b, operand).is_some_and(|o| comparison.holds(o)),
+                Bound::Excluded(b) => compare_values(b, operand).is_some_and(|o| o != Ordering::Less),
+                Bound::Unbounded => false,
+            },
+            Operator::Compare(comparison, operand) => match upper {
+                Bound::Included(b) => compare_values(b, operand).is_some_and(|o| comparison.holds(o)),
+                Bound::Excluded(b) => compare_values(b, operand).is_some_and(|o| o != Ordering::Greater),
+                Bound::Unbounded => false,
+            },
+            // Range conditions only match documents that have the field
+            Operator::Exists(true) if !matches!((lower, upper), (Bound::Unbounded, Bound::Unbounded)) => true,
+            _ => own.operators().iter().any(|own| own.implies(operator)),
+        })
+    }
+}
+
+// The branches of $and or $or, a non-empty array of queries
+fn compile_branches(op: &str, value: &Value) -> Result<Vec<Query>, String> {
+    match value {
+        Value::Array(branches) if !branches.is_empty() => branches.iter()
+            .map(|branch| match branch {
+                Value::Object(obj) => Query::compile(obj.clone(), false),
+                _ => Err(format!("{} branches must be objects", op)),
+            })
+            .collect(),
+        _ => Err(format!("{} requires a non-empty array", op)),
+    }
+}
+
+// A field condition: a literal, or an object of operators
+#[derive(Debug)]
+enum Condition {
+    Equals(Value),
+    Operators(Vec<Operator>),
+}
+
+impl Condition {
+    fn compile(condition: &Value) -> Result<Condition, String> {
         match condition {
-            Value::Object(obj) => {
-                // Check for operators
-                for (op, op_value) in obj {
-                    match op.as_str() {
-                        "$eq" => {
-                            if let Some(value) = doc_value {
-                                if value != op_value {
-                                    return false;
-                                }
-                            } else {
-                                return false;
-                            }
-                        },
-                        "$ne" => {
-                            if let Some(value) = doc_value {
-                                if value == op_value {
-                                    return false;
-                                }
-                            }
-                        },
-                        "$gt" => {
-                            if let Some(value) = doc_value {
-                                if let (Value::Number(a), Value::Number(b)) = (value, op_value) {
-                                    if let (Some(a_f64), Some(b_f64)) = (a.as_f64(), b.as_f64()) {
-                                        if a_f64 <= b_f64 {
-                                            return false;
-                                        }
-                                    } else {
-                                        return false;
-                                    }
-                                } else {
-                                    return false;
-                                }
-                            } else {
-                                return false;
-                            }
-                        },
-                        "$gte" => {
-                            if let Some(value) = doc_value {
-                                if let (Value::Number(a), Value::Number(b)) = (value, op_value) {
-                                    if let (Some(a_f64), Some(b_f64)) = (a.as_f64(), b.as_f64()) {
-                                        if a_f64 < b_f64 {
-                                            return false;
-                                        }
-                                    } else {
-                                        return false;
-                                    }
-                                } else {
-                                    return false;
-                                }
-                            } else {
-                                return false;
-                            }
-                        },
-                        "$lt" => {
-                            if let Some(value) = doc_value {
-                                if let (Value::Number(a), Value::Number(b)) = (value, op_value) {
-                                    if let (Some(a_f64), Some(b_f64)) = (a.as_f64(), b.as_f64()) {
-                                        if a_f64 >= b_f64 {
-                                            return false;
-                                        }
-                                    } else {
-                                        return false;
-                                    }
-                                } else {
-                                    return false;
-                                }
-                            } else {
-                                return false;
-                            }
-                        },
-                        "$lte" => {
-                            if let Some(value) = doc_value {
-                                if let (Value::Number(a), Value::Number(b)) = (value, op_value) {
-                                    if let (Some(a_f64), Some(b_f64)) = (a.as_f64(), b.as_f64()) {
-                                        if a_f64 > b_f64 {
-                                            return false;
-                                        }
-                                    } else {
-                                        return false;
-                                    }
-                                } else {
-                                    return false;
-                                }
-                            } else {
-                                return false;
-                            }
-                        },
-                        "$in" => {
-                            if let Some(value) = doc_value {
-                                if let Value::Array(arr) = op_value {
-                                    if !arr.contains(value) {
-                                        return false;
-                                    }
-                                } else {
-                                    return false;
-                                }
-                            } else {
-                                return false;
-                            }
-                        },
-                        "$nin" => {
-                            if let Some(value) = doc_value {
-                                if let Value::Array(arr) = op_value {
-                                    if arr.contains(value) {
-                                        return false;
-                                    }
-                                } else {
-                                    return false;
-                                }
-                            }
-                        },
-                        "$exists" => {
-                            if let Value::Bool(should_exist) = op_value {
-                                if *should_exist && doc_value.is_none() {
-                                    return false;
-                                }
-                                if !*should_exist && doc_value.is_some() {
-                                    return false;
-                                }
-                            }
-                        },
-                        "$regex" => {
-                            if let Some(Value::String(s)) = doc_value {
-                                if let Value::String(pattern) = op_value {
-                                    // Simple regex implementation
-                                    // In a real implementation, we would use a proper regex engine
-                                    if !s.contains(pattern) {
-                                        return false;
-                                    }
-                                } else {
-                                    return false;
-                                }
-                            } else {
-                                return false;
-                            }
-                        },
-                        _ => {
-                            // Unknown operator
-                            return false;
-                        }
-                    }
+            Value::Object(obj) if obj.keys().any(|key| key.starts_with('$')) => compile_operators(obj).map(Condition::Operators),
+            _ => Ok(Condition::Equals(condition.clone())),
+        }
+    }
+    
+    // A condition holds when any of the field's values, or any element of an
+    // array value, satisfies it
+    fn matches(&self, values: Candidates) -> bool {
+        match self {
+            Condition::Equals(expected) => values.any_or_null(|value| any_element(value, |v| key::equal(v, expected))),
+            Condition::Operators(operators) => operators.iter().all(|operator| operator.matches(values)),
+        }
+    }
+    
+    fn operators(&self) -> &[Operator] {
+        match self {
+            Condition::Operators(operators) => operators,
+            Condition::Equals(_) => &[],
+        }
+    }
+    
+    // Range of strings matched by a $regex anchored to a literal prefix
+    fn prefix_range(&self) -> Option<(Bound<&Value>, Bound<&Value>)> {
+        match self {
+            Condition::Operators(operators) => operators.iter().find_map(|operator| match operator {
+                Operator::Regex(pattern) => pattern.prefix_range(),
+                _ => None,
+            }),
+            Condition::Equals(_) => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Operator {
+    Eq(Value),
+    Ne(Value),
+    Compare(Comparison, Value),
+    In(Vec<Value>),
+    Nin(Vec<Value>),
+    Exists(bool),
+    Geo(GeoCondition),
+    Regex(Pattern),
+    All(Vec<AllOperand>),
+    Size(usize),
+    ElemMatch(ElementFilter),
+}
+
+// A value $all requires the field to hold, or an {$elemMatch: ...} one of its
+// elements must match
+#[derive(Debug)]
+enum AllOperand {
+    Value(Value),
+    ElemMatch(ElementFilter),
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Comparison {
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+}
+
+impl Comparison {
+    fn parse(op: &str) -> Option<Comparison> {
+        match op {
+            "$gt" => Some(Comparison::Gt),
+            "$gte" => Some(Comparison::Gte),
+            "$lt" => Some(Comparison::Lt),
+            "$lte" => Some(Comparison::Lte),
+            _ => None,
+        }
+    }
+    
+    // Whether a value ordered `ordering` relative to the operand satisfies
+    // the range operator
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            Comparison::Gt => ordering == Ordering::Greater,
+            Comparison::Gte => ordering != Ordering::Less,
+            Comparison::Lt => ordering == Ordering::Less,
+            Comparison::Lte => ordering != Ordering::Greater,
+        }
+    }
+}
+
+// Compile an object of operators, rejecting unknown operators and operands
+// of the wrong type
+fn compile_operators(obj: &Map<String, Value>) -> Result<Vec<Operator>, String> {
+    let mut operators = Vec::with_capacity(obj.len());
+    
+    for (op, operand) in obj {
+        let operator = match op.as_str() {
+            "$eq" => Operator::Eq(operand.clone()),
+            "$ne" => Operator::Ne(operand.clone()),
+            "$in" => Operator::In(array_operand(op, operand)?.clone()),
+            "$nin" => Operator::Nin(array_operand(op, operand)?.clone()),
+            "$exists" => match operand {
+                Value::Bool(should_exist) => Operator::Exists(*should_exist),
+                _ => return Err("$exists requires a boolean".to_string()),
+            },
+            "$maxDistance" | "$minDistance" => {
+                // Read by $near
+                if !operand.is_number() {
+                    return Err(format!("{} requires a number", op));
                 }
-                
-                true
+                continue;
+            },
+            "$regex" => {
+                let pattern = operand.as_str().ok_or("$regex requires a string")?;
+                let options = match obj.get("$options") {
+                    Some(Value::String(options)) => options.as_str(),
+                    Some(_) => return Err("$options requires a string".to_string()),
+                    None => "",
+                };
+                Operator::Regex(Pattern::compile(pattern, options)?)
             },
-            _ => {
-                // Simple equality check
-                if let Some(value) = doc_value {
-                    value == condition
-                } else {
-                    false
+            "$options" => {
+                // Read by $regex
+                if !obj.contains_key("$regex") {
+                    return Err("$options requires $regex".to_string());
                 }
-            }
+                continue;
+            },
+            "$all" => Operator::All(array_operand(op, operand)?.iter()
+                .map(|item| match item.get("$elemMatch") {
+                    Some(condition) => ElementFilter::compile(condition).map(AllOperand::ElemMatch),
+                    None => Ok(AllOperand::Value(item.clone())),
+                })
+                .collect::<Result<_, String>>()?),
+            "$size" => match operand.as_f64() {
+                Some(size) if size >= 0.0 && size.fract() == 0.0 => Operator::Size(size as usize),
+                _ => return Err("$size requires a non-negative integer".to_string()),
+            },
+            "$elemMatch" => match operand {
+                Value::Object(_) => Operator::ElemMatch(ElementFilter::compile(operand)?),
+                _ => return Err("$elemMatch requires an object".to_string()),
+            },
+            op if geo::is_geo_operator(op) => Operator::Geo(GeoCondition::parse(op, operand, obj)?),
+            op => match Comparison::parse(op) {
+                Some(comparison) => Operator::Compare(comparison, operand.clone()),
+                None => return Err(format!("Unknown operator '{}'", op)),
+            },
+        };
+        operators.push(operator);
+    }
+    
+    Ok(operators)
+}
+
+fn array_operand<'a>(op: &str, operand: &'a Value) -> Result<&'a Vec<Value>, String> {
+    operand.as_array().ok_or_else(|| format!("{} requires an array", op))
+}
+
+impl Operator {
+    fn as_geo(&self) -> Option<&GeoCondition> {
+        match self {
+            Operator::Geo(geo) => Some(geo),
+            _ => None,
         }
     }
     
-    pub fn get_field_value(&self, field: &str) -> Option<&Value> {
-        self.conditions.get(field)
+    // Whether every value satisfying this operator satisfies `other`, for
+    // operators the range bounds of a condition leave out
+    fn implies(&self, other: &Operator) -> bool {
+        match (self, other) {
+            (Operator::Ne(a), Operator::Ne(b)) => key::equal(a, b),
+            (Operator::Ne(a), Operator::Nin(b)) => b.iter().all(|b| key::equal(a, b)),
+            (Operator::Nin(a), Operator::Ne(b)) => a.iter().any(|a| key::equal(a, b)),
+            (Operator::Nin(a), Operator::Nin(b)) => b.iter().all(|b| a.iter().any(|a| key::equal(a, b))),
+            (Operator::Exists(a), Operator::Exists(b)) => a == b,
+            (Operator::Size(a), Operator::Size(b)) => a == b,
+            _ => false,
+        }
     }
     
-    pub fn has_simple_equality(&self, field: &str) -> bool {
-        if let Some(value) = self.conditions.get(field) {
-            !value.is_object()
-        } else {
-            false
+    fn matches(&self, values: Candidates) -> bool {
+        match self {
+            Operator::Eq(operand) => values.any_or_null(|value| any_element(value, |v| key::equal(v, operand))),
+            Operator::Ne(operand) => !values.any_or_null(|value| any_element(value, |v| key::equal(v, operand))),
+            Operator::Compare(comparison, operand) => values.any(|value| any_element(value, |v| {
+                compare_values(v, operand).is_some_and(|ordering| comparison.holds(ordering))
+            })),
+            Operator::In(operands) => values.any_or_null(|value| any_element(value, |v| operands.iter().any(|item| key::equal(item, v)))),
+            Operator::Nin(operands) => !values.any_or_null(|value| any_element(value, |v| operands.iter().any(|item| key::equal(item, v)))),
+            Operator::Exists(should_exist) => values.exists() == *should_exist,
+            Operator::Geo(geo) => values.any(|value| geo.matches(value)),
+            Operator::Regex(pattern) => values.any(|value| any_element(value, |v| pattern.is_match(v))),
+            // Every operand must be held, and $all of nothing matches nothing
+            Operator::All(operands) => !operands.is_empty() && operands.iter().all(|operand| match operand {
+                AllOperand::Value(expected) => values.any(|value| any_element(value, |v| key::equal(v, expected))),
+                AllOperand::ElemMatch(filter) => values.any(|value| filter.matches_element_of(value)),
+            }),
+            Operator::Size(size) => values.any(|value| matches!(value, Value::Array(items) if items.len() == *size)),
+            Operator::ElemMatch(filter) => values.any(|value| filter.matches_element_of(value)),
+        }
+    }
+}
+
+// A condition on a single array element, as $elemMatch and $pull apply it:
+// operator objects apply to the element itself, other objects are queries
+// over subdocument elements and anything else must equal the element
+#[derive(Debug)]
+pub struct ElementFilter(ElementCondition);
+
+#[derive(Debug)]
+enum ElementCondition {
+    Operators(Vec<Operator>),
+    Query(Filter),
+    Equals(Value),
+}
+
+impl ElementFilter {
+    pub fn compile(condition: &Value) -> Result<ElementFilter, String> {
+        let condition = match condition {
+            Value::Object(obj) if is_operator_object(obj) => ElementCondition::Operators(compile_operators(obj)?),
+            Value::Object(obj) => ElementCondition::Query(Filter::compile(obj, false)?),
+            _ => ElementCondition::Equals(condition.clone()),
+        };
+        Ok(ElementFilter(condition))
+    }
+    
+    pub fn matches(&self, element: &Value) -> bool {
+        match &self.0 {
+            ElementCondition::Operators(operators) => {
+                operators.iter().all(|operator| operator.matches(Candidates::Value(element)))
+            },
+            ElementCondition::Query(filter) => element.is_object() && filter.matches(element),
+            ElementCondition::Equals(expected) => key::equal(element, expected),
         }
     }
     
-    pub fn has_equality_operator(&self, field: &str) -> Option<&Value> {
-        if let Some(Value::Object(obj)) = self.conditions.get(field) {
-            obj.get("$eq")
-        } else {
-            None
+    // Whether the value is an array with an element matching the condition
+    fn matches_element_of(&self, value: &Value) -> bool {
+        matches!(value, Value::Array(items) if items.iter().any(|item| self.matches(item)))
+    }
+}
+
+// Whether an object is a set of operators on a value rather than a query,
+// which may combine its field conditions with logical operators
+fn is_operator_object(obj: &Map<String, Value>) -> bool {
+    !obj.is_empty() && obj.keys().all(|key| {
+        key.starts_with('$') && !matches!(key.as_str(), "$and" | "$or" | "$not" | "$expr" | "$text")
+    })
+}
+
+// The values a field condition is checked against: those a path resolves to
+// within a document, or a single value
+#[derive(Clone, Copy)]
+enum Candidates<'a> {
+    Path(&'a dyn Fields, &'a str),
+    Value(&'a Value),
+}
+
+impl Candidates<'_> {
+    fn any(&self, mut predicate: impl FnMut(&Value) -> bool) -> bool {
+        match self {
+            Candidates::Path(doc, path) => doc.any_value(path, &mut |value| predicate(value)),
+            Candidates::Value(value) => predicate(value),
+        }
+    }
+    
+    // Like `any`, but a path resolving to nothing is checked as null, so
+    // equality to null also matches documents missing the field. Indexes key
+    // those documents under null the same way.
+    fn any_or_null(&self, mut predicate: impl FnMut(&Value) -> bool) -> bool {
+        let mut found = false;
+        self.any(|value| {
+            found = true;
+            predicate(value)
+        }) || (!found && predicate(&Value::Null))
+    }
+    
+    fn exists(&self) -> bool {
+        self.any(|_| true)
+    }
+}
+
+// Replace each "$$name" string within a value by the variable's value,
+// wrapped as {"$literal": value} when the result is read as an expression,
+// as it is inside $expr
+pub fn bind_variables(value: &Value, variables: &Map<String, Value>, literal: bool) -> Value {
+    match value {
+        Value::String(s) => match s.strip_prefix("$$").and_then(|reference| variable_value(reference, variables)) {
+            Some(bound) if literal => {
+                let mut wrapped = Map::new();
+                wrapped.insert("$literal".to_string(), bound);
+                Value::Object(wrapped)
+            },
+            Some(bound) => bound,
+            None => value.clone(),
+        },
+        Value::Array(items) => Value::Array(items.iter()
+            .map(|item| bind_variables(item, variables, literal))
+            .collect()),
+        Value::Object(obj) => Value::Object(obj.iter()
+            .map(|(key, item)| (key.clone(), bind_variables(item, variables, literal || key == "$expr")))
+            .collect()),
+        _ => value.clone(),
+    }
+}
+
+// Value of a "name" or "name.path" variable reference, null where the path is
+// missing, or `None` if the variable is not bound
+fn variable_value(reference: &str, variables: &Map<String, Value>) -> Option<Value> {
+    match reference.split_once('.') {
+        Some((name, path)) => {
+            let bound = variables.get(name)?;
+            Some(bound.as_object().and_then(|obj| find::get_path(obj, path)).cloned().unwrap_or(Value::Null))
+        },
+        None => variables.get(reference).cloned(),
+    }
+}
+
+// True if the value, or for arrays any of its elements, satisfies the predicate
+fn any_element(value: &Value, predicate: impl Fn(&Value) -> bool) -> bool {
+    if predicate(value) {
+        return true;
+    }
+    
+    match value {
+        Value::Array(items) => items.iter().any(predicate),
+        _ => false,
+    }
+}
+
+// Order two values of the same type by the canonical value order. Values of
+// different types are not comparable, as in ordered index scans.
+pub fn compare_values(a: &Value, b: &Value) -> Option<Ordering> {
+    if key::same_type(a, b) {
+        Some(key::compare(a, b))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    fn query(value: Value) -> Result<Query, String> {
+        match value {
+            Value::Object(obj) => Query::try_from(obj),
+            _ => panic!("queries are objects"),
+        }
+    }
+    
+    #[test]
+    fn text_is_only_allowed_at_the_top_level() {
+        let top = query(json!({"$text": {"$search": "rust"}, "a": 1})).unwrap();
+        assert!(top.has_text());
+        assert_eq!(top.text_search(), Some("rust"));
+        
+        for nested in [
+            json!({"$or": [{"$text": {"$search": "rust"}}, {"a": 1}]}),
+            json!({"$and": [{"$text": {"$search": "rust"}}]}),
+            json!({"$not": {"$text": {"$search": "rust"}}}),
+            json!({"items": {"$elemMatch": {"$text": {"$search": "rust"}}}}),
+            json!({"items": {"$all": [{"$elemMatch": {"$text": {"$search": "rust"}}}]}}),
+        ] {
+            assert_eq!(query(nested).unwrap_err(), "$text is only allowed at the top level of a query");
         }
+        assert!(ElementFilter::compile(&json!({"$text": {"$search": "rust"}})).is_err());
+    }
+    
+    #[test]
+    fn null_equality_matches_missing_fields() {
+        let missing = json!({"g": 1});
+        let null = json!({"f": null});
+        let set = json!({"f": 1});
+        let matches = |condition: Value, doc: &Value| query(json!({"f": condition})).unwrap().matches(doc);
+        
+        for condition in [json!(null), json!({"$eq": null}), json!({"$in": [null, 2]})] {
+            assert!(matches(condition.clone(), &missing), "{}", condition);
+            assert!(matches(condition.clone(), &null), "{}", condition);
+            assert!(!matches(condition.clone(), &set), "{}", condition);
+        }
+        for condition in [json!({"$ne": null}), json!({"$nin": [null, 2]})] {
+            assert!(!matches(condition.clone(), &missing), "{}", condition);
+            assert!(!matches(condition.clone(), &null), "{}", condition);
+            assert!(matches(condition.clone(), &set), "{}", condition);
+        }
+        
+        // Other values still need the field
+        assert!(!matches(json!(1), &missing));
+        assert!(matches(json!({"$ne": 1}), &missing));
+        assert!(!matches(json!({"$in": [1]}), &missing));
+    }
+    
+    #[test]
+    fn planner_helpers_read_the_compiled_conditions() {
+        let q = query(json!({
+            "a": 1, "b": {"$eq": 2, "$in": [3]}, "c": {"$in": [4, 5]}, "d": {"$gt": 1, "$lte": 9}, "e": {"$regex": "^ab"},
+            "loc": {"$near": [0, 0], "$maxDistance": 2}, "$and": [{"f": 1}, {"g": 2}], "$or": [{"h": 1}],
+        })).unwrap();
+        
+        assert_eq!(q.equality_values("a"), Some(vec![&json!(1)]));
+        assert_eq!(q.equality_values("b"), Some(vec![&json!(2)]));
+        assert_eq!(q.equality_values("c"), Some(vec![&json!(4), &json!(5)]));
+        assert_eq!(q.equality_values("d"), None);
+        assert_eq!(q.range_bounds("d"), Some((Bound::Excluded(&json!(1)), Bound::Included(&json!(9)))));
+        assert_eq!(q.range_bounds("e"), Some((Bound::Included(&json!("ab")), Bound::Excluded(&json!("ac")))));
+        assert_eq!(q.range_bounds("a"), None);
+        
+        assert!(q.has_near());
+        assert!(q.geo_condition("loc").is_some_and(GeoCondition::is_near));
+        assert!(q.geo_condition("a").is_none());
+        assert!(!q.has_text());
+        
+        let branches: Vec<&Query> = q.and_branches().collect();
+        assert_eq!(branches.len(), 2);
+        assert_eq!(branches[1].equality_values("g"), Some(vec![&json!(2)]));
+        assert_eq!(q.or_branches().map(<[Query]>::len), Some(1));
+    }
+    
+    #[test]
+    fn implied_filters() {
+        let implies = |a: Value, b: Value| query(a).unwrap().implies(&query(b).unwrap());
+        
+        assert!(implies(json!({"a": 5}), json!({"a": {"$gt": 3}})));
+        assert!(implies(json!({"a": {"$gt": 5}}), json!({"a": {"$gte": 5, "$exists": true}})));
+        assert!(!implies(json!({"a": {"$gt": 5}}), json!({"a": {"$lt": 9}})));
+        assert!(implies(json!({"a": {"$ne": 1}}), json!({"a": {"$ne": 1}})));
+        assert!(implies(json!({"a": {"$nin": [1, 2]}}), json!({"a": {"$ne": 2}})));
+        assert!(!implies(json!({"a": {"$nin": [1]}}), json!({"a": {"$nin": [1, 2]}})));
+        assert!(implies(json!({"a": {"$exists": false}}), json!({"a": {"$exists": false}})));
+        
+        // Logical clauses on either side
+        assert!(implies(json!({"a": 1}), json!({"$or": [{"a": 1}, {"b": 1}]})));
+        assert!(implies(json!({"$or": [{"a": 1}, {"a": 2}]}), json!({"$or": [{"a": {"$lt": 3}}]})));
+        assert!(implies(json!({"$and": [{"a": 1}, {"b": 2}]}), json!({"b": 2, "$and": [{"a": {"$gte": 1}}]})));
+        assert!(!implies(json!({"$or": [{"a": 1}, {"b": 1}]}), json!({"a": 1})));
+        assert!(!implies(json!({"a": 1}), json!({"$not": {"a": 2}})));
+        
+        assert!(implies(json!({"$text": {"$search": "x"}, "a": 1}), json!({"$text": {"$search": "x"}})));
+        assert!(!implies(json!({"a": 1}), json!({"$text": {"$search": "x"}})));
+    }
+    
+    #[test]
+    fn text_requires_a_search_string() {
+        assert_eq!(query(json!({"$text": {}})).unwrap_err(), "$text requires a $search string");
+        assert_eq!(query(json!({"$text": {"$search": 1}})).unwrap_err(), "$text requires a $search string");
+    }
+    
+    fn array_documents() -> Vec<Value> {
+        vec![
+            json!({"id": "a", "tags": ["a", "b", "c"], "items": [{"p": 5, "q": 1}, {"p": 20, "q": 0}], "m": [[1, 2], [3]]}),
+            json!({"id": "b", "tags": ["b"], "items": [{"p": 5, "q": 0}, {"p": 15, "q": 2}], "m": [1, 2]}),
+            json!({"id": "c", "tags": "a", "items": {"p": 30, "q": 2}}),
+            json!({"id": "d", "tags": [], "scores": [82, 90, 70]}),
+        ]
+    }
+    
+    fn matching(filter: Value) -> Vec<String> {
+        let filter = query(filter).unwrap();
+        array_documents().iter()
+            .filter(|doc| filter.matches(*doc))
+            .map(|doc| doc["id"].as_str().unwrap().to_string())
+            .collect()
+    }
+    
+    #[test]
+    fn conditions_match_array_elements() {
+        assert_eq!(matching(json!({"tags": "a"})), ["a", "c"]);
+        assert_eq!(matching(json!({"tags": {"$in": ["c", "z"]}})), ["a"]);
+        assert_eq!(matching(json!({"tags": {"$ne": "b"}})), ["c", "d"]);
+        assert_eq!(matching(json!({"tags": ["b"]})), ["b"]);
+        assert_eq!(matching(json!({"m": [3]})), ["a"]);
+        assert_eq!(matching(json!({"m": 2})), ["b"]);
+    }
+    
+    #[test]
+    fn all_and_size() {
+        assert_eq!(matching(json!({"tags": {"$all": ["a", "c"]}})), ["a"]);
+        assert_eq!(matching(json!({"tags": {"$all": ["a"]}})), ["a", "c"]);
+        assert!(matching(json!({"tags": {"$all": []}})).is_empty());
+        assert_eq!(matching(json!({"m": {"$all": [[3], [1, 2]]}})), ["a"]);
+        assert_eq!(matching(json!({"items": {"$all": [{"$elemMatch": {"p": 5}}, {"$elemMatch": {"p": 20}}]}})), ["a"]);
+        
+        assert_eq!(matching(json!({"tags": {"$size": 1}})), ["b"]);
+        assert_eq!(matching(json!({"tags": {"$size": 0}})), ["d"]);
+        assert!(matching(json!({"items": {"$size": 1}})).is_empty());
+        
+        assert!(query(json!({"tags": {"$size": "1"}})).is_err());
+        assert!(query(json!({"tags": {"$size": -1}})).is_err());
+        assert!(query(json!({"tags": {"$all": "a"}})).is_err());
+    }
+    
+    #[test]
+    fn elem_match_needs_one_element_for_every_condition() {
+        assert_eq!(matching(json!({"items": {"$elemMatch": {"p": {"$gt": 10}, "q": {"$gte": 1}}}})), ["b"]);
+        assert_eq!(matching(json!({"items": {"$elemMatch": {"p": 5, "q": 1}}})), ["a"]);
+        assert_eq!(matching(json!({"items": {"$elemMatch": {"$or": [{"p": 30}, {"q": 2}]}}})), ["b"]);
+        assert_eq!(matching(json!({"scores": {"$elemMatch": {"$gte": 80, "$lt": 85}}})), ["d"]);
+        assert!(matching(json!({"scores": {"$elemMatch": {"$gte": 91}}})).is_empty());
+        assert_eq!(matching(json!({"tags": {"$elemMatch": {"$regex": "^c"}}})), ["a"]);
+        
+        // Without $elemMatch each condition may be met by a different element
+        assert_eq!(matching(json!({"scores": {"$gte": 85, "$lt": 75}})), ["d"]);
+        
+        assert!(query(json!({"items": {"$elemMatch": 5}})).is_err());
+    }
+    
+    #[test]
+    fn element_filters_take_values_or_conditions() {
+        let filter = ElementFilter::compile(&json!({"$gte": 80, "$lt": 85})).unwrap();
+        assert!(filter.matches(&json!(82)));
+        assert!(!filter.matches(&json!(90)));
+        
+        let filter = ElementFilter::compile(&json!({"p": {"$gt": 10}})).unwrap();
+        assert!(filter.matches(&json!({"p": 20, "q": 0})));
+        assert!(!filter.matches(&json!({"p": 5})));
+        assert!(!filter.matches(&json!(20)));
+        
+        let filter = ElementFilter::compile(&json!("a")).unwrap();
+        assert!(filter.matches(&json!("a")));
+        assert!(!filter.matches(&json!(["a"])));
+        
+        assert!(ElementFilter::compile(&json!({"$bogus": 1})).is_err());
+    }
+    
+    #[test]
+    fn rejects_malformed_queries() {
+        for (filter, error) in [
+            (json!({"a": {"$foo": 1}}), "Unknown operator '$foo'"),
+            (json!({"$foo": 1}), "Unknown operator '$foo'"),
+            (json!({"a": {"$gt": 1, "b": 2}}), "Unknown operator 'b'"),
+            (json!({"a": {"$in": 1}}), "$in requires an array"),
+            (json!({"a": {"$nin": "x"}}), "$nin requires an array"),
+            (json!({"a": {"$all": {}}}), "$all requires an array"),
+            (json!({"a": {"$exists": "yes"}}), "$exists requires a boolean"),
+            (json!({"a": {"$size": 1.5}}), "$size requires a non-negative integer"),
+            (json!({"a": {"$elemMatch": 1}}), "$elemMatch requires an object"),
+            (json!({"a": {"$elemMatch": {"b": {"$bad": 1}}}}), "Unknown operator '$bad'"),
+            (json!({"a": {"$regex": 1}}), "$regex requires a string"),
+            (json!({"a": {"$options": "i"}}), "$options requires $regex"),
+            (json!({"$or": []}), "$or requires a non-empty array"),
+            (json!({"$and": [1]}), "$and branches must be objects"),
+            (json!({"$or": [{"a": {"$in": 3}}]}), "$in requires an array"),
+            (json!({"$not": []}), "$not requires an object"),
+            (json!({"loc": {"$near": [0, 0], "$maxDistance": "far"}}), "$maxDistance requires a number"),
+        ] {
+            assert_eq!(query(filter.clone()).unwrap_err(), error, "{}", filter);
+        }
+        
+        for (filter, prefix) in [
+            (json!({"a": {"$regex": "("}}), "Invalid $regex"),
+            (json!({"a": {"$expr": 1}}), "Unknown operator"),
+            (json!({"$expr": {"$bogus": []}}), "Invalid $expr"),
+            (json!({"loc": {"$geoWithin": {"$box": [1]}}}), "Invalid $geoWithin operand"),
+        ] {
+            let error = query(filter.clone()).unwrap_err();
+            assert!(error.starts_with(prefix), "{} -> {}", filter, error);
+        }
+        
+        // Deserializing reports the same errors
+        let error = serde_json::from_value::<Query>(json!({"a": {"$in": 1}})).unwrap_err();
+        assert!(error.to_string().contains("$in requires an array"));
+    }
+    
+    #[test]
+    fn literal_objects_compare_whole() {
+        let doc = json!({"o": {"b": 1}, "s": [{"b": 1}], "e": {}});
+        let matches = |filter: Value| query(filter).unwrap().matches(&doc);
+        
+        assert!(matches(json!({"o": {"b": 1}})));
+        assert!(!matches(json!({"o": {"b": 1, "c": 2}})));
+        assert!(matches(json!({"s": {"b": 1}})));
+        assert!(!matches(json!({"o": {}})));
+        assert!(matches(json!({"e": {}})));
+    }
+    
+    #[test]
+    fn logical_clauses_nest() {
+        let a = json!({"o": {"b": 1}, "s": [{"b": 1}]});
+        let b = json!({"o": {"b": 2}});
+        let matches = |filter: Value, doc: &Value| query(filter).unwrap().matches(doc);
+        
+        let not = json!({"$not": {"o.b": 1}});
+        assert!(!matches(not.clone(), &a));
+        assert!(matches(not, &b));
+        
+        let nested = json!({"$and": [{"$or": [{"o.b": 2}, {"s.b": 1}]}, {"o.b": {"$gte": 1}}]});
+        assert!(matches(nested.clone(), &a));
+        assert!(matches(nested, &b));
+        
+        let expr = json!({"$expr": {"$gt": ["$o.b", 1]}});
+        assert!(!matches(expr.clone(), &a));
+        assert!(matches(expr, &b));
+    }
+    
+    #[test]
+    fn bound_variables_are_validated() {
+        let filter = query(json!({"a": "$$x"})).unwrap();
+        let variables = |value: Value| match value {
+            Value::Object(obj) => obj,
+            _ => unreachable!(),
+        };
+        
+        let bound = filter.bind(&variables(json!({"x": 3}))).unwrap();
+        assert!(bound.matches(&json!({"a": 3})));
+        assert!(!bound.matches(&json!({"a": 4})));
+        
+        // Bound values are compiled with the query, so they must be valid
+        let bound = filter.bind(&variables(json!({"x": {"$gt": 1}}))).unwrap();
+        assert!(bound.matches(&json!({"a": 3})));
+        assert_eq!(filter.bind(&variables(json!({"x": {"$bad": 1}}))).unwrap_err(), "Unknown operator '$bad'");
     }
 }
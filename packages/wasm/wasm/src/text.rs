@@ -0,0 +1,319 @@
+use std::collections::{HashMap, HashSet};
+use serde_json::Value;
+
+// BM25 tuning constants
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+const STOP_WORDS: &[&str] = &[
+    "a", "about", "above", "after", "again", "against", "all", "am", "an", "and", "any", "are", "as",
+    "at", "be", "because", "been", "before", "being", "below", "between", "both", "but", "by", "can",
+    "did", "do", "does", "doing", "down", "during", "each", "few", "for", "from", "further", "had",
+    "has", "have", "having", "he", "her", "here", "hers", "herself", "him", "himself", "his", "how",
+    "i", "if", "in", "into", "is", "it", "its", "itself", "just", "me", "more", "most", "my", "myself",
+    "no", "nor", "not", "now", "of", "off", "on", "once", "only", "or", "other", "our", "ours",
+    "ourselves", "out", "over", "own", "same", "she", "should", "so", "some", "such", "than", "that",
+    "the", "their", "theirs", "them", "themselves", "then", "there", "these", "they", "this", "those",
+    "through", "to", "too", "under", "until", "up", "very", "was", "we", "were", "what", "when",
+    "where", "which", "while", "who", "whom", "why", "will", "with", "you", "your", "yours",
+    "yourself", "yourselves",
+];
+
+// Inverted index over the string content of one or more fields, scored with BM25
+#[derive(Debug, Clone, Default)]
+pub struct TextIndex {
+    stemming: bool,
+    // term -> document_id -> term frequency
+    postings: HashMap<String, HashMap<String, u32>>,
+    // document_id -> number of indexed terms
+    doc_lengths: HashMap<String, u32>,
+    total_length: u64,
+}
+
+impl TextIndex {
+    pub fn new(stemming: bool) -> Self {
+        TextIndex {
+            stemming,
+            ..TextIndex::default()
+        }
+    }
+    
+    pub fn add(&mut self, id: &str, values: &[&Value]) {
+        let terms = self.tokenize_values(values);
+        if terms.is_empty() {
+            return;
+        }
+        
+        self.doc_lengths.insert(id.to_string(), terms.len() as u32);
+        self.total_length += terms.len() as u64;
+        
+        for term in terms {
+            *self.postings.entry(term).or_default().entry(id.to_string()).or_insert(0) += 1;
+        }
+    }
+    
+    pub fn remove(&mut self, id: &str, values: &[&Value]) {
+        let length = match self.doc_lengths.remove(id) {
+            Some(length) => length,
+            None => return,
+        };
+        self.total_length -= length as u64;
+        
+        for term in self.tokenize_values(values) {
+            if let Some(docs) = self.postings.get_mut(&term) {
+                docs.remove(id);
+                if docs.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+    
+    pub fn clear(&mut self) {
+        self.postings.clear();
+        self.doc_lengths.clear();
+        self.total_length = 0;
+    }
+    
+    // Number of distinct terms and of (term, document) postings
+    pub fn stats(&self) -> (usize, usize) {
+        (self.postings.len(), self.postings.values().map(HashMap::len).sum())
+    }
+    
+    // Upper bound on the number of documents a search returns
+    pub fn estimate(&self, search: &str) -> usize {
+        let (terms, _) = self.parse_search(search);
+        let postings: usize = terms.iter()
+            .filter_map(|term| self.postings.get(term))
+            .map(HashMap::len)
+            .sum();
+        postings.min(self.doc_lengths.len())
+    }
+    
+    // Ids of the documents containing any of the search terms and none of the
+    // negated (`-term`) ones
+    pub fn search(&self, search: &str) -> Vec<&str> {
+        self.score(search).into_iter().map(|(id, _)| id).collect()
+    }
+    
+    // BM25 score of every document returned by `search`
+    pub fn score(&self, search: &str) -> Vec<(&str, f64)> {
+        let (terms, negated) = self.parse_search(search);
+        
+        let doc_count = self.doc_lengths.len() as f64;
+        let avg_length = if self.doc_lengths.is_empty() {
+            0.0
+        } else {
+            self.total_length as f64 / doc_count
+        };
+        
+        let mut scores: HashMap<&str, f64> = HashMap::new();
+        
+        for term in &terms {
+            let docs = match self.postings.get(term) {
+                Some(docs) => docs,
+                None => continue,
+            };
+            
+            let df = docs.len() as f64;
+            let idf = (1.0 + (doc_count - df + 0.5) / (df + 0.5)).ln();
+            
+            for (id, &tf) in docs {
+                let tf = tf as f64;
+                let length = self.doc_lengths[id] as f64;
+                let norm = K1 * (1.0 - B + B * length / avg_length);
+                *scores.entry(id.as_str()).or_insert(0.0) += idf * tf * (K1 + 1.0) / (tf + norm);
+            }
+        }
+        
+        for term in &negated {
+            if let Some(docs) = self.postings.get(term) {
+                for id in docs.keys() {
+                    scores.remove(id.as_str());
+                }
+            }
+        }
+        
+        scores.into_iter().collect()
+    }
+    
+    fn parse_search(&self, search: &str) -> (HashSet<String>, HashSet<String>) {
+        let mut terms = HashSet::new();
+        let mut negated = HashSet::new();
+        
+        for word in search.split_whitespace() {
+            match word.strip_prefix('-') {
+                Some(word) => negated.extend(self.tokenize(word)),
+                None => terms.extend(self.tokenize(word)),
+            }
+        }
+        
+        (terms, negated)
+    }
+    
+    fn tokenize_values(&self, values: &[&Value]) -> Vec<String> {
+        let mut terms = Vec::new();
+        
+        for value in values {
+            match value {
+                Value::String(s) => terms.extend(self.tokenize(s)),
+                Value::Array(items) => {
+                    for item in items {
+                        if let Value::String(s) = item {
+                            terms.extend(self.tokenize(s));
+                        }
+                    }
+                },
+                _ => {}
+            }
+        }
+        
+        terms
+    }
+    
+    // Split on anything that isn't alphanumeric, lowercase, drop stop words and
+    // optionally stem
+    fn tokenize<'a>(&'a self, text: &'a str) -> impl Iterator<Item = String> + 'a {
+        text.split(|c: char| !c.is_alphanumeric())
+            .filter(|word| !word.is_empty())
+            .map(str::to_lowercase)
+            .filter(|word| !STOP_WORDS.contains(&word.as_str()))
+            .map(move |word| if self.stemming { stem(&word) } else { word })
+    }
+}
+
+// Light English suffix stripping, enough to conflate plurals and common
+// verb forms ("indexes", "indexed", "indexing" -> "index")
+fn stem(word: &str) -> String {
+    if word.len() <= 3 || !word.is_ascii() {
+        return word.to_string();
+    }
+    
+    let mut stem = word.to_string();
+    
+    // Plurals
+    if stem.ends_with("sses") || ["xes", "ches", "shes"].iter().any(|s| stem.ends_with(s)) {
+        stem.truncate(stem.len() - 2);
+    } else if stem.ends_with("ies") || stem.ends_with("ied") {
+        stem.truncate(stem.len() - 3);
+        stem.push('y');
+    } else if stem.ends_with('s') && !stem.ends_with("ss") && !stem.ends_with("us") && !stem.ends_with("is") {
+        stem.pop();
+    }
+    
+    // Verb forms and adverbs, keeping at least three letters and a vowel
+    for suffix in ["ing", "ed", "ly"] {
+        if let Some(base) = stem.strip_suffix(suffix) {
+            if base.len() >= 3 && base.contains(|c| "aeiouy".contains(c)) {
+                let bytes = base.as_bytes();
+                let last = bytes[bytes.len() - 1];
+                // Undo consonant doubling: "running" -> "run"
+                let doubled = last == bytes[bytes.len() - 2] && !b"lsz".contains(&last);
+                stem.truncate(if doubled { base.len() - 1 } else { base.len() });
+            }
+            break;
+        }
+    }
+    
+    // Silent e, so "note", "notes" and "noted" agree
+    if stem.len() > 3 && stem.ends_with('e') {
+        stem.pop();
+    }
+    
+    stem
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    fn index(stemming: bool, texts: &[(&str, &str)]) -> TextIndex {
+        let mut index = TextIndex::new(stemming);
+        for (id, text) in texts {
+            index.add(id, &[&json!(text)]);
+        }
+        index
+    }
+    
+    fn sorted(mut ids: Vec<&str>) -> Vec<&str> {
+        ids.sort();
+        ids
+    }
+    
+    #[test]
+    fn stemming_conflates_word_forms() {
+        for (word, stemmed) in [
+            ("indexes", "index"),
+            ("indexed", "index"),
+            ("indexing", "index"),
+            ("running", "run"),
+            ("stories", "story"),
+            ("notes", "not"),
+            ("noted", "not"),
+            ("status", "status"),
+            ("cats", "cat"),
+            ("bed", "bed"),
+            ("naïve", "naïve"),
+        ] {
+            assert_eq!(stem(word), stemmed, "{}", word);
+        }
+    }
+    
+    #[test]
+    fn search_skips_stop_words_and_negated_terms() {
+        let index = index(true, &[
+            ("a", "Indexing in Rust: rust rust indexes"),
+            ("b", "The best pasta is indexed nowhere"),
+            ("c", "Rust notes"),
+        ]);
+        
+        assert_eq!(sorted(index.search("rust")), ["a", "c"]);
+        assert_eq!(sorted(index.search("INDEX")), ["a", "b"]);
+        assert_eq!(index.search("index -rust"), ["b"]);
+        assert!(index.search("the is").is_empty());
+        assert!(index.search("").is_empty());
+        assert_eq!(index.estimate("rust pasta"), 3);
+        assert_eq!(index.estimate("missing"), 0);
+    }
+    
+    #[test]
+    fn scores_favour_frequent_terms_in_short_documents() {
+        let index = index(false, &[
+            ("a", "rust rust rust"),
+            ("b", "rust and a great many other words"),
+            ("c", "nothing relevant"),
+        ]);
+        
+        let scores: HashMap<&str, f64> = index.score("rust").into_iter().collect();
+        assert_eq!(scores.len(), 2);
+        assert!(scores["a"] > scores["b"]);
+        assert!(scores["b"] > 0.0);
+    }
+    
+    #[test]
+    fn removing_a_document_drops_its_postings() {
+        let mut index = index(false, &[("a", "rust ownership"), ("b", "rust borrowing")]);
+        assert_eq!(index.stats(), (3, 4));
+        
+        index.remove("a", &[&json!("rust ownership")]);
+        assert_eq!(index.stats(), (2, 2));
+        assert_eq!(index.search("rust ownership"), ["b"]);
+        
+        // Documents without any indexed term are never added, so removing
+        // them is a no-op
+        index.add("c", &[&json!(42), &json!("the")]);
+        index.remove("c", &[&json!("the")]);
+        assert_eq!(index.stats(), (2, 2));
+    }
+    
+    #[test]
+    fn arrays_of_strings_are_indexed() {
+        let mut index = TextIndex::new(false);
+        index.add("a", &[&json!(["red", 1, "green"]), &json!("blue")]);
+        
+        assert_eq!(index.search("green"), ["a"]);
+        assert_eq!(index.search("blue"), ["a"]);
+        assert!(index.search("1").is_empty());
+    }
+}
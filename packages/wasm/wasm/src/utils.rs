@@ -9,9 +9,25 @@ pub fn set_panic_hook() {
     console_error_panic_hook::set_once();
 }
 
+// Current time in milliseconds since the Unix epoch
+pub fn now() -> f64 {
+    #[cfg(target_arch = "wasm32")]
+    {
+        js_sys::Date::now()
+    }
+    
+    #[cfg(not(target_arch = "wasm32"))]
+    {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs_f64() * 1000.0)
+            .unwrap_or_default()
+    }
+}
+
 // Generate a random UUID v4
 pub fn generate_uuid() -> String {
-    use js_sys::{Math, Array, Uint8Array};
+    use js_sys::{Math, Uint8Array};
     
     let bytes = Uint8Array::new_with_length(16);
     
@@ -0,0 +1,567 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use serde::Deserialize;
+use serde_json::Value;
+
+#[derive(Deserialize, Default, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub enum Metric {
+    #[default]
+    Cosine,
+    DotProduct,
+    Euclidean,
+}
+
+impl Metric {
+    // Distance used for searching, smaller is closer
+    fn distance(&self, a: &[f64], b: &[f64]) -> f64 {
+        match self {
+            Metric::Cosine => 1.0 - cosine_similarity(a, b),
+            Metric::DotProduct => -dot(a, b),
+            Metric::Euclidean => squared_distance(a, b).sqrt(),
+        }
+    }
+    
+    // Score reported to callers, larger is closer: cosine similarity, dot
+    // product, or 1 / (1 + distance) for euclidean
+    fn score(&self, distance: f64) -> f64 {
+        match self {
+            Metric::Cosine => 1.0 - distance,
+            Metric::DotProduct => -distance,
+            Metric::Euclidean => 1.0 / (1.0 + distance),
+        }
+    }
+}
+
+// Parameters of the approximate HNSW graph; leaving them out of the index
+// options selects exact flat search
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HnswOptions {
+    // Neighbors kept per node on the upper layers, twice that on layer 0
+    pub m: usize,
+    pub ef_construction: usize,
+    pub ef_search: usize,
+}
+
+impl HnswOptions {
+    // The graph needs at least two neighbors per node to stay connected, and
+    // searches need room for at least one candidate
+    pub fn validate(&self) -> Result<(), String> {
+        if self.m < 2 {
+            return Err(format!("hnsw.m must be at least 2, got {}", self.m));
+        }
+        if self.ef_construction < 1 {
+            return Err("hnsw.efConstruction must be at least 1".to_string());
+        }
+        if self.ef_search < 1 {
+            return Err("hnsw.efSearch must be at least 1".to_string());
+        }
+        
+        Ok(())
+    }
+}
+
+impl Default for HnswOptions {
+    fn default() -> Self {
+        HnswOptions {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 50,
+        }
+    }
+}
+
+// k-nearest-neighbour index over a numeric array field
+#[derive(Debug, Clone)]
+pub struct VectorIndex {
+    metric: Metric,
+    // Set by the index options, or else inferred from the first vector added
+    dimensions: Option<usize>,
+    configured_dimensions: Option<usize>,
+    // For flat search: document_id -> vector
+    vectors: HashMap<String, Vec<f64>>,
+    hnsw: Option<Hnsw>,
+}
+
+impl VectorIndex {
+    pub fn new(metric: Metric, dimensions: Option<usize>, hnsw: Option<HnswOptions>) -> Self {
+        VectorIndex {
+            metric,
+            dimensions,
+            configured_dimensions: dimensions,
+            vectors: HashMap::new(),
+            hnsw: hnsw.map(Hnsw::new),
+        }
+    }
+    
+    // Index the first of a document's field values that is a numeric array.
+    // Documents without one are not indexed.
+    pub fn add(&mut self, id: &str, values: &[&Value]) -> Result<(), String> {
+        let vector = match values.iter().find_map(|value| to_vector(value)) {
+            Some(vector) => vector,
+            None => return Ok(()),
+        };
+        
+        match self.dimensions {
+            Some(dimensions) if dimensions != vector.len() => {
+                return Err(format!("Expected a vector of {} dimensions, got {}", dimensions, vector.len()));
+            },
+            Some(_) => {},
+            None => self.dimensions = Some(vector.len()),
+        }
+        
+        match &mut self.hnsw {
+            Some(hnsw) => hnsw.insert(id, vector, self.metric),
+            None => {
+                self.vectors.insert(id.to_string(), vector);
+            },
+        }
+        
+        Ok(())
+    }
+    
+    pub fn remove(&mut self, id: &str) {
+        match &mut self.hnsw {
+            Some(hnsw) => hnsw.remove(id, self.metric),
+            None => {
+                self.vectors.remove(id);
+            },
+        }
+    }
+    
+    pub fn len(&self) -> usize {
+        match &self.hnsw {
+            Some(hnsw) => hnsw.node_ids.len(),
+            None => self.vectors.len(),
+        }
+    }
+    
+    pub fn clear(&mut self) {
+        self.dimensions = self.configured_dimensions;
+        self.vectors.clear();
+        if let Some(hnsw) = &mut self.hnsw {
+            *hnsw = Hnsw::new(hnsw.options.clone());
+        }
+    }
+    
+    // The k documents closest to the vector among those accepted by `filter`,
+    // closest first, with their scores
+    pub fn search(&self, vector: &[f64], k: usize, filter: impl Fn(&str) -> bool) -> Result<Vec<(&str, f64)>, String> {
+        if let Some(dimensions) = self.dimensions {
+            if dimensions != vector.len() {
+                return Err(format!("Expected a query vector of {} dimensions, got {}", dimensions, vector.len()));
+            }
+        }
+        
+        if let Some(hnsw) = &self.hnsw {
+            let ef = hnsw.options.ef_search.max(k);
+            let candidates = hnsw.search(vector, ef, self.metric);
+            let exhaustive = candidates.len() >= hnsw.live_count();
+            let results: Vec<(&str, f64)> = candidates.into_iter()
+                .filter(|(id, _)| filter(id))
+                .take(k)
+                .map(|(id, distance)| (id, self.metric.score(distance)))
+                .collect();
+            
+            // A selective filter can leave too few approximate candidates, so
+            // fall back to an exact search over everything
+            if results.len() == k || exhaustive {
+                return Ok(results);
+            }
+        }
+        
+        let mut scored: Vec<(&str, f64)> = self.live()
+            .filter(|(id, _)| filter(id))
+            .map(|(id, v)| (id, self.metric.distance(vector, v)))
+            .collect();
+        scored.sort_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(b.0)));
+        scored.truncate(k);
+        
+        Ok(scored.into_iter().map(|(id, distance)| (id, self.metric.score(distance))).collect())
+    }
+    
+    fn live(&self) -> Box<dyn Iterator<Item = (&str, &[f64])> + '_> {
+        match &self.hnsw {
+            Some(hnsw) => Box::new(hnsw.nodes.iter()
+                .filter(|node| !node.deleted)
+                .map(|node| (node.id.as_str(), node.vector.as_slice()))),
+            None => Box::new(self.vectors.iter().map(|(id, v)| (id.as_str(), v.as_slice()))),
+        }
+    }
+}
+
+pub fn to_vector(value: &Value) -> Option<Vec<f64>> {
+    match value {
+        Value::Array(items) if !items.is_empty() => items.iter().map(Value::as_f64).collect(),
+        _ => None,
+    }
+}
+
+fn dot(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+fn squared_distance(a: &[f64], b: &[f64]) -> f64 {
+    a.iter().zip(b).map(|(x, y)| (x - y) * (x - y)).sum()
+}
+
+fn cosine_similarity(a: &[f64], b: &[f64]) -> f64 {
+    let norms = dot(a, a).sqrt() * dot(b, b).sqrt();
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot(a, b) / norms
+    }
+}
+
+// Distance paired with a node, ordered by distance for the search heaps
+#[derive(Debug, Clone, Copy)]
+struct Candidate(f64, usize);
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0).then(self.1.cmp(&other.1))
+    }
+}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Candidate {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Candidate {}
+
+#[derive(Debug, Clone)]
+struct Node {
+    id: String,
+    vector: Vec<f64>,
+    // Neighbor node positions, one list per layer the node is on
+    neighbors: Vec<Vec<usize>>,
+    // Removed documents stay in the graph to keep it navigable until the next rebuild
+    deleted: bool,
+}
+
+// Hierarchical navigable small world graph (Malkov & Yashunin)
+#[derive(Debug, Clone)]
+struct Hnsw {
+    options: HnswOptions,
+    nodes: Vec<Node>,
+    // document_id -> position in `nodes` of its live node
+    node_ids: HashMap<String, usize>,
+    entry: Option<usize>,
+    deleted_count: usize,
+}
+
+impl Hnsw {
+    fn new(options: HnswOptions) -> Self {
+        Hnsw {
+            options,
+            nodes: Vec::new(),
+            node_ids: HashMap::new(),
+            entry: None,
+            deleted_count: 0,
+        }
+    }
+    
+    fn live_count(&self) -> usize {
+        self.node_ids.len()
+    }
+    
+    fn insert(&mut self, id: &str, vector: Vec<f64>, metric: Metric) {
+        self.remove(id, metric);
+        
+        let level = self.random_level(id);
+        let node = self.nodes.len();
+        self.nodes.push(Node {
+            id: id.to_string(),
+            vector,
+            neighbors: vec![Vec::new(); level + 1],
+            deleted: false,
+        });
+        self.node_ids.insert(id.to_string(), node);
+        
+        let entry = match self.entry {
+            Some(entry) => entry,
+            None => {
+                self.entry = Some(node);
+                return;
+            },
+        };
+        
+        let top = self.nodes[entry].neighbors.len() - 1;
+        let vector = self.nodes[node].vector.clone();
+        
+        // Greedy descent through the layers above the new node's level
+        let mut nearest = entry;
+        for layer in (level + 1..=top).rev() {
+            nearest = self.search_layer(&vector, &[nearest], 1, layer, metric)[0].1;
+        }
+        
+        // Connect the node on each of its layers
+        let mut entry_points = vec![nearest];
+        for layer in (0..=level.min(top)).rev() {
+            let candidates = self.search_layer(&vector, &entry_points, self.options.ef_construction, layer, metric);
+            let max_neighbors = self.max_neighbors(layer);
+            
+            let neighbors: Vec<usize> = candidates.iter().take(max_neighbors).map(|c| c.1).collect();
+            for &neighbor in &neighbors {
+                self.nodes[neighbor].neighbors[layer].push(node);
+                if self.nodes[neighbor].neighbors[layer].len() > max_neighbors {
+                    self.prune(neighbor, layer, max_neighbors, metric);
+                }
+            }
+            self.nodes[node].neighbors[layer] = neighbors;
+            
+            entry_points = candidates.iter().map(|c| c.1).collect();
+        }
+        
+        if level > top {
+            self.entry = Some(node);
+        }
+    }
+    
+    fn remove(&mut self, id: &str, metric: Metric) {
+        if let Some(node) = self.node_ids.remove(id) {
+            self.nodes[node].deleted = true;
+            self.deleted_count += 1;
+            
+            // Rebuild once deleted nodes outnumber live ones
+            if self.deleted_count > self.node_ids.len() {
+                self.rebuild(metric);
+            }
+        }
+    }
+    
+    fn rebuild(&mut self, metric: Metric) {
+        let nodes = std::mem::take(&mut self.nodes);
+        *self = Hnsw::new(self.options.clone());
+        
+        for node in nodes.into_iter().filter(|node| !node.deleted) {
+            self.insert(&node.id, node.vector, metric);
+        }
+    }
+    
+    // Live nodes nearest to the vector, closest first, with their distances
+    fn search(&self, vector: &[f64], ef: usize, metric: Metric) -> Vec<(&str, f64)> {
+        let entry = match self.entry {
+            Some(entry) => entry,
+            None => return Vec::new(),
+        };
+        
+        let mut nearest = entry;
+        for layer in (1..self.nodes[entry].neighbors.len()).rev() {
+            nearest = self.search_layer(vector, &[nearest], 1, layer, metric)[0].1;
+        }
+        
+        self.search_layer(vector, &[nearest], ef, 0, metric)
+            .into_iter()
+            .filter(|c| !self.nodes[c.1].deleted)
+            .map(|c| (self.nodes[c.1].id.as_str(), c.0))
+            .collect()
+    }
+    
+    // Best-first search of one layer, returning up to `ef` candidates closest first
+    fn search_layer(&self, vector: &[f64], entry_points: &[usize], ef: usize, layer: usize, metric: Metric) -> Vec<Candidate> {
+        let mut visited: HashSet<usize> = entry_points.iter().copied().collect();
+        let mut candidates: BinaryHeap<Reverse<Candidate>> = BinaryHeap::new();
+        let mut results: BinaryHeap<Candidate> = BinaryHeap::new();
+        
+        for &point in entry_points {
+            let candidate = Candidate(metric.distance(vector, &self.nodes[point].vector), point);
+            candidates.push(Reverse(candidate));
+            results.push(candidate);
+        }
+        while results.len() > ef {
+            results.pop();
+        }
+        
+        while let Some(Reverse(closest)) = candidates.pop() {
+            if results.len() >= ef && results.peek().is_some_and(|furthest| closest.0 > furthest.0) {
+                break;
+            }
+            
+            for &neighbor in &self.nodes[closest.1].neighbors[layer] {
+                if !visited.insert(neighbor) {
+                    continue;
+                }
+                
+                let candidate = Candidate(metric.distance(vector, &self.nodes[neighbor].vector), neighbor);
+                if results.len() < ef || results.peek().is_some_and(|furthest| candidate < *furthest) {
+                    candidates.push(Reverse(candidate));
+                    results.push(candidate);
+                    if results.len() > ef {
+                        results.pop();
+                    }
+                }
+            }
+        }
+        
+        results.into_sorted_vec()
+    }
+    
+    // Keep only the closest neighbors of a node on a layer
+    fn prune(&mut self, node: usize, layer: usize, max_neighbors: usize, metric: Metric) {
+        let vector = &self.nodes[node].vector;
+        let mut neighbors: Vec<Candidate> = self.nodes[node].neighbors[layer].iter()
+            .map(|&n| Candidate(metric.distance(vector, &self.nodes[n].vector), n))
+            .collect();
+        neighbors.sort();
+        neighbors.truncate(max_neighbors);
+        
+        self.nodes[node].neighbors[layer] = neighbors.into_iter().map(|c| c.1).collect();
+    }
+    
+    fn max_neighbors(&self, layer: usize) -> usize {
+        if layer == 0 {
+            self.options.m * 2
+        } else {
+            self.options.m
+        }
+    }
+    
+    // Exponentially distributed level, derived from a hash of the id so the
+    // graph is the same whenever the same documents are inserted
+    fn random_level(&self, id: &str) -> usize {
+        let mut hash: u64 = 0xcbf29ce484222325;
+        for byte in id.bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        // Mix the bits so ids sharing a prefix still spread out
+        hash ^= hash >> 33;
+        hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+        hash ^= hash >> 33;
+        
+        let uniform = ((hash >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+        let ml = 1.0 / (self.options.m.max(2) as f64).ln();
+        (-uniform.ln() * ml) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    
+    // Deterministic pseudo-random vectors with components in [-0.5, 0.5)
+    fn vectors(count: usize, dimensions: usize) -> Vec<Vec<f64>> {
+        let mut seed: u64 = 42;
+        let mut next = move || {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (seed >> 33) as f64 / (1u64 << 31) as f64 - 0.5
+        };
+        (0..count).map(|_| (0..dimensions).map(|_| next()).collect()).collect()
+    }
+    
+    fn index(metric: Metric, hnsw: Option<HnswOptions>, vectors: &[Vec<f64>]) -> VectorIndex {
+        let mut index = VectorIndex::new(metric, None, hnsw);
+        for (i, vector) in vectors.iter().enumerate() {
+            index.add(&i.to_string(), &[&json!(vector)]).unwrap();
+        }
+        index
+    }
+    
+    fn ids<'a>(results: &[(&'a str, f64)]) -> Vec<&'a str> {
+        results.iter().map(|(id, _)| *id).collect()
+    }
+    
+    #[test]
+    fn metrics_score_closer_vectors_higher() {
+        let vectors = [vec![1.0, 0.0], vec![0.0, 1.0], vec![1.0, 1.0]];
+        
+        let cosine = index(Metric::Cosine, None, &vectors);
+        let results = cosine.search(&[1.0, 0.1], 3, |_| true).unwrap();
+        assert_eq!(ids(&results), ["0", "2", "1"]);
+        assert!((results[0].1 - 0.995).abs() < 0.001);
+        
+        let dot = index(Metric::DotProduct, None, &vectors);
+        let results = dot.search(&[1.0, 0.1], 3, |_| true).unwrap();
+        assert_eq!(ids(&results), ["2", "0", "1"]);
+        assert_eq!(results[0].1, 1.1);
+        
+        let euclidean = index(Metric::Euclidean, None, &vectors);
+        let results = euclidean.search(&[1.0, 0.0], 1, |_| true).unwrap();
+        assert_eq!(results, [("0", 1.0)]);
+    }
+    
+    #[test]
+    fn dimensions_are_fixed_by_the_first_vector() {
+        let mut index = VectorIndex::new(Metric::Cosine, None, None);
+        index.add("a", &[&json!([1, 2])]).unwrap();
+        
+        assert!(index.add("b", &[&json!([1, 2, 3])]).is_err());
+        assert!(index.search(&[1.0], 1, |_| true).is_err());
+        
+        // Values that aren't numeric arrays are skipped rather than rejected
+        index.add("c", &[&json!("text")]).unwrap();
+        index.add("d", &[&json!([])]).unwrap();
+        index.add("e", &[]).unwrap();
+        assert_eq!(index.len(), 1);
+    }
+    
+    #[test]
+    fn clearing_forgets_inferred_dimensions() {
+        for hnsw in [None, Some(HnswOptions::default())] {
+            let mut index = VectorIndex::new(Metric::Cosine, None, hnsw.clone());
+            index.add("a", &[&json!([1, 2])]).unwrap();
+            index.clear();
+            index.add("a", &[&json!([1, 2, 3])]).unwrap();
+            assert_eq!(index.search(&[1.0, 2.0, 3.0], 1, |_| true).unwrap().len(), 1);
+            
+            // Dimensions given in the options still hold after clearing
+            let mut index = VectorIndex::new(Metric::Cosine, Some(2), hnsw);
+            index.clear();
+            assert!(index.add("a", &[&json!([1, 2, 3])]).is_err());
+        }
+    }
+    
+    #[test]
+    fn hnsw_recalls_most_exact_neighbours() {
+        let vectors = vectors(1000, 16);
+        let flat = index(Metric::Euclidean, None, &vectors);
+        let hnsw = index(Metric::Euclidean, Some(HnswOptions { m: 8, ..HnswOptions::default() }), &vectors);
+        
+        let mut found = 0;
+        for query in &vectors[..20] {
+            let exact = ids(&flat.search(query, 10, |_| true).unwrap());
+            let approximate = hnsw.search(query, 10, |_| true).unwrap();
+            assert!(approximate.windows(2).all(|pair| pair[0].1 >= pair[1].1));
+            found += approximate.iter().filter(|(id, _)| exact.contains(id)).count();
+        }
+        assert!(found > 180, "recall {}/200", found);
+    }
+    
+    #[test]
+    fn selective_filters_still_return_k_results() {
+        let vectors = vectors(500, 8);
+        let hnsw = index(Metric::Cosine, Some(HnswOptions::default()), &vectors);
+        
+        let results = hnsw.search(&vectors[0], 5, |id| id.ends_with('7')).unwrap();
+        assert_eq!(results.len(), 5);
+        assert!(results.iter().all(|(id, _)| id.ends_with('7')));
+    }
+    
+    #[test]
+    fn removed_vectors_are_never_returned() {
+        let vectors = vectors(200, 4);
+        let mut hnsw = index(Metric::Euclidean, Some(HnswOptions::default()), &vectors);
+        
+        for i in 0..150 {
+            hnsw.remove(&i.to_string());
+        }
+        let results = hnsw.search(&vectors[0], 10, |_| true).unwrap();
+        assert_eq!(results.len(), 10);
+        assert!(results.iter().all(|(id, _)| id.parse::<usize>().unwrap() >= 150));
+        
+        hnsw.clear();
+        assert!(hnsw.search(&vectors[0], 10, |_| true).unwrap().is_empty());
+    }
+}